@@ -6,7 +6,7 @@ use criterion::*;
 use rustlambda::{
     eval,
     expr::{self, expr_aliases::*, Defs, Expr},
-    lex, parse,
+    lex, parse, vm,
 };
 
 fn setup<M: measurement::Measurement>(gr: &mut BenchmarkGroup<M>) {
@@ -83,5 +83,24 @@ pub fn bench_exp(c: &mut Criterion) {
     }
 }
 
-criterion_group!(benches, bench_stuff, bench_rec_factorial, bench_exp);
+pub fn bench_vm(c: &mut Criterion) {
+    let mut gr = c.benchmark_group("vm recursive factorial");
+    setup(&mut gr);
+    let input = include_bytes!("../res/recfact");
+    let defs = parse::parse(lex::lex(&input[..]).unwrap()).unwrap().0;
+    for i in 1..=5 {
+        gr.bench_with_input(BenchmarkId::from_parameter(i), &i, |b, &i| {
+            let expr = appl(name("Fact"), chnum(i));
+            b.iter(|| vm::normalize(&expr, &defs))
+        });
+    }
+}
+
+criterion_group!(
+    benches,
+    bench_stuff,
+    bench_rec_factorial,
+    bench_exp,
+    bench_vm
+);
 criterion_main!(benches);
@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+
+use crate::debruijn::DeBruijn;
+use crate::expr::Expr;
+
+/// Handle to a node interned in an `Arena`.
+///
+/// Nodes are stored without parameter names, so two ids from the same arena
+/// are equal exactly when their terms are alpha-equivalent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(u32);
+
+impl NodeId {
+    /// Position of the node in `Arena::nodes`
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Node {
+    /// A bound variable by de Bruijn index
+    Bound(u32),
+    Free(u8),
+    Name(String),
+    Abstr(NodeId),
+    Appl(NodeId, NodeId),
+}
+
+/// Hash-consed storage for terms.
+///
+/// Every distinct subterm is stored once, however many times it occurs, and
+/// interning the same term twice gives back the same id. Nothing is ever
+/// removed.
+///
+/// This is for comparing and looking up terms, as readback and termination
+/// analysis do. Reduction does not use it: the reducers still rewrite trees
+/// of `Box<Expr>`, and take as much memory as before.
+#[derive(Default)]
+pub struct Arena {
+    nodes: Vec<Node>,
+    ids: HashMap<Node, NodeId>,
+}
+
+impl Arena {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of distinct nodes stored
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Every node in the order it was interned, children before parents
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    pub fn get(&self, id: NodeId) -> &Node {
+        &self.nodes[id.0 as usize]
+    }
+
+    /// Interns a single node whose children are already interned.
+    pub fn node(&mut self, node: Node) -> NodeId {
+        if let Some(&id) = self.ids.get(&node) {
+            return id;
+        }
+        let id = NodeId(self.nodes.len() as u32);
+        self.nodes.push(node.clone());
+        self.ids.insert(node, id);
+        id
+    }
+
+    pub fn insert(&mut self, expr: &Expr) -> NodeId {
+        use Expr::*;
+        enum Job<'a> {
+            Visit(&'a Expr),
+            Abstr(u8),
+            Appl,
+        }
+
+        let mut scopes: HashMap<u8, Vec<u32>> = HashMap::new();
+        let mut level = 0;
+        let mut jobs = vec![Job::Visit(expr)];
+        let mut done: Vec<NodeId> = Vec::new();
+        while let Some(job) = jobs.pop() {
+            let node = match job {
+                Job::Visit(Variable(v)) => match scopes.get(v).and_then(|s| s.last()) {
+                    Some(l) => Node::Bound(level - 1 - l),
+                    None => Node::Free(*v),
+                },
+                Job::Visit(Name(n)) => Node::Name(n.clone()),
+                Job::Visit(Abstr(p, b)) => {
+                    scopes.entry(*p).or_default().push(level);
+                    level += 1;
+                    jobs.push(Job::Abstr(*p));
+                    jobs.push(Job::Visit(b));
+                    continue;
+                }
+                Job::Visit(Appl(a, b)) => {
+                    jobs.push(Job::Appl);
+                    jobs.push(Job::Visit(b));
+                    jobs.push(Job::Visit(a));
+                    continue;
+                }
+                Job::Abstr(p) => {
+                    scopes.get_mut(&p).and_then(Vec::pop);
+                    level -= 1;
+                    Node::Abstr(done.pop().unwrap())
+                }
+                Job::Appl => {
+                    let b = done.pop().unwrap();
+                    let a = done.pop().unwrap();
+                    Node::Appl(a, b)
+                }
+            };
+            let id = self.node(node);
+            done.push(id);
+        }
+        done.pop().unwrap()
+    }
+
+    pub fn insert_debruijn(&mut self, term: &DeBruijn) -> NodeId {
+        enum Job<'a> {
+            Visit(&'a DeBruijn),
+            Abstr,
+            Appl,
+        }
+
+        let mut jobs = vec![Job::Visit(term)];
+        let mut done: Vec<NodeId> = Vec::new();
+        while let Some(job) = jobs.pop() {
+            let node = match job {
+                Job::Visit(DeBruijn::Bound(i)) => Node::Bound(*i),
+                Job::Visit(DeBruijn::Free(v)) => Node::Free(*v),
+                Job::Visit(DeBruijn::Name(n)) => Node::Name(n.clone()),
+                Job::Visit(DeBruijn::Abstr(_, b)) => {
+                    jobs.push(Job::Abstr);
+                    jobs.push(Job::Visit(b));
+                    continue;
+                }
+                Job::Visit(DeBruijn::Appl(a, b)) => {
+                    jobs.push(Job::Appl);
+                    jobs.push(Job::Visit(b));
+                    jobs.push(Job::Visit(a));
+                    continue;
+                }
+                Job::Abstr => Node::Abstr(done.pop().unwrap()),
+                Job::Appl => {
+                    let b = done.pop().unwrap();
+                    let a = done.pop().unwrap();
+                    Node::Appl(a, b)
+                }
+            };
+            let id = self.node(node);
+            done.push(id);
+        }
+        done.pop().unwrap()
+    }
+
+    /// Unfolds the shared node into a tree, naming parameters by depth.
+    pub fn to_debruijn(&self, id: NodeId) -> Box<DeBruijn> {
+        enum Job {
+            Visit(NodeId, u32),
+            Abstr(u8),
+            Appl,
+        }
+
+        let mut jobs = vec![Job::Visit(id, 0)];
+        let mut done: Vec<Box<DeBruijn>> = Vec::new();
+        while let Some(job) = jobs.pop() {
+            match job {
+                Job::Visit(id, depth) => match self.get(id) {
+                    Node::Bound(i) => done.push(Box::new(DeBruijn::Bound(*i))),
+                    Node::Free(v) => done.push(Box::new(DeBruijn::Free(*v))),
+                    Node::Name(n) => done.push(Box::new(DeBruijn::Name(n.clone()))),
+                    Node::Abstr(b) => {
+                        jobs.push(Job::Abstr(b'a' + (depth % 26) as u8));
+                        jobs.push(Job::Visit(*b, depth + 1));
+                    }
+                    Node::Appl(a, b) => {
+                        jobs.push(Job::Appl);
+                        jobs.push(Job::Visit(*b, depth));
+                        jobs.push(Job::Visit(*a, depth));
+                    }
+                },
+                Job::Abstr(hint) => {
+                    let body = done.pop().unwrap();
+                    done.push(Box::new(DeBruijn::Abstr(hint, body)));
+                }
+                Job::Appl => {
+                    let b = done.pop().unwrap();
+                    let a = done.pop().unwrap();
+                    done.push(Box::new(DeBruijn::Appl(a, b)));
+                }
+            }
+        }
+        done.pop().unwrap()
+    }
+
+    pub fn to_expr(&self, id: NodeId) -> Box<Expr> {
+        self.to_debruijn(id).to_expr()
+    }
+
+    /// Size of the term as a tree, counted like `Expr::size`.
+    ///
+    /// Shared nodes are counted every time they occur, so this can be far
+    /// larger than the number of nodes stored; it saturates at `u64::MAX`.
+    pub fn tree_size(&self, id: NodeId) -> u64 {
+        let mut sizes: HashMap<NodeId, u64> = HashMap::new();
+        let mut stack = vec![(id, false)];
+        while let Some((id, children_done)) = stack.pop() {
+            if sizes.contains_key(&id) {
+                continue;
+            }
+            let size = match self.get(id) {
+                Node::Bound(_) | Node::Free(_) | Node::Name(_) => 1,
+                Node::Abstr(b) if children_done => sizes[b].saturating_add(1),
+                Node::Appl(a, b) if children_done => sizes[a].saturating_add(sizes[b]),
+                Node::Abstr(b) => {
+                    stack.push((id, true));
+                    stack.push((*b, false));
+                    continue;
+                }
+                Node::Appl(a, b) => {
+                    stack.push((id, true));
+                    stack.push((*b, false));
+                    stack.push((*a, false));
+                    continue;
+                }
+            };
+            sizes.insert(id, size);
+        }
+        sizes[&id]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::*;
+
+    #[test]
+    fn alpha_equivalent_terms_share_ids() {
+        let mut arena = Arena::new();
+        let a = arena.insert(&process(r"\fad.da(lf)"));
+        let b = arena.insert(&process(r"\okg.gk(lo)"));
+        let c = arena.insert(&process(r"\okg.gk(ko)"));
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        // Free variables keep their names
+        assert_ne!(arena.insert(&process("a")), arena.insert(&process("b")));
+        // \c.c and \a.c are not the same function
+        assert_ne!(
+            arena.insert(&process(r"\c.c")),
+            arena.insert(&process(r"\a.c"))
+        );
+    }
+
+    #[test]
+    fn subterms_stored_once() {
+        let mut arena = Arena::new();
+        let n = 1000;
+        let id = arena.insert(&Expr::church_num(n));
+        // f, x, the n applications and the two abstractions
+        assert_eq!(arena.len(), n as usize + 4);
+        assert_eq!(arena.tree_size(id), Expr::church_num(n).size() as u64);
+
+        let before = arena.len();
+        arena.insert(&Expr::church_num(n - 1));
+        assert_eq!(arena.len(), before + 2);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut arena = Arena::new();
+        for src in [r"\fx.f(fx)", r"\abc.ba(\b.cb)(\ac.ba)", r"\a.b(\b.ab) Name"] {
+            let e = process(src);
+            let id = arena.insert(&e);
+            assert_eq!(arena.insert(&arena.to_expr(id)), id);
+            assert_eq!(arena.insert_debruijn(&DeBruijn::from_expr(&e)), id);
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! Tromp's binary lambda calculus, a prefix code for closed terms.
+//!
+//! An abstraction is `00` followed by its body, an application `01`
+//! followed by its function and argument, and the variable bound `n`
+//! abstractions out is `n + 1` ones followed by a zero. Bits are kept as
+//! text of `0` and `1`, and packed 8 to a byte, the first bit highest, to be
+//! read from or written to files.
+
+use std::error::Error;
+
+use crate::debruijn::{depth_hint, DeBruijn};
+use crate::expr::Expr;
+
+/// The bits of `expr`, which must be closed and have no names
+pub fn encode(expr: &Expr) -> Result<String, Box<dyn Error>> {
+    let term = DeBruijn::from_expr(expr);
+    let mut out = String::new();
+    let mut todo = vec![&*term];
+    while let Some(term) = todo.pop() {
+        match term {
+            DeBruijn::Abstr(_, body) => {
+                out.push_str("00");
+                todo.push(body);
+            }
+            DeBruijn::Appl(f, x) => {
+                out.push_str("01");
+                todo.push(x);
+                todo.push(f);
+            }
+            DeBruijn::Bound(i) => {
+                out.push_str(&"1".repeat(*i as usize + 1));
+                out.push('0');
+            }
+            DeBruijn::Free(v) => {
+                let v = std::ascii::escape_default(*v);
+                return Err(format!("{} is free, and BLC has closed terms only", v).into());
+            }
+            DeBruijn::Name(n) => return Err(format!("{} has no BLC form", n).into()),
+        }
+    }
+    Ok(out)
+}
+
+/// The size of `expr` in bits, if it has a BLC form
+pub fn size(expr: &Expr) -> Result<usize, Box<dyn Error>> {
+    Ok(encode(expr)?.len())
+}
+
+/// Reads the term at the start of `bits`, giving back the bits after it as
+/// well.
+pub fn decode(bits: &str) -> Result<(Box<Expr>, &str), Box<dyn Error>> {
+    enum Frame {
+        Abstr,
+        /// An application whose function is being read
+        Function,
+        /// An application whose argument is being read
+        Argument(Box<DeBruijn>),
+    }
+
+    let mut frames = Vec::new();
+    let mut depth = 0;
+    let mut rest = bits;
+    loop {
+        let mut term = if let Some(after) = rest.strip_prefix("00") {
+            rest = after;
+            frames.push(Frame::Abstr);
+            depth += 1;
+            continue;
+        } else if let Some(after) = rest.strip_prefix("01") {
+            rest = after;
+            frames.push(Frame::Function);
+            continue;
+        } else {
+            let ones = rest.len() - rest.trim_start_matches('1').len();
+            match rest[ones..].chars().next() {
+                // A lone 0 is the start of a cut off abstraction or application
+                Some('0') if ones > 0 => {}
+                Some('0') => return Err("BLC ends in the middle of a term".into()),
+                Some(c) => return Err(format!("Not a bit of BLC: {:?}", c).into()),
+                None => return Err("BLC ends in the middle of a term".into()),
+            }
+            if ones > depth {
+                return Err(format!("Variable {} has no abstraction to refer to", ones).into());
+            }
+            rest = &rest[ones + 1..];
+            Box::new(DeBruijn::Bound(ones as u32 - 1))
+        };
+        loop {
+            match frames.pop() {
+                None => return Ok((term.to_expr(), rest)),
+                Some(Frame::Abstr) => {
+                    depth -= 1;
+                    term = Box::new(DeBruijn::Abstr(depth_hint(depth), term));
+                }
+                Some(Frame::Function) => {
+                    frames.push(Frame::Argument(term));
+                    break;
+                }
+                Some(Frame::Argument(f)) => term = Box::new(DeBruijn::Appl(f, term)),
+            }
+        }
+    }
+}
+
+/// The bits of `text`, skipping whitespace
+pub fn bits(text: &str) -> Result<String, Box<dyn Error>> {
+    let mut out = String::new();
+    for c in text.chars().filter(|c| !c.is_whitespace()) {
+        match c {
+            '0' | '1' => out.push(c),
+            _ => return Err(format!("Not a bit of BLC: {:?}", c).into()),
+        }
+    }
+    Ok(out)
+}
+
+/// `bits` 8 to a byte, the last one padded with zeros
+pub fn pack(bits: &str) -> Vec<u8> {
+    bits.as_bytes()
+        .chunks(8)
+        .map(|chunk| {
+            let byte = chunk
+                .iter()
+                .fold(0, |byte, &b| byte << 1 | (b == b'1') as u8);
+            byte << (8 - chunk.len())
+        })
+        .collect()
+}
+
+/// The bits of `bytes`
+pub fn unpack(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:08b}", byte)).collect()
+}
+
+/// The bits after a term that `decode` read from packed bytes, without the
+/// zeros that pad the byte it ends in
+pub fn unpadded(rest: &str) -> &str {
+    &rest[rest.len() % 8..]
+}
+
+/// `bits` as a list of booleans, the input of a BLC program: `\f.f B L`
+/// for a bit `B` followed by the list `L`, ending in `\ab.b`. A 0 is
+/// `\ab.a` and a 1 is `\ab.b`.
+pub fn bit_list(bits: &str) -> Box<Expr> {
+    use DeBruijn::*;
+    let boolean = |index| Box::new(Abstr(b'a', Box::new(Abstr(b'b', Box::new(Bound(index))))));
+    let mut list = boolean(0);
+    for bit in bits.bytes().rev() {
+        let head = boolean(if bit == b'0' { 1 } else { 0 });
+        let pair = Appl(Box::new(Appl(Box::new(Bound(0)), head)), list);
+        list = Box::new(Abstr(b'f', Box::new(pair)));
+    }
+    list.to_expr()
+}
+
+/// The bits of a normal form that is a list of booleans like `bit_list`
+/// builds
+pub fn read_bit_list(expr: &Expr) -> Option<String> {
+    use DeBruijn::*;
+    let term = DeBruijn::from_expr(expr);
+    let mut out = String::new();
+    let mut list = &*term;
+    loop {
+        match list {
+            Abstr(_, box Abstr(_, box Bound(0))) => return Some(out),
+            Abstr(_, box Appl(box Appl(box Bound(0), head), tail)) => {
+                match &**head {
+                    Abstr(_, box Abstr(_, box Bound(1))) => out.push('0'),
+                    Abstr(_, box Abstr(_, box Bound(0))) => out.push('1'),
+                    _ => return None,
+                }
+                list = tail;
+            }
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval;
+    use crate::expr::Defs;
+    use crate::test::*;
+
+    #[test]
+    fn codes() {
+        assert_eq!(encode(&process(r"\x.x")).unwrap(), "0010");
+        assert_eq!(encode(&process(r"\xy.x")).unwrap(), "0000110");
+        assert_eq!(
+            encode(&process(r"\xyz.xz(yz)")).unwrap(),
+            "00000001011110100111010"
+        );
+        assert_eq!(size(&process(r"(\x.xx)(\x.xx)")).unwrap(), 2 + 2 * 8);
+        assert!(encode(&process(r"\x.y")).is_err());
+        assert!(encode(&process("I")).is_err());
+    }
+
+    #[test]
+    fn round_trips() {
+        for src in [
+            r"\x.x",
+            r"\fx.f(f(fx))",
+            r"\xy.(\z.zyx)(\ab.ba)",
+            r"(\x.xx)(\x.xx)",
+        ] {
+            let expr = process(src);
+            let bits = encode(&expr).unwrap();
+            let padded = bits.clone() + "0110";
+            let (decoded, rest) = decode(&padded).unwrap();
+            assert!(decoded.alpha_eq(&expr), "{}: {}", src, decoded);
+            assert_eq!(encode(&decoded).unwrap(), bits);
+            assert_eq!(rest, "0110");
+            assert_eq!(unpack(&pack(&bits))[..bits.len()], bits);
+            let packed = unpack(&pack(&bits)) + "11111111";
+            assert_eq!(unpadded(decode(&packed).unwrap().1), "11111111");
+        }
+        assert!(decode("0").is_err());
+        assert!(decode("00").is_err());
+        assert!(decode("0110").is_err());
+        assert!(decode("0012").is_err());
+        assert_eq!(pack("0000001011"), [0b0000_0010, 0b1100_0000]);
+    }
+
+    #[test]
+    fn bit_lists() {
+        let defs = Defs::new();
+        for bits in ["", "0", "1", "0110100"] {
+            assert_eq!(read_bit_list(&bit_list(bits)).as_deref(), Some(bits));
+        }
+        assert_eq!(read_bit_list(&process(r"\x.x")), None);
+
+        // The identity, and a left fold consing every bit onto the rest
+        let run = |program: &str, input: &str| {
+            let (program, _) = decode(program).unwrap();
+            let applied = Box::new(Expr::Appl(program, bit_list(input)));
+            let normal = eval::reduce(applied, &defs).0.unwrap();
+            read_bit_list(&normal)
+        };
+        assert_eq!(run("0010", "1101").as_deref(), Some("1101"));
+        let reverse = r"\l.(\y.(\x.xx)(\x.y(xx)))(\ral.l(\htz.r(\f.f h a)t)a)(\ab.b)l";
+        let reverse = encode(&process(reverse));
+        assert_eq!(run(&reverse.unwrap(), "1101").as_deref(), Some("1011"));
+    }
+}
@@ -0,0 +1,206 @@
+use std::fmt;
+use std::str::FromStr;
+
+use super::Comb;
+use crate::expr::Expr;
+
+/// Which combinators a term is translated to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Basis {
+    #[default]
+    Ski,
+    /// SKI with `B` and `C` for applications where only one side uses the
+    /// variable, as in Turner's abstraction
+    Skibc,
+    /// `B`, `C`, `K` and `W` only, `S` and `I` being written with them
+    Bckw,
+    /// `Iota` only, from the SKI translation
+    Iota,
+}
+
+impl Basis {
+    pub const NAMES: [&'static str; 4] = ["ski", "skibc", "bckw", "iota"];
+}
+
+impl FromStr for Basis {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ski" => Ok(Basis::Ski),
+            "skibc" => Ok(Basis::Skibc),
+            "bckw" => Ok(Basis::Bckw),
+            "iota" => Ok(Basis::Iota),
+            _ => Err(format!(
+                "Unknown basis: {}, expected one of {}",
+                s,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Basis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(Self::NAMES[*self as usize])
+    }
+}
+
+/// Translates `expr` to combinators by bracket abstraction, innermost
+/// abstraction first. Free variables and names stay as they are.
+///
+/// Besides `[x]x = I`, `[x]E = K E` and `[x](E F) = S ([x]E) ([x]F)`, the
+/// variable is eta reduced away, `[x](E x) = E`, wherever `E` doesn't use
+/// it, and with `B` and `C` at hand an application only gets `S` when both
+/// of its sides use the variable.
+pub fn convert(expr: &Expr, basis: Basis) -> Comb {
+    enum Job<'a> {
+        Visit(&'a Expr),
+        Abstr(u8),
+        Appl,
+    }
+
+    let mut jobs = vec![Job::Visit(expr)];
+    let mut done: Vec<Comb> = Vec::new();
+    while let Some(job) = jobs.pop() {
+        match job {
+            Job::Visit(Expr::Variable(v)) => done.push(Comb::Var(*v)),
+            Job::Visit(Expr::Name(n)) => done.push(Comb::Name(n.clone())),
+            Job::Visit(Expr::Abstr(v, b)) => {
+                jobs.push(Job::Abstr(*v));
+                jobs.push(Job::Visit(b));
+            }
+            Job::Visit(Expr::Appl(a, b)) => {
+                jobs.push(Job::Appl);
+                jobs.push(Job::Visit(b));
+                jobs.push(Job::Visit(a));
+            }
+            Job::Abstr(v) => {
+                let body = done.pop().unwrap();
+                done.push(abstract_var(v, body, basis));
+            }
+            Job::Appl => {
+                let b = done.pop().unwrap();
+                let a = done.pop().unwrap();
+                done.push(Comb::appl(a, b));
+            }
+        }
+    }
+    let comb = done.pop().unwrap();
+    match basis {
+        Basis::Iota => to_iota(comb),
+        _ => comb,
+    }
+}
+
+/// What a part of a body is, with the variable abstracted from it
+enum Part {
+    /// The part as it was, not using the variable
+    Constant(Comb),
+    /// The variable itself, which abstracts to `I`
+    Var,
+    Abstracted(Comb),
+}
+
+/// `[x]body`, for a `body` without abstractions
+fn abstract_var(x: u8, body: Comb, basis: Basis) -> Comb {
+    use Comb::*;
+    enum Job {
+        Visit(Comb),
+        Appl,
+    }
+
+    let identity = || match basis {
+        // `W K x = K x x = x`
+        Basis::Bckw => Comb::appl(W, K),
+        _ => I,
+    };
+    let abstracted = |part: Part| match part {
+        Part::Constant(c) => Comb::appl(K, c),
+        Part::Var => identity(),
+        Part::Abstracted(c) => c,
+    };
+
+    let bc = matches!(basis, Basis::Skibc | Basis::Bckw);
+    let mut jobs = vec![Job::Visit(body)];
+    let mut done: Vec<Part> = Vec::new();
+    while let Some(job) = jobs.pop() {
+        match job {
+            Job::Visit(Var(v)) if v == x => done.push(Part::Var),
+            Job::Visit(Appl(a, b)) => {
+                jobs.push(Job::Appl);
+                jobs.push(Job::Visit(*b));
+                jobs.push(Job::Visit(*a));
+            }
+            Job::Visit(c) => done.push(Part::Constant(c)),
+            Job::Appl => {
+                let b = done.pop().unwrap();
+                let a = done.pop().unwrap();
+                done.push(match (a, b) {
+                    (Part::Constant(e), Part::Constant(f)) => Part::Constant(Comb::appl(e, f)),
+                    // [x](E x) = E
+                    (Part::Constant(e), Part::Var) => Part::Abstracted(e),
+                    // [x](E x) = W ([x]E)
+                    (e, Part::Var) if basis == Basis::Bckw => {
+                        Part::Abstracted(Comb::appl(W, abstracted(e)))
+                    }
+                    // [x](E F) = B E ([x]F)
+                    (Part::Constant(e), f) if bc => {
+                        Part::Abstracted(Comb::appl(Comb::appl(B, e), abstracted(f)))
+                    }
+                    // [x](E F) = C ([x]E) F
+                    (e, Part::Constant(f)) if bc => {
+                        Part::Abstracted(Comb::appl(Comb::appl(C, abstracted(e)), f))
+                    }
+                    // [x](E F) = W (B (C ([x]E)) ([x]F)), since
+                    // `W (B (C e) f) x = C e (f x) x = e x (f x)`
+                    (e, f) if basis == Basis::Bckw => {
+                        let e = Comb::appl(C, abstracted(e));
+                        Part::Abstracted(Comb::appl(W, Comb::appl(Comb::appl(B, e), abstracted(f))))
+                    }
+                    // [x](E F) = S ([x]E) ([x]F)
+                    (e, f) => {
+                        Part::Abstracted(Comb::appl(Comb::appl(S, abstracted(e)), abstracted(f)))
+                    }
+                });
+            }
+        }
+    }
+    abstracted(done.pop().unwrap())
+}
+
+/// `comb` with `S`, `K` and `I` written with `Iota`
+pub(super) fn to_iota(comb: Comb) -> Comb {
+    use Comb::*;
+    enum Job {
+        Visit(Comb),
+        Appl,
+    }
+
+    // `Iota Iota = Iota S K = S S K K = S K (K K) = I`, and the others follow
+    let i = || Comb::appl(Iota, Iota);
+    let k = || Comb::appl(Iota, Comb::appl(Iota, i()));
+    let s = || Comb::appl(Iota, k());
+
+    let mut jobs = vec![Job::Visit(comb)];
+    let mut done: Vec<Comb> = Vec::new();
+    while let Some(job) = jobs.pop() {
+        match job {
+            Job::Visit(S) => done.push(s()),
+            Job::Visit(K) => done.push(k()),
+            Job::Visit(I) => done.push(i()),
+            Job::Visit(Appl(a, b)) => {
+                jobs.push(Job::Appl);
+                jobs.push(Job::Visit(*b));
+                jobs.push(Job::Visit(*a));
+            }
+            Job::Visit(c) => done.push(c),
+            Job::Appl => {
+                let b = done.pop().unwrap();
+                let a = done.pop().unwrap();
+                done.push(Comb::appl(a, b));
+            }
+        }
+    }
+    done.pop().unwrap()
+}
@@ -0,0 +1,274 @@
+use std::collections::{HashMap, HashSet};
+
+use super::bracket::to_iota;
+use super::{convert, Basis, Comb};
+use crate::eval::{EvalError, Numerals};
+use crate::expr::Defs;
+
+/// Index into the nodes of a graph
+type Ref = u32;
+
+#[derive(Clone, Debug)]
+enum Node {
+    /// A combinator, variable or name
+    Leaf(Comb),
+    Appl(Ref, Ref),
+    /// Reduced to the node it refers to
+    Ind(Ref),
+}
+
+/// A graph reducer for combinator terms.
+///
+/// A redex is overwritten with what it reduces to, so every term that
+/// shares it sees it reduced, and arguments that a combinator uses more than
+/// once are shared rather than copied. Names are unfolded to definitions
+/// converted to the same basis as needed, and numerals to `numerals`.
+pub struct Graph<'d> {
+    nodes: Vec<Node>,
+    defs: &'d Defs,
+    basis: Basis,
+    /// Where every name unfolded so far was put
+    globals: HashMap<String, Ref>,
+    steps: u64,
+    pub max_steps: u64,
+    pub max_nodes: usize,
+    pub numerals: Numerals,
+}
+
+/// Reduces `comb` to its normal form with `Graph`.
+pub fn reduce(comb: &Comb, defs: &Defs, basis: Basis) -> Result<Comb, Box<EvalError>> {
+    Graph::new(defs, basis).normalize(comb)
+}
+
+impl<'d> Graph<'d> {
+    pub fn new(defs: &'d Defs, basis: Basis) -> Self {
+        Graph {
+            nodes: Vec::new(),
+            defs,
+            basis,
+            globals: HashMap::new(),
+            steps: 0,
+            max_steps: 10000000,
+            max_nodes: 20000000,
+            numerals: Numerals::Church,
+        }
+    }
+
+    /// Combinator reductions performed so far
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Reduces every redex of `comb`, the head first and then the
+    /// arguments from the left.
+    ///
+    /// In Iota, the `S` and `K` that `Iota` reduces to are written with
+    /// `Iota` again in the normal form. A term that unfolds into itself
+    /// below its head, like `L = \\f.f L`, has no finite normal form and
+    /// gives an error.
+    pub fn normalize(&mut self, comb: &Comb) -> Result<Comb, Box<EvalError>> {
+        let root = self.import(comb)?;
+        // A shared node is normalized in place, so once is enough
+        let mut normalized = HashSet::new();
+        let mut todo = vec![root];
+        while let Some(node) = todo.pop() {
+            if !normalized.insert(self.resolve(node)) {
+                continue;
+            }
+            let args = self.whnf(node)?;
+            todo.extend(args.into_iter().rev());
+        }
+        let normal = self.export(root)?;
+        Ok(match self.basis {
+            Basis::Iota => to_iota(normal),
+            _ => normal,
+        })
+    }
+
+    fn alloc(&mut self, node: Node) -> Result<Ref, Box<EvalError>> {
+        if self.nodes.len() >= self.max_nodes {
+            return Err(EvalError::boxed(format!(
+                "Graph outgrew maximum size: {} nodes",
+                self.max_nodes
+            )));
+        }
+        self.nodes.push(node);
+        Ok(self.nodes.len() as Ref - 1)
+    }
+
+    fn appl(&mut self, f: Ref, x: Ref) -> Result<Ref, Box<EvalError>> {
+        self.alloc(Node::Appl(f, x))
+    }
+
+    fn leaf(&mut self, comb: Comb) -> Result<Ref, Box<EvalError>> {
+        self.alloc(Node::Leaf(comb))
+    }
+
+    fn resolve(&self, mut node: Ref) -> Ref {
+        while let Node::Ind(to) = self.nodes[node as usize] {
+            node = to;
+        }
+        node
+    }
+
+    /// Reduces `node` until its head can't be reduced, giving back the
+    /// arguments the head is applied to.
+    fn whnf(&mut self, node: Ref) -> Result<Vec<Ref>, Box<EvalError>> {
+        loop {
+            // The applications along the spine, innermost first
+            let mut spine = Vec::new();
+            let mut head = self.resolve(node);
+            while let Node::Appl(f, _) = self.nodes[head as usize] {
+                spine.push(head);
+                head = self.resolve(f);
+            }
+            spine.reverse();
+            let arg = |graph: &Self, i: usize| match graph.nodes[spine[i] as usize] {
+                Node::Appl(_, x) => x,
+                _ => unreachable!(),
+            };
+
+            let comb = match &self.nodes[head as usize] {
+                Node::Leaf(comb) => comb.clone(),
+                _ => unreachable!(),
+            };
+            if let Comb::Name(name) = &comb {
+                match self.unfold(name)? {
+                    Some(value) => {
+                        self.nodes[head as usize] = Node::Ind(value);
+                        continue;
+                    }
+                    None => return Ok((0..spine.len()).map(|i| arg(self, i)).collect()),
+                }
+            }
+            let arity = comb.arity();
+            if arity == 0 || spine.len() < arity {
+                return Ok((0..spine.len()).map(|i| arg(self, i)).collect());
+            }
+
+            if self.steps == self.max_steps {
+                return Err(EvalError::boxed(format!(
+                    "Step limit reached: {}",
+                    self.max_steps
+                )));
+            }
+            self.steps += 1;
+            let redex = spine[arity - 1];
+            let x = arg(self, 0);
+            let reduced = match comb {
+                Comb::I | Comb::K => Node::Ind(x),
+                Comb::S => {
+                    let (y, z) = (arg(self, 1), arg(self, 2));
+                    Node::Appl(self.appl(x, z)?, self.appl(y, z)?)
+                }
+                Comb::B => {
+                    let (y, z) = (arg(self, 1), arg(self, 2));
+                    Node::Appl(x, self.appl(y, z)?)
+                }
+                Comb::C => {
+                    let (y, z) = (arg(self, 1), arg(self, 2));
+                    Node::Appl(self.appl(x, z)?, y)
+                }
+                Comb::W => {
+                    let y = arg(self, 1);
+                    Node::Appl(self.appl(x, y)?, y)
+                }
+                Comb::Iota => {
+                    let s = self.leaf(Comb::S)?;
+                    let k = self.leaf(Comb::K)?;
+                    Node::Appl(self.appl(x, s)?, k)
+                }
+                Comb::Var(_) | Comb::Name(_) | Comb::Appl(_, _) => unreachable!(),
+            };
+            self.nodes[redex as usize] = reduced;
+        }
+    }
+
+    /// Where the value of a definition or numeral is, `None` for other names
+    fn unfold(&mut self, name: &str) -> Result<Option<Ref>, Box<EvalError>> {
+        if let Some(&node) = self.globals.get(name) {
+            return Ok(Some(node));
+        }
+        let value = match self.defs.get(name) {
+            Some(def) => def.value.clone(),
+            None => match name.parse() {
+                Ok(n) => self.numerals.encode(n).map_err(EvalError::boxed)?,
+                Err(_) => return Ok(None),
+            },
+        };
+        let node = self.import(&convert(&value, self.basis))?;
+        self.globals.insert(name.to_owned(), node);
+        Ok(Some(node))
+    }
+
+    fn import(&mut self, comb: &Comb) -> Result<Ref, Box<EvalError>> {
+        enum Job<'a> {
+            Visit(&'a Comb),
+            Appl,
+        }
+
+        let mut jobs = vec![Job::Visit(comb)];
+        let mut done: Vec<Ref> = Vec::new();
+        while let Some(job) = jobs.pop() {
+            match job {
+                Job::Visit(Comb::Appl(a, b)) => {
+                    jobs.push(Job::Appl);
+                    jobs.push(Job::Visit(b));
+                    jobs.push(Job::Visit(a));
+                }
+                Job::Visit(leaf) => {
+                    let node = self.leaf(leaf.clone())?;
+                    done.push(node);
+                }
+                Job::Appl => {
+                    let b = done.pop().unwrap();
+                    let a = done.pop().unwrap();
+                    let node = self.appl(a, b)?;
+                    done.push(node);
+                }
+            }
+        }
+        Ok(done.pop().unwrap())
+    }
+
+    /// The term at `node`, with shared nodes copied wherever they are used,
+    /// or an error if it contains itself
+    fn export(&self, node: Ref) -> Result<Comb, Box<EvalError>> {
+        enum Job {
+            Visit(Ref),
+            Appl(Ref),
+        }
+
+        // The applications being exported, which their parts can't be
+        let mut path: HashSet<Ref> = HashSet::new();
+        let mut jobs = vec![Job::Visit(node)];
+        let mut done: Vec<Comb> = Vec::new();
+        while let Some(job) = jobs.pop() {
+            match job {
+                Job::Visit(node) => {
+                    let node = self.resolve(node);
+                    match &self.nodes[node as usize] {
+                        Node::Leaf(comb) => done.push(comb.clone()),
+                        Node::Appl(..) if !path.insert(node) => {
+                            let msg = "No finite normal form, the term contains itself";
+                            return Err(EvalError::boxed(msg));
+                        }
+                        Node::Appl(a, b) => {
+                            jobs.push(Job::Appl(node));
+                            jobs.push(Job::Visit(*b));
+                            jobs.push(Job::Visit(*a));
+                        }
+                        Node::Ind(_) => unreachable!(),
+                    }
+                }
+                Job::Appl(node) => {
+                    path.remove(&node);
+                    let b = done.pop().unwrap();
+                    let a = done.pop().unwrap();
+                    done.push(Comb::appl(a, b));
+                }
+            }
+        }
+        Ok(done.pop().unwrap())
+    }
+}
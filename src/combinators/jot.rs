@@ -0,0 +1,45 @@
+//! Jot, where every string of bits is a program: the empty one is `I`, a
+//! `0` applies what comes before it to `S` and `K`, and a `1` turns it from
+//! `F` into `\xy.F(xy)`, which is `S (K F)`.
+
+use std::error::Error;
+
+use super::Comb;
+
+/// The bits of an SKI term, `K` being `11100`, `S` being `11111000` and the
+/// application `F A` being `1` followed by `F` and then `A`. `I` is written
+/// as `S K K`.
+///
+/// Variables, names and the combinators of other bases have no bits.
+pub fn to_jot(comb: &Comb) -> Result<String, Box<dyn Error>> {
+    let mut out = String::new();
+    let mut todo = vec![comb];
+    while let Some(comb) = todo.pop() {
+        match comb {
+            Comb::S => out.push_str("11111000"),
+            Comb::K => out.push_str("11100"),
+            // S K K
+            Comb::I => out.push_str("11111110001110011100"),
+            Comb::Appl(a, b) => {
+                out.push('1');
+                todo.push(b);
+                todo.push(a);
+            }
+            other => return Err(format!("{} has no Jot form", other).into()),
+        }
+    }
+    Ok(out)
+}
+
+/// The SKI term of a string of bits
+pub fn from_jot(bits: &str) -> Result<Comb, Box<dyn Error>> {
+    let mut comb = Comb::I;
+    for bit in bits.chars() {
+        comb = match bit {
+            '0' => Comb::appl(Comb::appl(comb, Comb::S), Comb::K),
+            '1' => Comb::appl(Comb::S, Comb::appl(Comb::K, comb)),
+            _ => return Err(format!("Not a bit of Jot: {:?}", bit).into()),
+        };
+    }
+    Ok(comb)
+}
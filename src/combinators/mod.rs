@@ -0,0 +1,141 @@
+use std::fmt;
+
+use crate::expr::expr_aliases::*;
+use crate::expr::Expr;
+use crate::{vabstr, vappl};
+
+pub mod bracket;
+pub mod graph;
+pub mod jot;
+
+pub use bracket::{convert, Basis};
+pub use graph::{reduce, Graph};
+pub use jot::{from_jot, to_jot};
+
+#[cfg(test)]
+mod tests;
+
+/// A term of combinatory logic, with the combinators of every basis that
+/// `convert` translates to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Comb {
+    /// `\xyz.xz(yz)`
+    S,
+    /// `\ab.a`
+    K,
+    /// `\a.a`
+    I,
+    /// `\xyz.x(yz)`
+    B,
+    /// `\xyz.xzy`
+    C,
+    /// `\xy.xyy`
+    W,
+    /// `\f.f S K`, the only combinator of Iota
+    Iota,
+    Var(u8),
+    Name(String),
+    Appl(Box<Comb>, Box<Comb>),
+}
+
+impl Comb {
+    pub fn appl(f: Comb, x: Comb) -> Comb {
+        Comb::Appl(Box::new(f), Box::new(x))
+    }
+
+    /// How many arguments the combinator takes to reduce, 0 for the rest
+    pub fn arity(&self) -> usize {
+        match self {
+            Comb::I | Comb::Iota => 1,
+            Comb::K | Comb::W => 2,
+            Comb::S | Comb::B | Comb::C => 3,
+            Comb::Var(_) | Comb::Name(_) | Comb::Appl(_, _) => 0,
+        }
+    }
+
+    /// The lambda term of a combinator, `None` for the rest
+    fn lambda(&self) -> Option<Box<Expr>> {
+        let (x, y, z) = (b'x', b'y', b'z');
+        Some(match self {
+            Comb::S => vabstr!(x, y, z, vappl!(var(x), var(z), appl(var(y), var(z)))),
+            Comb::K => vabstr!(x, y, var(x)),
+            Comb::I => abstr(x, var(x)),
+            Comb::B => vabstr!(x, y, z, appl(var(x), appl(var(y), var(z)))),
+            Comb::C => vabstr!(x, y, z, vappl!(var(x), var(z), var(y))),
+            Comb::W => vabstr!(x, y, vappl!(var(x), var(y), var(y))),
+            Comb::Iota => abstr(x, vappl!(var(x), Comb::S.lambda()?, Comb::K.lambda()?)),
+            Comb::Var(_) | Comb::Name(_) | Comb::Appl(_, _) => return None,
+        })
+    }
+
+    /// The lambda term with every combinator replaced by its definition.
+    ///
+    /// It reduces to the normal form of the term it was converted from, up
+    /// to eta.
+    pub fn to_expr(&self) -> Box<Expr> {
+        enum Job<'a> {
+            Visit(&'a Comb),
+            Appl,
+        }
+
+        let mut jobs = vec![Job::Visit(self)];
+        let mut done: Vec<Box<Expr>> = Vec::new();
+        while let Some(job) = jobs.pop() {
+            match job {
+                Job::Visit(Comb::Var(v)) => done.push(Box::new(Expr::Variable(*v))),
+                Job::Visit(Comb::Name(n)) => done.push(Box::new(Expr::Name(n.clone()))),
+                Job::Visit(Comb::Appl(a, b)) => {
+                    jobs.push(Job::Appl);
+                    jobs.push(Job::Visit(b));
+                    jobs.push(Job::Visit(a));
+                }
+                Job::Visit(comb) => done.push(comb.lambda().unwrap()),
+                Job::Appl => {
+                    let b = done.pop().unwrap();
+                    let a = done.pop().unwrap();
+                    done.push(Box::new(Expr::Appl(a, b)));
+                }
+            }
+        }
+        done.pop().unwrap()
+    }
+}
+
+/// Applications are written with spaces, and combinators with the names
+/// `res/lib` defines them under, so that the output reads back as a term.
+impl fmt::Display for Comb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        enum Job<'a> {
+            Visit(&'a Comb),
+            Str(&'static str),
+        }
+
+        let mut jobs = vec![Job::Visit(self)];
+        while let Some(job) = jobs.pop() {
+            match job {
+                Job::Str(s) => f.write_str(s)?,
+                Job::Visit(Comb::S) => f.write_str("S")?,
+                Job::Visit(Comb::K) => f.write_str("K")?,
+                Job::Visit(Comb::I) => f.write_str("I")?,
+                Job::Visit(Comb::B) => f.write_str("B")?,
+                Job::Visit(Comb::C) => f.write_str("C")?,
+                Job::Visit(Comb::W) => f.write_str("W")?,
+                Job::Visit(Comb::Iota) => f.write_str("Iota")?,
+                Job::Visit(Comb::Var(v)) => write!(f, "{}", std::ascii::escape_default(*v))?,
+                Job::Visit(Comb::Name(n)) => f.write_str(n)?,
+                Job::Visit(Comb::Appl(a, b)) => {
+                    if let Comb::Appl(_, _) = **b {
+                        jobs.push(Job::Str(")"));
+                        jobs.push(Job::Visit(b));
+                        jobs.push(Job::Str(" ("));
+                    } else {
+                        jobs.push(Job::Visit(b));
+                        jobs.push(Job::Str(" "));
+                    }
+                    jobs.push(Job::Visit(a));
+                }
+            }
+        }
+        Ok(())
+    }
+}
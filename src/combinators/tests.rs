@@ -0,0 +1,127 @@
+use super::*;
+use crate::eval;
+use crate::expr::{Def, Defs};
+use crate::test::*;
+
+fn lib() -> Defs {
+    let src = include_bytes!("../../res/lib");
+    parse::parse(lex::lex(&src[..]).unwrap()).unwrap().0
+}
+
+const BASES: [Basis; 4] = [Basis::Ski, Basis::Skibc, Basis::Bckw, Basis::Iota];
+
+fn normal(expr: Box<Expr>, defs: &Defs) -> Box<Expr> {
+    eval::reduce(expr, defs).0.unwrap()
+}
+
+#[test]
+fn bracket_abstraction() {
+    let ski = |src| convert(&process(src), Basis::Ski).to_string();
+    assert_eq!(ski(r"\x.x"), "I");
+    assert_eq!(ski(r"\xy.x"), "K");
+    assert_eq!(ski(r"\xyz.xz(yz)"), "S");
+    assert_eq!(ski(r"\xy.y"), "K I");
+    assert_eq!(ski(r"\xy.yx"), "S (K (S I)) K");
+    assert_eq!(ski(r"\y.f y y"), "S f I");
+
+    let skibc = |src| convert(&process(src), Basis::Skibc).to_string();
+    assert_eq!(skibc(r"\xyz.x(yz)"), "B");
+    assert_eq!(skibc(r"\xyz.xzy"), "C");
+    assert_eq!(skibc(r"\xy.yx"), "C I");
+
+    let bckw = |src| convert(&process(src), Basis::Bckw).to_string();
+    assert_eq!(bckw(r"\xy.xyy"), "W");
+    assert_eq!(bckw(r"\x.x"), "W K");
+
+    assert_eq!(
+        convert(&process(r"\x.x"), Basis::Iota).to_string(),
+        "Iota Iota"
+    );
+}
+
+#[test]
+fn same_normal_forms() {
+    let defs = lib();
+    for src in [
+        "Add 2 3",
+        "Pair (Succ 2) (Fst (Pair 1 0))",
+        r"\xy.x y (Not y)",
+        "Fact 3",
+        "RecFact 2",
+    ] {
+        let expected = normal(process(src), &defs);
+        for basis in BASES {
+            let comb = convert(&process(src), basis);
+            let got = normal(comb.to_expr(), &defs);
+            assert!(got.alpha_eq(&expected), "{} in {}: {}", src, basis, got);
+        }
+    }
+}
+
+#[test]
+fn graph_reduction() {
+    let defs = lib();
+    for src in [
+        "Mul 2 3",
+        "Fact 3",
+        "RecFact 3",
+        "Sub 7 3",
+        "Pair x (Snd (Pair 0 y))",
+    ] {
+        let expected = normal(process(src), &defs);
+        for basis in BASES {
+            let reduced = reduce(&convert(&process(src), basis), &defs, basis).unwrap();
+            let got = normal(reduced.to_expr(), &defs);
+            assert!(got.alpha_eq(&expected), "{} in {}: {}", src, basis, got);
+        }
+    }
+
+    // Weak reduction stops at combinators short of arguments
+    let reduced = reduce(&convert(&process(r"S K x"), Basis::Ski), &defs, Basis::Ski);
+    assert_eq!(reduced.unwrap().to_string(), "S K x");
+    let reduced = reduce(
+        &convert(&process(r"K (I x) y"), Basis::Ski),
+        &defs,
+        Basis::Ski,
+    );
+    assert_eq!(reduced.unwrap().to_string(), "x");
+
+    let mut graph = Graph::new(&defs, Basis::Ski);
+    graph.max_steps = 1000;
+    assert!(graph
+        .normalize(&convert(&process("Y I"), Basis::Ski))
+        .is_err());
+    assert_eq!(graph.steps(), 1000);
+
+    // A term that only unfolds into itself under its head ends too
+    let mut defs = defs;
+    defs.insert("L".into(), Def::new(process(r"\f.f L")));
+    let err = reduce(&convert(&process("L"), Basis::Ski), &defs, Basis::Ski).unwrap_err();
+    assert!(err.to_string().contains("finite"), "{}", err);
+
+    // Numerals unfold as the graph is told to
+    let mut graph = Graph::new(&defs, Basis::Ski);
+    graph.numerals = eval::Numerals::Scott;
+    let reduced = graph.normalize(&Comb::Name("2".into())).unwrap();
+    let got = normal(reduced.to_expr(), &defs);
+    assert!(got.alpha_eq(&scott_num(2)), "{}", got);
+}
+
+#[test]
+fn jot() {
+    let defs = lib();
+    assert_eq!(to_jot(&Comb::K).unwrap(), "11100");
+    assert!(normal(from_jot("11100").unwrap().to_expr(), &defs).alpha_eq(&process(r"\ab.a")));
+    assert!(normal(from_jot("").unwrap().to_expr(), &defs).alpha_eq(&process(r"\a.a")));
+
+    let unfolded = eval::util::unfold(&process("Add 2 3"), &defs, &eval::Config::default());
+    let bits = to_jot(&convert(&unfolded, Basis::Ski)).unwrap();
+    assert!(bits.chars().all(|c| c == '0' || c == '1'));
+    let reduced = reduce(&from_jot(&bits).unwrap(), &defs, Basis::Ski).unwrap();
+    let got = normal(reduced.to_expr(), &defs);
+    assert_eq!(got.try_unchurch_num(), Some(5));
+
+    assert!(to_jot(&convert(&process("Add 2 3"), Basis::Ski)).is_err());
+    assert!(to_jot(&convert(&process(r"\x.y"), Basis::Ski)).is_err());
+    assert!(from_jot("102").is_err());
+}
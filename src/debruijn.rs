@@ -0,0 +1,593 @@
+use crate::expr::Expr;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+/// A term with de Bruijn indices in place of bound variables.
+///
+/// `Bound(0)` refers to the innermost enclosing abstraction. Abstractions keep
+/// the name of their parameter as a hint, so converting back to `Expr` renames
+/// only what would otherwise be captured.
+///
+/// As text, written and parsed by `Display` and `parse`, indices count from
+/// 1 as in most papers: `\\2(12)` is `\xy.x(yx)`. An index is a single digit,
+/// or any number in braces like `{12}`. `λ` can stand for `\`, names start
+/// with a capital letter or `_`, and a lowercase letter is a free variable.
+/// Other names, like those of numerals, are quoted as in `"3"`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DeBruijn {
+    Bound(u32),
+    Free(u8),
+    Name(String),
+    Abstr(u8, Box<DeBruijn>),
+    Appl(Box<DeBruijn>, Box<DeBruijn>),
+}
+
+impl DeBruijn {
+    pub fn from_expr(expr: &Expr) -> Box<DeBruijn> {
+        use DeBruijn::*;
+        enum Job<'a> {
+            Visit(&'a Expr),
+            Abstr(u8),
+            Appl,
+        }
+
+        // Innermost binding level of every variable name
+        let mut scopes: HashMap<u8, Vec<u32>> = HashMap::new();
+        let mut level = 0;
+        let mut jobs = vec![Job::Visit(expr)];
+        let mut done: Vec<Box<DeBruijn>> = Vec::new();
+        while let Some(job) = jobs.pop() {
+            match job {
+                Job::Visit(Expr::Variable(v)) => {
+                    done.push(Box::new(match scopes.get(v).and_then(|s| s.last()) {
+                        Some(l) => Bound(level - 1 - l),
+                        None => Free(*v),
+                    }))
+                }
+                Job::Visit(Expr::Name(n)) => done.push(Box::new(Name(n.clone()))),
+                Job::Visit(Expr::Abstr(p, b)) => {
+                    scopes.entry(*p).or_default().push(level);
+                    level += 1;
+                    jobs.push(Job::Abstr(*p));
+                    jobs.push(Job::Visit(b));
+                }
+                Job::Visit(Expr::Appl(a, b)) => {
+                    jobs.push(Job::Appl);
+                    jobs.push(Job::Visit(b));
+                    jobs.push(Job::Visit(a));
+                }
+                Job::Abstr(p) => {
+                    scopes.get_mut(&p).and_then(Vec::pop);
+                    level -= 1;
+                    let body = done.pop().unwrap();
+                    done.push(Box::new(Abstr(p, body)));
+                }
+                Job::Appl => {
+                    let b = done.pop().unwrap();
+                    let a = done.pop().unwrap();
+                    done.push(Box::new(Appl(a, b)));
+                }
+            }
+        }
+        done.pop().unwrap()
+    }
+
+    /// Names every abstraction, preferring its hint and then `a..=z`.
+    ///
+    /// A parameter never shadows an outer parameter it would capture, nor a
+    /// free variable of its body.
+    pub fn to_expr(&self) -> Box<Expr> {
+        use DeBruijn::*;
+
+        // Pass 1: free indices and free variables below every abstraction,
+        // numbered in preorder
+        struct Free {
+            indices: Vec<u32>,
+            vars: Vec<u8>,
+        }
+        fn merge<T: Ord + Copy>(a: &[T], b: &[T]) -> Vec<T> {
+            let mut v: Vec<T> = a.iter().chain(b).copied().collect();
+            v.sort_unstable();
+            v.dedup();
+            v
+        }
+        enum Up<'a> {
+            Visit(&'a DeBruijn),
+            Abstr(usize),
+            Appl,
+        }
+        let mut frees: Vec<Option<Free>> = Vec::new();
+        let mut jobs = vec![Up::Visit(self)];
+        let mut done: Vec<Free> = Vec::new();
+        while let Some(job) = jobs.pop() {
+            match job {
+                Up::Visit(Bound(i)) => done.push(Free {
+                    indices: vec![*i],
+                    vars: vec![],
+                }),
+                Up::Visit(Free(v)) => done.push(Free {
+                    indices: vec![],
+                    vars: vec![*v],
+                }),
+                Up::Visit(Name(_)) => done.push(Free {
+                    indices: vec![],
+                    vars: vec![],
+                }),
+                Up::Visit(Abstr(_, b)) => {
+                    frees.push(None);
+                    jobs.push(Up::Abstr(frees.len() - 1));
+                    jobs.push(Up::Visit(b));
+                }
+                Up::Visit(Appl(a, b)) => {
+                    jobs.push(Up::Appl);
+                    jobs.push(Up::Visit(b));
+                    jobs.push(Up::Visit(a));
+                }
+                Up::Abstr(id) => {
+                    let body = done.pop().unwrap();
+                    let this = Free {
+                        indices: body
+                            .indices
+                            .iter()
+                            .filter(|&&i| i > 0)
+                            .map(|i| i - 1)
+                            .collect(),
+                        vars: body.vars.clone(),
+                    };
+                    // What the parameter must not be called
+                    frees[id] = Some(body);
+                    done.push(this);
+                }
+                Up::Appl => {
+                    let b = done.pop().unwrap();
+                    let a = done.pop().unwrap();
+                    done.push(Free {
+                        indices: merge(&a.indices, &b.indices),
+                        vars: merge(&a.vars, &b.vars),
+                    });
+                }
+            }
+        }
+
+        // Pass 2: pick names top-down and build the expression
+        enum Down<'a> {
+            Visit(&'a DeBruijn),
+            Abstr(u8),
+            Appl,
+        }
+        let mut names: Vec<u8> = Vec::new();
+        let mut next_abstr = 0;
+        let mut jobs = vec![Down::Visit(self)];
+        let mut done: Vec<Box<Expr>> = Vec::new();
+        while let Some(job) = jobs.pop() {
+            match job {
+                Down::Visit(Bound(i)) => {
+                    let v = names[names.len() - 1 - *i as usize];
+                    done.push(Box::new(Expr::Variable(v)))
+                }
+                Down::Visit(Free(v)) => done.push(Box::new(Expr::Variable(*v))),
+                Down::Visit(Name(n)) => done.push(Box::new(Expr::Name(n.clone()))),
+                Down::Visit(Abstr(hint, b)) => {
+                    let body = frees[next_abstr].as_ref().unwrap();
+                    next_abstr += 1;
+                    let taken = |c: u8| {
+                        body.vars.contains(&c)
+                            || body
+                                .indices
+                                .iter()
+                                .filter(|&&i| i > 0 && (i as usize) <= names.len())
+                                .any(|&i| names[names.len() - i as usize] == c)
+                    };
+                    let name = std::iter::once(*hint)
+                        .chain(b'a'..=b'z')
+                        .chain((0..=u8::MAX).filter(|c| !c.is_ascii_lowercase()))
+                        .find(|&c| !taken(c))
+                        .expect("Ran out of variables");
+                    names.push(name);
+                    jobs.push(Down::Abstr(name));
+                    jobs.push(Down::Visit(b));
+                }
+                Down::Visit(Appl(a, b)) => {
+                    jobs.push(Down::Appl);
+                    jobs.push(Down::Visit(b));
+                    jobs.push(Down::Visit(a));
+                }
+                Down::Abstr(p) => {
+                    names.pop();
+                    let body = done.pop().unwrap();
+                    done.push(Box::new(Expr::Abstr(p, body)));
+                }
+                Down::Appl => {
+                    let b = done.pop().unwrap();
+                    let a = done.pop().unwrap();
+                    done.push(Box::new(Expr::Appl(a, b)));
+                }
+            }
+        }
+        done.pop().unwrap()
+    }
+
+    /// Reads a term written with indices, see above.
+    pub fn parse(src: &str) -> Result<Box<DeBruijn>, Box<dyn Error>> {
+        use DeBruijn::*;
+        enum Item {
+            Abstr,
+            Paren(usize),
+            Term(Box<DeBruijn>),
+        }
+        fn append(items: &mut Vec<Item>, term: Box<DeBruijn>) {
+            match items.pop() {
+                Some(Item::Term(f)) => items.push(Item::Term(Box::new(Appl(f, term)))),
+                Some(other) => {
+                    items.push(other);
+                    items.push(Item::Term(term));
+                }
+                None => items.push(Item::Term(term)),
+            }
+        }
+        /// Closes everything since the last parenthesis, giving back the
+        /// term it makes
+        fn close(
+            items: &mut Vec<Item>,
+            depth: &mut usize,
+            at: usize,
+        ) -> Result<Box<DeBruijn>, Box<dyn Error>> {
+            let mut term = match items.pop() {
+                Some(Item::Term(term)) => term,
+                other => {
+                    items.extend(other);
+                    return Err(format!("Term missing at {}", at).into());
+                }
+            };
+            // An abstraction reaches as far right as it can, so it may be
+            // the argument of a term before it
+            loop {
+                match items.pop() {
+                    Some(Item::Abstr) => {
+                        *depth -= 1;
+                        term = Box::new(Abstr(depth_hint(*depth), term));
+                    }
+                    Some(Item::Term(f)) => term = Box::new(Appl(f, term)),
+                    Some(paren) => {
+                        items.push(paren);
+                        return Ok(term);
+                    }
+                    None => return Ok(term),
+                }
+            }
+        }
+
+        let mut items: Vec<Item> = Vec::new();
+        let mut depth = 0;
+        let mut chars = src.char_indices().peekable();
+        while let Some((at, c)) = chars.next() {
+            let at = at + 1;
+            let index = match c {
+                _ if c.is_whitespace() => continue,
+                '\\' | 'λ' => {
+                    // Some write a dot after the lambda
+                    chars.next_if(|&(_, c)| c == '.');
+                    items.push(Item::Abstr);
+                    depth += 1;
+                    continue;
+                }
+                '(' => {
+                    items.push(Item::Paren(at));
+                    continue;
+                }
+                ')' => {
+                    let term = close(&mut items, &mut depth, at)?;
+                    match items.pop() {
+                        Some(Item::Paren(_)) => append(&mut items, term),
+                        _ => return Err(format!("Unmatched ')' at {}", at).into()),
+                    }
+                    continue;
+                }
+                'a'..='z' => {
+                    append(&mut items, Box::new(Free(c as u8)));
+                    continue;
+                }
+                'A'..='Z' | '_' => {
+                    let mut name = c.to_string();
+                    while let Some((_, c)) =
+                        chars.next_if(|(_, c)| c.is_ascii_alphanumeric() || *c == '_' || *c == '\'')
+                    {
+                        name.push(c);
+                    }
+                    append(&mut items, Box::new(Name(name)));
+                    continue;
+                }
+                '"' => {
+                    let mut name = String::new();
+                    while let Some((_, c)) = chars.next_if(|(_, c)| *c != '"') {
+                        name.push(c);
+                    }
+                    if chars.next().is_none() || name.is_empty() {
+                        return Err(format!("Bad name at {}", at).into());
+                    }
+                    append(&mut items, Box::new(Name(name)));
+                    continue;
+                }
+                '0'..='9' => c.to_digit(10).unwrap(),
+                '{' => {
+                    let mut digits = String::new();
+                    while let Some((_, c)) = chars.next_if(|(_, c)| *c != '}') {
+                        digits.push(c);
+                    }
+                    if chars.next().is_none() {
+                        return Err(format!("Unmatched '{{' at {}", at).into());
+                    }
+                    digits
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("Bad index {{{}}} at {}", digits, at))?
+                }
+                _ => return Err(format!("Unexpected {:?} at {}", c, at).into()),
+            };
+            if index == 0 || index as usize > depth {
+                return Err(
+                    format!("Index {} at {} has no abstraction to refer to", index, at).into(),
+                );
+            }
+            append(&mut items, Box::new(Bound(index - 1)));
+        }
+        let term = close(&mut items, &mut depth, src.len() + 1);
+        match items.pop() {
+            Some(Item::Paren(at)) => Err(format!("Unmatched '(' at {}", at).into()),
+            _ => term,
+        }
+    }
+
+    /// Whether `Bound(index)` occurs free, counting from this term's context.
+    pub fn references(&self, index: u32) -> bool {
+        use DeBruijn::*;
+        let mut stack = vec![(self, index)];
+        while let Some((term, i)) = stack.pop() {
+            match term {
+                Bound(j) if *j == i => return true,
+                Bound(_) | Free(_) | Name(_) => {}
+                Abstr(_, b) => stack.push((b, i + 1)),
+                Appl(a, b) => {
+                    stack.push((b, i));
+                    stack.push((a, i));
+                }
+            }
+        }
+        false
+    }
+
+    /// Adds `by` to every free index at or above `cutoff`.
+    ///
+    /// Panics when a referenced index would become negative.
+    pub fn shift(&mut self, by: i64, cutoff: u32) {
+        use DeBruijn::*;
+        let mut stack = vec![(self, cutoff)];
+        while let Some((term, c)) = stack.pop() {
+            match term {
+                Bound(i) if *i >= c => {
+                    let shifted = *i as i64 + by;
+                    assert!(shifted >= 0, "index {} shifted below 0 by {}", i, by);
+                    *i = shifted as u32
+                }
+                Bound(_) | Free(_) | Name(_) => {}
+                Abstr(_, b) => stack.push((b, c + 1)),
+                Appl(a, b) => {
+                    stack.push((b, c));
+                    stack.push((a, c));
+                }
+            }
+        }
+    }
+}
+
+/// A parameter name for an abstraction under `depth` others, so that nested
+/// ones differ
+pub(crate) fn depth_hint(depth: usize) -> u8 {
+    b'a' + (depth % 26) as u8
+}
+
+/// Whether `parse` reads `name` back as a name without quotes
+fn is_plain(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some('A'..='Z' | '_'))
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '\'')
+}
+
+impl fmt::Display for DeBruijn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use DeBruijn::*;
+        enum Job<'a> {
+            Visit(&'a DeBruijn),
+            Str(&'static str),
+        }
+
+        let mut jobs = vec![Job::Visit(self)];
+        // Whether an index or variable, or else a name, was written last.
+        // Names are set apart from those next to them, which they would run
+        // into.
+        let (mut after_atom, mut after_name) = (false, false);
+        while let Some(job) = jobs.pop() {
+            let atom = match job {
+                Job::Visit(Bound(i)) if *i < 9 => (i + 1).to_string(),
+                Job::Visit(Bound(i)) => format!("{{{}}}", i + 1),
+                Job::Visit(Free(v)) => std::ascii::escape_default(*v).to_string(),
+                Job::Visit(Name(n)) => {
+                    if after_atom || after_name {
+                        f.write_str(" ")?;
+                    }
+                    if is_plain(n) {
+                        f.write_str(n)?;
+                    } else {
+                        write!(f, "\"{}\"", n)?;
+                    }
+                    after_name = true;
+                    after_atom = false;
+                    continue;
+                }
+                Job::Visit(Abstr(_, b)) => {
+                    jobs.push(Job::Visit(b));
+                    jobs.push(Job::Str("\\"));
+                    continue;
+                }
+                Job::Visit(Appl(a, b)) => {
+                    if let Appl(_, _) | Abstr(_, _) = **b {
+                        jobs.push(Job::Str(")"));
+                        jobs.push(Job::Visit(b));
+                        jobs.push(Job::Str("("));
+                    } else {
+                        jobs.push(Job::Visit(b));
+                    }
+                    if let Abstr(_, _) = **a {
+                        jobs.push(Job::Str(")"));
+                        jobs.push(Job::Visit(a));
+                        jobs.push(Job::Str("("));
+                    } else {
+                        jobs.push(Job::Visit(a));
+                    }
+                    continue;
+                }
+                Job::Str(s) => {
+                    f.write_str(s)?;
+                    after_atom = false;
+                    after_name = false;
+                    continue;
+                }
+            };
+            if after_name {
+                f.write_str(" ")?;
+            }
+            f.write_str(&atom)?;
+            after_atom = true;
+            after_name = false;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DeBruijn {
+    fn drop(&mut self) {
+        use DeBruijn::*;
+        fn take(b: &mut DeBruijn) -> DeBruijn {
+            std::mem::replace(b, Bound(0))
+        }
+        // Same as for `Expr`: move children out before their boxes are freed
+        let mut stack: Vec<DeBruijn> = Vec::new();
+        match self {
+            Abstr(_, b) => stack.push(take(b)),
+            Appl(a, b) => {
+                stack.push(take(a));
+                stack.push(take(b));
+            }
+            Bound(_) | Free(_) | Name(_) => return,
+        }
+        while let Some(mut next) = stack.pop() {
+            match &mut next {
+                Abstr(_, b) => stack.push(take(b)),
+                Appl(a, b) => {
+                    stack.push(take(a));
+                    stack.push(take(b));
+                }
+                Bound(_) | Free(_) | Name(_) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::*;
+
+    #[test]
+    fn round_trip() {
+        for src in [
+            r"\fx.f(fx)",
+            r"\abc.ba(\b.cb)(\ac.ba)(\ap.caa)",
+            r"\a.b(\b.ab)",
+            "Name a",
+        ] {
+            let e = process(src);
+            let back = DeBruijn::from_expr(&e).to_expr();
+            assert!(e.alpha_eq(&back));
+            assert_eq!(e.to_string(), back.to_string());
+        }
+    }
+
+    #[test]
+    fn indices() {
+        use DeBruijn::*;
+        let d = DeBruijn::from_expr(&process(r"\fx.f(fx)"));
+        let expected = Abstr(
+            b'f',
+            Box::new(Abstr(
+                b'x',
+                Box::new(Appl(
+                    Box::new(Bound(1)),
+                    Box::new(Appl(Box::new(Bound(1)), Box::new(Bound(0)))),
+                )),
+            )),
+        );
+        assert_eq!(*d, expected);
+    }
+
+    #[test]
+    fn avoids_capture() {
+        use DeBruijn::*;
+        // \a.\a.a' where the inner hint would capture the outer parameter
+        let d = Abstr(
+            b'a',
+            Box::new(Abstr(
+                b'a',
+                Box::new(Appl(Box::new(Bound(1)), Box::new(Free(b'b')))),
+            )),
+        );
+        let e = d.to_expr();
+        assert_eq!(e.to_string(), r"\ac.ab");
+    }
+
+    #[test]
+    fn notation() {
+        let parse = |src: &str| DeBruijn::parse(src).unwrap().to_expr();
+        assert!(parse(r"\ \ 2 (1 2)").alpha_eq(&process(r"\xy.x(yx)")));
+        assert!(parse("λλ2(12)").alpha_eq(&process(r"\xy.x(yx)")));
+        assert!(parse(r"\.\.\.31(21)").alpha_eq(&process(r"\xyz.xz(yz)")));
+        assert!(parse(r"\1 \1 \1").alpha_eq(&process(r"\x.x(\y.y(\z.z))")));
+        assert!(parse(r"(\1 x) Succ").alpha_eq(&process(r"(\y.y x) Succ")));
+        let deep = format!("{}{{11}}", r"\".repeat(11));
+        assert!(parse(&deep).alpha_eq(&process(r"\abcdefghijk.a")));
+
+        for src in [
+            r"\xy.x(yx)",
+            r"\x.x(\y.y)",
+            r"(\x.x x)(\x.x x)",
+            r"\f.f I (\x.Succ x)",
+            r"\x.x y",
+            r"\x.Succ x 3",
+            r"+ 1 (* 2 3)",
+        ] {
+            let expr = process(src);
+            let shown = format!("{:+}", expr);
+            assert!(parse(&shown).alpha_eq(&expr), "{} as {}", src, shown);
+        }
+        assert_eq!(format!("{:+}", process(r"\xy.x(yx)")), r"\\2(12)");
+        assert_eq!(format!("{:+}", process(r"\x.Succ x I")), r"\Succ 1 I");
+        assert_eq!(format!("{:+}", process(r"\x.x 3 I")), r#"\1 "3" I"#);
+        assert!(format!("{:+}", DeBruijn::parse(&deep).unwrap().to_expr()).ends_with("{11}"));
+
+        for bad in [
+            "1",
+            r"\0",
+            r"\(1",
+            r"\1)",
+            r"\",
+            "()",
+            r"\1 ?",
+            r"\{x}",
+            r#"\1 "3"#,
+            "\"\"",
+        ] {
+            assert!(DeBruijn::parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
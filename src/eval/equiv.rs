@@ -0,0 +1,143 @@
+use std::fmt;
+
+use super::eval::{reduce_with, Config};
+use super::util::{eta_reduce, unfold};
+use super::EvalError;
+use crate::arena::Arena;
+use crate::expr::{Defs, Expr};
+use crate::parse::{AssertKind, Assertion};
+
+/// How two terms are related, from the closest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Equivalence {
+    /// The same up to the names of parameters
+    Alpha,
+    /// The same beta normal form, with definitions unfolded
+    Beta,
+    /// The same after eta reductions alone
+    Eta,
+    /// The same beta-eta normal form, which neither beta nor eta reductions
+    /// alone get to
+    BetaEta,
+    Different,
+}
+
+/// Finds the closest relation between `a` and `b`.
+///
+/// Normal forms are found under the limits of `config`, and running into
+/// one is an error.
+pub fn equiv(
+    a: &Expr,
+    b: &Expr,
+    defs: &Defs,
+    config: &Config,
+) -> Result<Equivalence, Box<EvalError>> {
+    relate(a, b, defs, config).map(|(relation, _)| relation)
+}
+
+/// Beta normal forms of two terms, in order
+type Forms = (Box<Expr>, Box<Expr>);
+
+/// Like `equiv`, but also returns the beta normal forms of `a` and `b`
+/// unless they are alpha-equivalent.
+fn relate(
+    a: &Expr,
+    b: &Expr,
+    defs: &Defs,
+    config: &Config,
+) -> Result<(Equivalence, Option<Forms>), Box<EvalError>> {
+    // Alpha-equivalent terms share their node
+    let mut arena = Arena::new();
+    let mut same = |a: &Expr, b: &Expr| arena.insert(a) == arena.insert(b);
+
+    if same(a, b) {
+        return Ok((Equivalence::Alpha, None));
+    }
+    let beta = Config {
+        eta: false,
+        ..config.clone()
+    };
+    let beta_a = reduce_with(Box::new(a.clone()), defs, &beta).0?;
+    let beta_b = reduce_with(Box::new(b.clone()), defs, &beta).0?;
+    let relation = if same(&beta_a, &beta_b) {
+        Equivalence::Beta
+    } else if same(
+        &eta_reduce(Box::new(a.clone())),
+        &eta_reduce(Box::new(b.clone())),
+    ) {
+        Equivalence::Eta
+    } else if same(
+        // Eta reductions never make a beta normal form reducible again
+        &eta_reduce(beta_a.clone()),
+        &eta_reduce(beta_b.clone()),
+    ) {
+        Equivalence::BetaEta
+    } else {
+        Equivalence::Different
+    };
+    Ok((relation, Some((beta_a, beta_b))))
+}
+
+/// Outcome of `equivalent` and `normalizes_to`
+#[derive(Debug)]
+pub enum Verdict {
+    Equal,
+    /// The forms that were compared, left side first
+    NotEqual(Box<Expr>, Box<Expr>),
+    /// No normal form was found within the limits
+    Unknown(Box<EvalError>),
+}
+
+/// Whether `a` and `b` have the same normal form under `config`, which is
+/// beta-eta equality unless eta reductions are turned off.
+pub fn equivalent(a: &Expr, b: &Expr, defs: &Defs, config: &Config) -> Verdict {
+    let (a, b) = match relate(a, b, defs, config) {
+        Ok((Equivalence::Different, Some(forms))) => forms,
+        Ok((Equivalence::Eta | Equivalence::BetaEta, Some(forms))) if !config.eta => forms,
+        Ok(_) => return Verdict::Equal,
+        Err(e) => return Verdict::Unknown(e),
+    };
+    if config.eta {
+        Verdict::NotEqual(eta_reduce(a), eta_reduce(b))
+    } else {
+        Verdict::NotEqual(a, b)
+    }
+}
+
+/// Whether `a` normalizes to `b` under `config`, `b` as it is written apart
+/// from its definitions and numerals, and etas if those are on.
+pub fn normalizes_to(a: &Expr, b: &Expr, defs: &Defs, config: &Config) -> Verdict {
+    let normal = match reduce_with(Box::new(a.clone()), defs, config).0 {
+        Ok(normal) => normal,
+        Err(e) => return Verdict::Unknown(e),
+    };
+    let mut expected = unfold(b, defs, config);
+    if config.eta {
+        expected = eta_reduce(expected);
+    }
+    let mut arena = Arena::new();
+    if arena.insert(&normal) == arena.insert(&expected) {
+        Verdict::Equal
+    } else {
+        Verdict::NotEqual(normal, expected)
+    }
+}
+
+/// Checks an `assert` statement by its kind.
+pub fn check(assertion: &Assertion, defs: &Defs, config: &Config) -> Verdict {
+    let (left, right) = (&assertion.left, &assertion.right);
+    match assertion.kind {
+        AssertKind::Equivalent => equivalent(left, right, defs, config),
+        AssertKind::Normalizes => normalizes_to(left, right, defs, config),
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verdict::Equal => write!(f, "Equal"),
+            Verdict::NotEqual(..) => write!(f, "Not equal"),
+            Verdict::Unknown(e) => write!(f, "Unknown: {}", e),
+        }
+    }
+}
@@ -1,145 +1,557 @@
 use empty_box::EmptyBox;
-use std::error;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::error::Error;
 use std::fmt;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use super::numerals::Numerals;
+use super::profile::{address, Profile};
 use super::util::*;
 use super::EvalError;
-use crate::expr::{Def, Defs, Expr};
+use crate::expr::{Defs, Expr, Memo, Unpacked};
+use crate::parse::{ParseError, Pragma};
 
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone)]
 pub struct Stats {
-    reduced: bool,
+    pub(super) reduced: bool,
     betas: u32,
     etas: u32,
+    /// Primitives applied, see `Config::primitives`
+    pub(super) deltas: u32,
     max_depth: u32,
     depth: u32,
-    size: u32,
-    max_size: u32,
+    pub(super) size: u32,
+    pub(super) max_size: u32,
+    /// Unfoldings of every definition
+    unfolds: HashMap<String, u32>,
+    /// Parameters renamed to avoid capture
+    pub(super) alphas: u32,
+    /// Nodes copied to substitute an argument more than once
+    pub(super) cloned: u64,
+    /// Beta reductions at every depth
+    redex_depths: BTreeMap<u32, u32>,
+    time: Duration,
+    /// Where the beta reductions came from, see `Config::profile`
+    pub(super) profile: Option<Profile>,
+}
+
+impl Stats {
+    pub fn betas(&self) -> u32 {
+        self.betas
+    }
+
+    pub fn etas(&self) -> u32 {
+        self.etas
+    }
+
+    pub fn deltas(&self) -> u32 {
+        self.deltas
+    }
+
+    /// Deepest node visited, the root being at depth 1
+    pub fn max_depth(&self) -> u32 {
+        self.max_depth
+    }
+
+    /// Largest size of the term after a pass
+    pub fn max_size(&self) -> u32 {
+        self.max_size
+    }
+
+    pub fn unfolds(&self) -> &HashMap<String, u32> {
+        &self.unfolds
+    }
+
+    pub fn alphas(&self) -> u32 {
+        self.alphas
+    }
+
+    pub fn cloned(&self) -> u64 {
+        self.cloned
+    }
+
+    /// Number of beta reductions by the depth of their redex, the root
+    /// being at depth 1
+    pub fn redex_depths(&self) -> &BTreeMap<u32, u32> {
+        &self.redex_depths
+    }
+
+    /// Wall time of the reduction
+    pub fn time(&self) -> Duration {
+        self.time
+    }
+
+    /// Where the beta reductions came from, if `Config::profile` was set
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Definitions by how often they were unfolded, most first
+    fn unfolds_sorted(&self) -> Vec<(&str, u32)> {
+        let mut unfolds: Vec<(&str, u32)> =
+            self.unfolds.iter().map(|(k, &v)| (k.as_str(), v)).collect();
+        unfolds.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        unfolds
+    }
+
+    /// Adds the counts of a subterm that was reduced on its own, `depth`
+    /// levels below the root, apart from its sizes.
+    pub(super) fn absorb(&mut self, sub: &Stats, depth: u32) {
+        self.betas += sub.betas;
+        self.etas += sub.etas;
+        self.deltas += sub.deltas;
+        self.max_depth = self.max_depth.max(depth + sub.max_depth);
+        for (name, n) in &sub.unfolds {
+            *self.unfolds.entry(name.clone()).or_default() += n;
+        }
+        self.alphas += sub.alphas;
+        self.cloned += sub.cloned;
+        for (d, n) in &sub.redex_depths {
+            *self.redex_depths.entry(depth + d).or_default() += n;
+        }
+    }
+
+    pub(super) fn set_time(&mut self, time: Duration) {
+        self.time = time;
+    }
+
+    /// Ends a reduction begun at `start`.
+    fn finish(&mut self, start: Instant) {
+        self.time = start.elapsed();
+        if let Some(profile) = &mut self.profile {
+            profile.finish();
+        }
+    }
+
+    /// Counts a beta reduction of `abstr`, which is about to go away
+    fn beta(&mut self, abstr: &Expr) {
+        self.betas += 1;
+        self.reduced = true;
+        *self.redex_depths.entry(self.depth).or_default() += 1;
+        if let Some(profile) = &mut self.profile {
+            profile.beta(abstr);
+        }
+    }
+
+    /// All of the stats as a JSON object
+    pub fn to_json(&self) -> String {
+        fn string(out: &mut String, s: &str) {
+            out.push('"');
+            for c in s.chars() {
+                match c {
+                    '"' => out.push_str("\\\""),
+                    '\\' => out.push_str("\\\\"),
+                    c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+                    c => out.push(c),
+                }
+            }
+            out.push('"');
+        }
+
+        let mut out = String::new();
+        write!(
+            out,
+            r#"{{"betas":{},"etas":{},"deltas":{},"max_depth":{},"max_size":{},"alphas":{},"cloned":{},"time_ms":{}"#,
+            self.betas,
+            self.etas,
+            self.deltas,
+            self.max_depth,
+            self.max_size,
+            self.alphas,
+            self.cloned,
+            self.time.as_secs_f64() * 1000.0
+        )
+        .unwrap();
+        out.push_str(r#","unfolds":{"#);
+        for (i, (name, n)) in self.unfolds_sorted().into_iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            string(&mut out, name);
+            write!(out, ":{}", n).unwrap();
+        }
+        out.push_str(r#"},"redex_depths":{"#);
+        for (i, (depth, n)) in self.redex_depths.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            write!(out, r#""{}":{}"#, depth, n).unwrap();
+        }
+        out.push_str("}}");
+        out
+    }
+}
+
+/// Limits and options of a reduction
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub max_iterations: u32,
+    pub max_size: u32,
+    /// Unfold names to the normal forms cached by `memoize`, where known
+    pub memoize: bool,
+    /// Iteration limit for normalizing each definition in `memoize`
+    pub memo_iterations: u32,
+    /// Size limit for normalizing each definition in `memoize`
+    pub memo_size: u32,
+    /// Reduce `\a.E a` to `E` where `a` is not free in `E`
+    pub eta: bool,
+    /// Stops the reduction once set, from any thread
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Time limit of each call to a reducer
+    pub timeout: Option<Duration>,
+    /// Keep numerals as integer literals and apply the built-in `+`, `*`,
+    /// `-` (stopping at 0), `==`, `If`, `ToChurch` and `FromChurch` to them,
+    /// unless a definition of the same name hides one
+    pub primitives: bool,
+    /// What numerals unfold to when they aren't primitive literals
+    pub numerals: Numerals,
+    /// Stop with an error once a pass gives back a term alpha-equivalent to
+    /// one of this many terms before it
+    pub cycles: Option<usize>,
+    /// Keep a `Profile` of where the beta reductions came from in the
+    /// stats. Only `reduce_with` does.
+    pub profile: bool,
 }
 
-pub fn reduce(
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_iterations: 10000000,
+            max_size: 10000000,
+            memoize: false,
+            memo_iterations: 1000,
+            memo_size: 10000,
+            eta: true,
+            cancel: None,
+            timeout: None,
+            primitives: false,
+            numerals: Numerals::Church,
+            cycles: None,
+            profile: false,
+        }
+    }
+}
+
+impl Config {
+    /// Applies a `#pragma` of a source, of which there is one:
+    /// `#pragma numerals <encoding>` picks what its numerals unfold to.
+    pub fn pragma(&mut self, pragma: &Pragma) -> Result<(), Box<dyn Error>> {
+        let at = |msg: String| -> Box<dyn Error> { ParseError::boxed(msg, pragma.row, pragma.col) };
+        match &pragma.words[..] {
+            [name, numerals] if name == "numerals" => {
+                self.numerals = numerals.parse().map_err(at)?;
+                Ok(())
+            }
+            _ => Err(at(format!("Unknown pragma: {}", pragma.words.join(" ")))),
+        }
+    }
+}
+
+pub fn reduce(expr: Box<Expr>, defs: &Defs) -> (Result<Box<Expr>, Box<EvalError>>, Stats) {
+    reduce_with(expr, defs, &Config::default())
+}
+
+pub fn reduce_with(
     mut expr: Box<Expr>,
     defs: &Defs,
-) -> (Result<Box<Expr>, Box<dyn error::Error>>, Stats) {
-    let max_iterations = 10000000;
-    let max_size = 10000000;
-
-    let mut stats = Stats::default();
-    for i in 1..=max_iterations {
-        stats.reduced = false;
-        stats.size = 0;
-        expr = do_reduce(expr, defs, &mut stats);
-        if stats.size > stats.max_size {
-            stats.max_size = stats.size
+    config: &Config,
+) -> (Result<Box<Expr>, Box<EvalError>>, Stats) {
+    let start = Instant::now();
+    let mut stats = Stats {
+        profile: config.profile.then(Profile::new),
+        ..Default::default()
+    };
+    let mut cycles = Cycles::new(config);
+    for i in 1..=config.max_iterations {
+        if let Err(e) = cycles.see(&expr, i - 1) {
+            stats.finish(start);
+            return (Err(e), stats);
         }
+        expr = pass(expr, defs, config, &mut stats);
         //eprintln!("Reduce: {}", expr);
         if !stats.reduced {
             break;
         }
         //let expr_size = expr.size();
         //debug_assert_eq!(expr_size, stats.size);
-        if stats.size > max_size {
-            return (
-                Err(EvalError::boxed(format!(
-                    "Size outgrew maximum size: {} out of {}",
-                    stats.size, max_size
-                ))),
-                stats,
-            );
-        } else if i == max_iterations {
-            return (
-                Err(EvalError::boxed(format!(
-                    "Iteration limit reached: {}",
-                    max_iterations
-                ))),
-                stats,
-            );
+        if let Err(e) = limits(stats.size, i, config, start) {
+            stats.finish(start);
+            return (Err(e.with_term(expr)), stats);
         }
     }
+    stats.finish(start);
     (Ok(expr), stats)
 }
 
-fn do_reduce(expr: Box<Expr>, defs: &Defs, st: &mut Stats) -> Box<Expr> {
-    st.depth += 1;
-    st.size += 1;
-    if st.depth > st.max_depth {
-        st.max_depth = st.depth
+/// Goes on with a reduction that stopped at a limit, under the limits of
+/// `config`. The stats count only the resumed part.
+///
+/// Errors without a term to resume from are given back as they are.
+pub fn resume(
+    mut err: Box<EvalError>,
+    defs: &Defs,
+    config: &Config,
+) -> (Result<Box<Expr>, Box<EvalError>>, Stats) {
+    match err.term.take() {
+        Some(term) => reduce_with(term, defs, config),
+        None => (Err(err), Stats::default()),
+    }
+}
+
+/// Reduces every redex of the term found in one traversal.
+pub(super) fn pass(expr: Box<Expr>, defs: &Defs, config: &Config, st: &mut Stats) -> Box<Expr> {
+    st.reduced = false;
+    st.size = 0;
+    let expr = do_reduce(expr, defs, config, st);
+    if st.size > st.max_size {
+        st.max_size = st.size
     }
-    //println!("\tdo_reduce: {}", expr);
+    expr
+}
 
-    use Expr::*;
-    let (ex, eb) = EmptyBox::take(expr);
-    // TODO: recursive -> iterative for trivial cases
-    let result = match ex {
-        // Irreducable
-        Variable(_) => ex,
-        Name(ref s) => {
-            if let Some(Def { value }) = defs.get(s) {
-                st.reduced = true;
-                st.size -= 1;
-                *value.clone()
-            } else if let Ok(n) = s.parse() {
-                st.reduced = true;
-                st.size -= 1;
-                *Expr::church_num(n)
-            } else {
-                ex
+/// Checks the limits after the `i`th pass that reduced something to a term
+/// of `size`, of a reduction begun at `start`.
+pub(super) fn limits(
+    size: u32,
+    i: u32,
+    config: &Config,
+    start: Instant,
+) -> Result<(), Box<EvalError>> {
+    let timed_out = config.timeout.filter(|&timeout| start.elapsed() >= timeout);
+    if matches!(&config.cancel, Some(cancel) if cancel.load(Ordering::Relaxed)) {
+        Err(EvalError::boxed("Evaluation cancelled"))
+    } else if let Some(timeout) = timed_out {
+        Err(EvalError::boxed(format!(
+            "Time limit reached: {:?}",
+            timeout
+        )))
+    } else if size > config.max_size {
+        Err(outgrown(size, config))
+    } else if i == config.max_iterations {
+        Err(EvalError::boxed(format!(
+            "Iteration limit reached: {}",
+            config.max_iterations
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+pub(super) fn outgrown(size: u32, config: &Config) -> Box<EvalError> {
+    EvalError::boxed(format!(
+        "Size outgrew maximum size: {} out of {}",
+        size, config.max_size
+    ))
+}
+
+/// The terms of the last few passes of a reduction, by their `alpha_hash`,
+/// for `Config::cycles`
+pub(super) struct Cycles {
+    window: usize,
+    recent: VecDeque<(u32, u64, Box<Expr>)>,
+}
+
+impl Cycles {
+    pub(super) fn new(config: &Config) -> Self {
+        Cycles {
+            window: config.cycles.unwrap_or(0),
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Keeps the term of the `i`th pass, failing if it is one of the recent
+    /// terms again.
+    pub(super) fn see(&mut self, expr: &Expr, i: u32) -> Result<(), Box<EvalError>> {
+        if self.window == 0 {
+            return Ok(());
+        }
+        let hash = expr.alpha_hash();
+        for (j, seen, term) in &self.recent {
+            if *seen == hash && term.alpha_eq(expr) {
+                return Err(EvalError::diverges(i - j, term.clone()));
             }
         }
+        if self.recent.len() == self.window {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((i, hash, Box::new(expr.clone())));
+        Ok(())
+    }
+}
+
+fn do_reduce(expr: Box<Expr>, defs: &Defs, config: &Config, st: &mut Stats) -> Box<Expr> {
+    use Expr::*;
+
+    /// What to do with a reduced subexpression
+    enum Frame {
+        /// Rebuild the abstraction around its reduced body
+        Abstr(u8, EmptyBox<Expr>),
+        /// An eta reduced abstraction is replaced by its reduced body
+        Eta,
+        /// Beta reduce the application if its reduced function is an
+        /// abstraction, otherwise reduce the argument
+        ApplFunction(Box<Expr>, EmptyBox<Expr>, u32),
+        /// Rebuild the application around its reduced argument
+        ApplArgument(Box<Expr>, EmptyBox<Expr>),
+    }
+
+    enum Step {
+        Down(Box<Expr>),
+        Up(Box<Expr>),
+    }
 
-        // Eta reduction:
-        //   Reduce(\a.Ea)
-        //   a is not free in E
-        //=> Reduce(E)
-        Abstr(var, box Appl(rest, box Variable(last)))
-            if var == last && !rest.unbounds().contains(&var) =>
-        {
-            st.etas += 1;
-            st.reduced = true;
-            *do_reduce(rest, defs, st)
-        }
-        //   Reduce[\a.E]  =>  \a.Reduce[E]
-        Abstr(var, body) => {
-            let e = do_reduce(body, defs, st);
-            Abstr(var, e)
-        }
-
-        // Beta reduction:
-        //   Reduce[(\x.A)B]  => Reduce[A[x->B]]
-        Appl(box Abstr(from, body), to) => {
-            st.betas += 1;
-            st.reduced = true;
-            let res = beta_reduce(body, from, to);
-            st.size += res.size() - 1;
-            *res
-        }
-        //   Reduce[AB]
-        Appl(a, to) => {
-            let sz = st.size;
-            let red_box = do_reduce(a, defs, st);
-            let (reduced_a, red_eb) = EmptyBox::take(red_box);
-            match reduced_a {
-                //   if Reduce[A] => \x.C
-                //        Beta reduction:
-                //        Reduce[AB] => Reduce[(\x.C)B] => Reduce[C[x->B]]
-                Abstr(from, body) => {
-                    st.betas += 1;
-                    st.reduced = true;
-                    let res = beta_reduce(body, from, to);
-                    st.size = sz + res.size() - 1;
-                    *res
+    let mut frames: Vec<Frame> = Vec::new();
+    let mut step = Step::Down(expr);
+    loop {
+        step = match step {
+            Step::Down(expr) => {
+                st.depth += 1;
+                st.size += 1;
+                if st.depth > st.max_depth {
+                    st.max_depth = st.depth
                 }
-                //   else Reduce[AB] => (Reduce[A])(Reduce[B])
-                other => {
-                    let e = do_reduce(to, defs, st);
-                    st.size -= 1;
-                    Appl(red_eb.put(other), e)
+                //println!("\tdo_reduce: {}", expr);
+
+                // Delta reduction:
+                //   Reduce[P A..B]  =>  the primitive P applied to A..B
+                if config.primitives {
+                    if let Some(res) = delta(&expr, defs) {
+                        if let Some(profile) = &mut st.profile {
+                            profile.delta(&expr, &res);
+                        }
+                        st.deltas += 1;
+                        st.reduced = true;
+                        st.size += res.size() - 1;
+                        step = Step::Up(res);
+                        continue;
+                    }
+                }
+
+                let at = address(&expr);
+                let (ex, eb) = EmptyBox::take(expr);
+                match ex.unpack() {
+                    // Irreducable
+                    Unpacked::Variable(v) => Step::Up(eb.put(Variable(v))),
+                    Unpacked::Name(s) => {
+                        if let Some(def) = defs.get(&s) {
+                            st.reduced = true;
+                            st.size -= 1;
+                            let value = match &def.normal {
+                                Memo::Normal(normal) if config.memoize => normal,
+                                _ => &def.value,
+                            };
+                            let value = eb.put(*value.clone());
+                            if let Some(profile) = &mut st.profile {
+                                let tag = profile.unfold(at, &s);
+                                profile.tag(&value, tag);
+                            }
+                            match st.unfolds.get_mut(&s) {
+                                Some(n) => *n += 1,
+                                None => {
+                                    st.unfolds.insert(s, 1);
+                                }
+                            }
+                            Step::Up(value)
+                        } else if let Some(num) = numeral(&s, config) {
+                            st.reduced = true;
+                            st.size -= 1;
+                            let num = eb.put(*num);
+                            if let Some(profile) = &mut st.profile {
+                                let tag = profile.take(at);
+                                profile.tag(&num, tag);
+                            }
+                            Step::Up(num)
+                        } else {
+                            Step::Up(eb.put(Name(s)))
+                        }
+                    }
+
+                    // Eta reduction:
+                    //   Reduce(\a.Ea)
+                    //   a is not free in E
+                    //=> Reduce(E)
+                    Unpacked::Abstr(var, body)
+                        if config.eta
+                            && matches!(&*body, Appl(rest, box Variable(last))
+                            if *last == var && !rest.unbounds().contains(&var)) =>
+                    {
+                        st.etas += 1;
+                        st.reduced = true;
+                        if let Some(profile) = &mut st.profile {
+                            profile.take(at);
+                        }
+                        match body.unpack() {
+                            Unpacked::Appl(rest, _) => {
+                                frames.push(Frame::Eta);
+                                Step::Down(rest)
+                            }
+                            _ => unreachable!(),
+                        }
+                    }
+                    //   Reduce[\a.E]  =>  \a.Reduce[E]
+                    Unpacked::Abstr(var, body) => {
+                        frames.push(Frame::Abstr(var, eb));
+                        Step::Down(body)
+                    }
+
+                    // Beta reduction:
+                    //   Reduce[(\x.A)B]  => Reduce[A[x->B]]
+                    Unpacked::Appl(a, to) if matches!(*a, Abstr(_, _)) => {
+                        st.beta(&a);
+                        match a.unpack() {
+                            Unpacked::Abstr(from, body) => {
+                                let res = beta_reduce(body, from, to, st);
+                                st.size += res.size() - 1;
+                                Step::Up(res)
+                            }
+                            _ => unreachable!(),
+                        }
+                    }
+                    //   Reduce[AB]
+                    Unpacked::Appl(a, to) => {
+                        frames.push(Frame::ApplFunction(to, eb, st.size));
+                        Step::Down(a)
+                    }
+                }
+            }
+            Step::Up(reduced) => {
+                st.depth -= 1;
+                match frames.pop() {
+                    None => return reduced,
+                    Some(Frame::Abstr(var, eb)) => Step::Up(eb.put(Abstr(var, reduced))),
+                    Some(Frame::Eta) => Step::Up(reduced),
+                    Some(Frame::ApplFunction(to, eb, sz)) => match *reduced {
+                        //   if Reduce[A] => \x.C
+                        //        Beta reduction:
+                        //        Reduce[AB] => Reduce[(\x.C)B] => Reduce[C[x->B]]
+                        Abstr(_, _) => {
+                            st.beta(&reduced);
+                            match reduced.unpack() {
+                                Unpacked::Abstr(from, body) => {
+                                    let res = beta_reduce(body, from, to, st);
+                                    st.size = sz + res.size() - 1;
+                                    Step::Up(res)
+                                }
+                                _ => unreachable!(),
+                            }
+                        }
+                        //   else Reduce[AB] => (Reduce[A])(Reduce[B])
+                        _ => {
+                            frames.push(Frame::ApplArgument(reduced, eb));
+                            Step::Down(to)
+                        }
+                    },
+                    Some(Frame::ApplArgument(a, eb)) => {
+                        st.size -= 1;
+                        Step::Up(eb.put(Appl(a, reduced)))
+                    }
                 }
             }
         }
-    };
-    st.depth -= 1;
-    eb.put(result)
+    }
 }
 
 impl fmt::Display for Stats {
@@ -147,8 +559,12 @@ impl fmt::Display for Stats {
         let Stats {
             betas,
             etas,
+            deltas,
             max_depth,
             max_size,
+            alphas,
+            cloned,
+            time,
             ..
         } = *self;
         writeln!(
@@ -156,9 +572,22 @@ impl fmt::Display for Stats {
             r#"Stats:
   Beta reductions: {}
   Eta reductions: {}
+  Delta reductions: {}
   Maximum depth: {}
-  Maximum size: {}"#,
-            betas, etas, max_depth, max_size
-        )
+  Maximum size: {}
+  Alpha renamings: {}
+  Cloned nodes: {}
+  Time: {:?}"#,
+            betas, etas, deltas, max_depth, max_size, alphas, cloned, time
+        )?;
+        let unfolds = self.unfolds_sorted();
+        if !unfolds.is_empty() {
+            write!(f, "  Most unfolded:")?;
+            for (name, n) in unfolds.into_iter().take(5) {
+                write!(f, " {} ({})", name, n)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
     }
 }
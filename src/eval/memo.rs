@@ -0,0 +1,97 @@
+use std::collections::{HashMap, HashSet};
+
+use super::eval::{reduce_with, Config};
+use crate::expr::{Defs, Memo};
+
+/// Normalizes every pending definition and caches the result in it.
+///
+/// Definitions are normalized after the ones they use, so those unfold to
+/// their cached normal forms already. Each is limited by
+/// `config.memo_iterations` and `config.memo_size`, and one without a normal
+/// form within those is marked `Memo::GaveUp` and unfolds as written from
+/// then on.
+pub fn memoize(defs: &mut Defs, config: &Config) {
+    let config = Config {
+        max_iterations: config.memo_iterations,
+        max_size: config.memo_size,
+        memoize: true,
+        profile: false,
+        ..config.clone()
+    };
+
+    // Post-order over the names each definition refers to
+    let mut order: Vec<String> = Vec::new();
+    let mut seen: HashSet<&str> = HashSet::new();
+    let mut roots: Vec<&String> = defs.keys().collect();
+    roots.sort();
+    for root in roots {
+        let mut stack: Vec<(&str, bool)> = vec![(root, false)];
+        while let Some((name, deps_done)) = stack.pop() {
+            if deps_done {
+                order.push(name.to_owned());
+                continue;
+            }
+            if !seen.insert(name) {
+                continue;
+            }
+            stack.push((name, true));
+            let mut deps: Vec<&str> = defs[name].value.names().into_iter().collect();
+            deps.sort_unstable();
+            for dep in deps.into_iter().rev() {
+                if let Some((dep, _)) = defs.get_key_value(dep) {
+                    if !seen.contains(dep.as_str()) {
+                        stack.push((dep, false));
+                    }
+                }
+            }
+        }
+    }
+
+    for name in order {
+        if !matches!(defs[&name].normal, Memo::Pending) {
+            continue;
+        }
+        let value = defs[&name].value.clone();
+        let normal = match reduce_with(value, defs, &config).0 {
+            Ok(normal) => Memo::Normal(normal),
+            Err(_) => Memo::GaveUp,
+        };
+        defs.get_mut(&name).unwrap().normal = normal;
+    }
+}
+
+/// Forgets the normal forms of `name` and every definition that uses it,
+/// directly or through others.
+pub fn invalidate(defs: &mut Defs, name: &str) {
+    let mut users: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (k, def) in defs.iter() {
+        for dep in def.value.names() {
+            users.entry(dep).or_default().push(k);
+        }
+    }
+
+    let mut stale: HashSet<String> = HashSet::new();
+    let mut stack = vec![name];
+    while let Some(n) = stack.pop() {
+        if stale.insert(n.to_owned()) {
+            stack.extend(users.get(n).into_iter().flatten());
+        }
+    }
+    for n in stale {
+        if let Some(def) = defs.get_mut(&n) {
+            def.normal = Memo::Pending;
+        }
+    }
+}
+
+/// Adds or replaces definitions, forgetting normal forms that depended on
+/// the replaced ones, and memoizes them if `config.memoize` is set.
+pub fn define(all_defs: &mut Defs, defs: Defs, config: &Config) {
+    for (k, v) in defs {
+        invalidate(all_defs, &k);
+        all_defs.insert(k, v);
+    }
+    if config.memoize {
+        memoize(all_defs, config);
+    }
+}
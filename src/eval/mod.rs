@@ -1,9 +1,26 @@
 use std::{error, fmt};
 
+use crate::expr::Expr;
+
+pub mod equiv;
+#[allow(clippy::module_inception)]
 pub mod eval;
+pub mod memo;
+mod numerals;
+pub mod par;
+pub mod profile;
+mod readback;
+mod termination;
 pub mod util;
 
-pub use eval::reduce;
+pub use equiv::{check, equiv, equivalent, normalizes_to, Equivalence, Verdict};
+pub use eval::{reduce, reduce_with, resume, Config, Stats};
+pub use memo::{define, invalidate, memoize};
+pub use numerals::{resolve_numerals, Numerals};
+pub use par::{reduce_par, Parallel};
+pub use profile::Profile;
+pub use readback::{readback, Readback};
+pub use termination::{termination, Termination};
 
 #[cfg(test)]
 mod tests;
@@ -11,11 +28,52 @@ mod tests;
 #[derive(Clone)]
 pub struct EvalError {
     msg: String,
+    /// How far the reduction got before it stopped
+    term: Option<Box<Expr>>,
+    /// The term a reduction kept coming back to, and every how many passes
+    cycle: Option<(u32, Box<Expr>)>,
 }
 
 impl EvalError {
-    fn boxed(msg: impl Into<String>) -> Box<EvalError> {
-        Box::new(EvalError { msg: msg.into() })
+    pub(crate) fn boxed(msg: impl Into<String>) -> Box<EvalError> {
+        Box::new(EvalError {
+            msg: msg.into(),
+            term: None,
+            cycle: None,
+        })
+    }
+
+    /// The error of a reduction that came back to `term` after `passes`
+    pub(crate) fn diverges(passes: u32, term: Box<Expr>) -> Box<EvalError> {
+        let mut err = EvalError::boxed(format!(
+            "Reduction diverges, coming back to {} every {} pass{}",
+            term,
+            passes,
+            if passes == 1 { "" } else { "es" }
+        ));
+        err.cycle = Some((passes, term));
+        err
+    }
+
+    pub(crate) fn with_term(mut self: Box<Self>, term: Box<Expr>) -> Box<Self> {
+        self.term = Some(term);
+        self
+    }
+
+    /// The partially reduced term, if the reduction stopped at a limit and
+    /// can be resumed from it.
+    pub fn term(&self) -> Option<&Expr> {
+        self.term.as_deref()
+    }
+
+    /// The length of the cycle the reduction was found in, in passes, and
+    /// the term that repeats, if it stopped for that.
+    pub fn cycle(&self) -> Option<(u32, &Expr)> {
+        self.cycle.as_ref().map(|(passes, term)| (*passes, &**term))
+    }
+
+    pub fn into_term(self) -> Option<Box<Expr>> {
+        self.term
     }
 }
 
@@ -0,0 +1,129 @@
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use super::eval::Config;
+use super::EvalError;
+
+use crate::expr::decode::{BinaryNum, ChurchNum, Decoder, ParigotNum, ScottNum, Value};
+use crate::expr::expr_aliases::*;
+use crate::expr::{Defs, Expr};
+use crate::parse::Program;
+
+/// What numerals like `3` unfold to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Numerals {
+    /// `\fx.f (f (f x))`
+    #[default]
+    Church,
+    /// `\zs.s N` for the predecessor `N`, as in `res/scott`
+    Scott,
+    /// `\zs.s N (N z s)` for the predecessor `N`, as in `res/prgt`
+    Parigot,
+    /// The bits from the lowest, see `binary_num`
+    Binary,
+    /// `Succ (Succ (Succ Zero))`, with whatever `Zero` and `Succ` are
+    /// defined as
+    User,
+}
+
+impl Numerals {
+    pub const NAMES: [&'static str; 5] = ["church", "scott", "parigot", "binary", "user"];
+
+    /// The term of `n`, or an error for unary numerals too large to build
+    pub fn encode(self, n: u64) -> Result<Box<Expr>, String> {
+        let unary = u32::try_from(n)
+            .map_err(|_| format!("Numeral {} is too large for {} numerals", n, self));
+        Ok(match self {
+            Numerals::Church => chnum(unary?),
+            Numerals::Scott => scott_num(unary?),
+            Numerals::Parigot => parigot_num(unary?),
+            Numerals::Binary => binary_num(n),
+            Numerals::User => {
+                let mut num = name("Zero");
+                for _ in 0..unary? {
+                    num = appl(name("Succ"), num);
+                }
+                num
+            }
+        })
+    }
+
+    /// The number a normal form stands for, if known for this encoding
+    pub fn decode(self, expr: &Expr) -> Option<u64> {
+        let value = match self {
+            Numerals::Church => ChurchNum.decode(expr),
+            Numerals::Scott => ScottNum.decode(expr),
+            Numerals::Parigot => ParigotNum.decode(expr),
+            Numerals::Binary => BinaryNum.decode(expr),
+            Numerals::User => None,
+        };
+        match value? {
+            Value::Num(n) => Some(n),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Numerals {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "church" => Ok(Numerals::Church),
+            "scott" => Ok(Numerals::Scott),
+            "parigot" => Ok(Numerals::Parigot),
+            "binary" => Ok(Numerals::Binary),
+            "user" => Ok(Numerals::User),
+            _ => Err(format!(
+                "Unknown numerals: {}, expected one of {}",
+                s,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Numerals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(Self::NAMES[*self as usize])
+    }
+}
+
+/// Unfolds the numerals in the definitions, main term and assertions of a
+/// source to `config.numerals`, so that they keep the encoding its pragmas
+/// picked once they are used from other sources.
+///
+/// Names defined in `defs` or the source itself are left alone, and so is
+/// everything with `config.primitives`, where numerals stay literals.
+/// Numerals too large for the encoding are an error.
+pub fn resolve_numerals(
+    program: &mut Program,
+    defs: &Defs,
+    config: &Config,
+) -> Result<(), Box<EvalError>> {
+    let own: HashSet<String> = program.defs.keys().cloned().collect();
+    let mut stack: Vec<&mut Expr> = program.defs.values_mut().map(|d| &mut *d.value).collect();
+    stack.extend(program.main.as_deref_mut());
+    for assert in &mut program.asserts {
+        stack.push(&mut assert.left);
+        stack.push(&mut assert.right);
+    }
+    while let Some(expr) = stack.pop() {
+        match expr {
+            Expr::Name(n) if !defs.contains_key(n) && !own.contains(n) => {
+                if let (false, Ok(n)) = (config.primitives, n.parse()) {
+                    *expr = *config.numerals.encode(n).map_err(EvalError::boxed)?;
+                }
+            }
+            Expr::Abstr(_, b) => stack.push(b),
+            Expr::Appl(a, b) => {
+                stack.push(a);
+                stack.push(b);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,294 @@
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use super::eval::{limits, outgrown, pass, Config, Cycles, Stats};
+use super::util::is_primitive;
+use super::EvalError;
+use crate::expr::expr_aliases::*;
+use crate::expr::{Defs, Expr, Unpacked};
+
+/// How many arguments deep `reduce_par` still splits terms; past that they
+/// are reduced in place, so that nested spines don't exhaust the stack.
+const MAX_NESTING: u32 = 64;
+
+/// Options of `reduce_par`
+#[derive(Debug, Clone)]
+pub struct Parallel {
+    /// Worker threads, or 0 for one per CPU
+    pub threads: usize,
+    /// Smallest term whose arguments are worth reducing on other threads
+    pub threshold: u32,
+}
+
+impl Default for Parallel {
+    fn default() -> Self {
+        Parallel {
+            threads: 0,
+            threshold: 1000,
+        }
+    }
+}
+
+/// Reduces `expr` like `reduce_with`, but normalizes the arguments of stuck
+/// applications on a thread pool.
+///
+/// Once the head of a term is a variable or an undefined name, no redex can
+/// span two of its arguments, so every argument goes through the same steps
+/// it would in `reduce_with`, only on its own. The result and all counts,
+/// like those of betas, etas and unfoldings, are the same as the sequential
+/// ones, and so is `max_size`, which adds up the sizes of the arguments after
+/// the same pass. Terms are not split while an eta reduction above their
+/// arguments is possible, as it would change their depths; depths only
+/// differ where one becomes possible once the arguments are reduced.
+///
+/// Iterations are counted along the longest argument. The size limit is
+/// checked against the size of the whole term, as far as every argument has
+/// got, and once they are all done against the sizes it would have had
+/// after every pass.
+pub fn reduce_par(
+    expr: Box<Expr>,
+    defs: &Defs,
+    config: &Config,
+    parallel: &Parallel,
+) -> (Result<Box<Expr>, Box<EvalError>>, Stats) {
+    let pool = match ThreadPoolBuilder::new()
+        .num_threads(parallel.threads)
+        .build()
+    {
+        Ok(pool) => pool,
+        Err(e) => return (Err(EvalError::boxed(e.to_string())), Stats::default()),
+    };
+    let run = Run {
+        defs,
+        config,
+        parallel,
+        start: Instant::now(),
+        live: AtomicU64::new(0),
+    };
+    let (result, mut stats, _) = pool.install(|| run.normalize(expr, 0, 0));
+    stats.set_time(run.start.elapsed());
+    (result, stats)
+}
+
+/// What the arguments reduced on other threads share
+struct Run<'a> {
+    defs: &'a Defs,
+    config: &'a Config,
+    parallel: &'a Parallel,
+    start: Instant,
+    /// Size of the whole term, as far as each of its parts has got
+    live: AtomicU64,
+}
+
+impl Run<'_> {
+    /// Reduces until normal, starting after `done` passes of the whole
+    /// term, `nesting` arguments deep. Also returns the size of the term
+    /// after every pass it made.
+    fn normalize(
+        &self,
+        mut expr: Box<Expr>,
+        mut done: u32,
+        nesting: u32,
+    ) -> (Result<Box<Expr>, Box<EvalError>>, Stats, Vec<u32>) {
+        let (defs, config) = (self.defs, self.config);
+        let first = done;
+        let mut stats = Stats::default();
+        let mut sizes: Vec<u32> = Vec::new();
+        let mut size = expr.size();
+        // This part's share of `live`
+        let mut mine = 0;
+        self.resize(&mut mine, size);
+        let mut split = nesting >= MAX_NESTING;
+        let mut cycles = Cycles::new(config);
+        let result = loop {
+            if !split && size >= self.parallel.threshold {
+                expr = match Spine::split(expr, defs, config) {
+                    Ok(mut spine) => {
+                        split = true;
+                        let args = std::mem::take(&mut spine.args);
+                        // The arguments count their own sizes while away
+                        let rest = spine.params.len() as u32 + spine.head.size();
+                        self.resize(&mut mine, rest);
+                        let results: Vec<_> = args
+                            .into_par_iter()
+                            .map(|arg| self.normalize(arg, done, nesting + 1))
+                            .collect();
+
+                        // Argument i of m is m - i levels below the outermost
+                        // application
+                        let m = results.len() as u32;
+                        let passes = results.iter().map(|(_, _, s)| s.len()).max();
+                        let mut largest = 0;
+                        for k in 0..passes.unwrap_or(0) {
+                            let args: u32 = results
+                                .iter()
+                                .map(|(_, _, s)| s.get(k).or(s.last()).copied().unwrap_or(0))
+                                .sum();
+                            sizes.push(rest + args);
+                            largest = largest.max(rest + args);
+                        }
+                        stats.max_size = stats.max_size.max(largest);
+                        let mut args = Vec::with_capacity(results.len());
+                        // The arguments may not have been at their largest
+                        // all at once, so the whole term can outgrow the
+                        // limit without `live` showing it
+                        let mut stopped = if largest > config.max_size {
+                            Some(outgrown(largest, config))
+                        } else {
+                            None
+                        };
+                        let mut failed: Option<Box<EvalError>> = None;
+                        for (i, (result, sub, _)) in results.into_iter().enumerate() {
+                            let depth = spine.params.len() as u32 + m - i as u32;
+                            stats.absorb(&sub, depth);
+                            match result {
+                                Ok(arg) => args.push(arg),
+                                // Resuming needs the whole term, made of every
+                                // argument as far as it got
+                                Err(mut e) => match e.term.take() {
+                                    Some(arg) => {
+                                        args.push(arg);
+                                        stopped = stopped.or(Some(e));
+                                    }
+                                    None => failed = failed.or(Some(e)),
+                                },
+                            }
+                        }
+                        done = first + sizes.len() as u32;
+                        if let Some(e) = failed {
+                            break Err(e);
+                        }
+                        let joined = spine.join(args);
+                        self.resize(&mut mine, joined.size());
+                        if let Some(e) = stopped {
+                            break Err(e.with_term(joined));
+                        }
+                        // The final pass over every argument changed nothing,
+                        // and neither will the next one except for etas
+                        done -= 1;
+                        sizes.pop();
+                        cycles = Cycles::new(config);
+                        joined
+                    }
+                    Err(expr) => expr,
+                };
+            }
+
+            if let Err(e) = cycles.see(&expr, done) {
+                break Err(e);
+            }
+            done += 1;
+            expr = pass(expr, defs, config, &mut stats);
+            sizes.push(stats.size);
+            self.resize(&mut mine, stats.size);
+            if !stats.reduced {
+                break Ok(expr);
+            }
+            let live = self.live.load(Ordering::Relaxed);
+            let live = u32::try_from(live).unwrap_or(u32::MAX);
+            if let Err(e) = limits(live, done, config, self.start) {
+                break Err(e.with_term(expr));
+            }
+            size = stats.size;
+        };
+        self.resize(&mut mine, 0);
+        (result, stats, sizes)
+    }
+
+    /// Changes a part's share of the live size to `size`.
+    fn resize(&self, share: &mut u32, size: u32) {
+        if size > *share {
+            self.live.fetch_add((size - *share) as u64, Ordering::Relaxed);
+        } else {
+            self.live.fetch_sub((*share - size) as u64, Ordering::Relaxed);
+        }
+        *share = size;
+    }
+}
+
+/// A term `\x..y.H A..B` whose head `H` can never be reduced
+// The arguments are boxed to be handed to the reducer as they are
+#[allow(clippy::vec_box)]
+struct Spine {
+    params: Vec<u8>,
+    head: Box<Expr>,
+    args: Vec<Box<Expr>>,
+}
+
+impl Spine {
+    /// Takes the term apart, or gives it back if it has no arguments or its
+    /// head may still reduce.
+    fn split(expr: Box<Expr>, defs: &Defs, config: &Config) -> Result<Spine, Box<Expr>> {
+        if !Self::rigid(&expr, defs, config) || (config.eta && Self::eta(&expr)) {
+            return Err(expr);
+        }
+        let mut params = Vec::new();
+        let mut args = Vec::new();
+        let mut head = expr;
+        while let Expr::Abstr(p, _) = *head {
+            params.push(p);
+            head = match head.unpack() {
+                Unpacked::Abstr(_, body) => body,
+                _ => unreachable!(),
+            };
+        }
+        while let Expr::Appl(..) = *head {
+            head = match head.unpack() {
+                Unpacked::Appl(f, x) => {
+                    args.push(x);
+                    f
+                }
+                _ => unreachable!(),
+            };
+        }
+        args.reverse();
+        Ok(Spine { params, head, args })
+    }
+
+    fn rigid(mut expr: &Expr, defs: &Defs, config: &Config) -> bool {
+        use Expr::*;
+        while let Abstr(_, body) = expr {
+            expr = body;
+        }
+        if !matches!(expr, Appl(..)) {
+            return false;
+        }
+        while let Appl(f, _) = expr {
+            expr = f;
+        }
+        match expr {
+            Variable(_) => true,
+            Name(n) if defs.contains_key(n) => false,
+            Name(n) if config.primitives => !is_primitive(n),
+            Name(n) => n.parse::<u64>().is_err(),
+            Abstr(..) => false,
+            Appl(..) => unreachable!(),
+        }
+    }
+
+    /// Whether the innermost parameter can be eta reduced
+    fn eta(mut expr: &Expr) -> bool {
+        use Expr::*;
+        let mut param = None;
+        while let Abstr(p, body) = expr {
+            param = Some(*p);
+            expr = body;
+        }
+        matches!((param, expr), (Some(p), Appl(rest, box Variable(last)))
+            if *last == p && !rest.unbounds().contains(&p))
+    }
+
+    fn join(self, args: impl IntoIterator<Item = Box<Expr>>) -> Box<Expr> {
+        let mut expr = self.head;
+        for arg in args {
+            expr = appl(expr, arg);
+        }
+        for &p in self.params.iter().rev() {
+            expr = abstr(p, expr);
+        }
+        expr
+    }
+}
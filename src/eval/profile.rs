@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::expr::Expr;
+
+/// Index of a chain of unfoldings in a `Profile`
+type Tag = u32;
+
+/// Beta reductions by where the abstraction of their redex came from.
+///
+/// That is a chain of definitions, starting at the main expression: a node
+/// unfolded from `B` inside the body of `A` is tagged `main;A;B`. Substituted
+/// arguments keep their own tags.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    /// Parent chain and last definition of every chain, the first being the
+    /// main expression itself
+    chains: Vec<(Tag, String)>,
+    ids: HashMap<(Tag, String), Tag>,
+    betas: Vec<u64>,
+    /// The chains the abstractions and names of the term being reduced came
+    /// from, by the address of their node. Those of the main expression are
+    /// left out. The reducer moves and drops these as it does the nodes.
+    tags: HashMap<usize, Tag>,
+}
+
+/// The key of a node in `Profile::tags`, valid for as long as it lives
+pub(super) fn address(expr: &Expr) -> usize {
+    expr as *const Expr as usize
+}
+
+impl Profile {
+    pub(super) fn new() -> Self {
+        Profile {
+            chains: vec![(0, "main".into())],
+            ids: HashMap::new(),
+            betas: vec![0],
+            tags: HashMap::new(),
+        }
+    }
+
+    fn chain(&mut self, parent: Tag, name: String) -> Tag {
+        if let Some(&tag) = self.ids.get(&(parent, name.clone())) {
+            return tag;
+        }
+        let tag = self.chains.len() as Tag;
+        self.chains.push((parent, name.clone()));
+        self.ids.insert((parent, name), tag);
+        self.betas.push(0);
+        tag
+    }
+
+    /// Takes the tag of the node at `address`, which is going away.
+    pub(super) fn take(&mut self, address: usize) -> Tag {
+        self.tags.remove(&address).unwrap_or(0)
+    }
+
+    /// Counts a beta reduction of the abstraction, which is going away.
+    pub(super) fn beta(&mut self, abstr: &Expr) {
+        let tag = self.take(address(abstr));
+        self.betas[tag as usize] += 1;
+    }
+
+    /// Takes the tag of the name at `address` and gives back the one of
+    /// what it unfolds to.
+    pub(super) fn unfold(&mut self, address: usize, name: &str) -> Tag {
+        let tag = self.take(address);
+        self.chain(tag, name.to_owned())
+    }
+
+    /// Tags every node of a new subterm.
+    pub(super) fn tag(&mut self, expr: &Expr, tag: Tag) {
+        let mut stack = vec![expr];
+        while let Some(e) = stack.pop() {
+            match e {
+                Expr::Variable(_) => {}
+                Expr::Name(_) => {
+                    self.tags.insert(address(e), tag);
+                }
+                Expr::Abstr(_, b) => {
+                    self.tags.insert(address(e), tag);
+                    stack.push(b);
+                }
+                Expr::Appl(a, b) => {
+                    stack.push(b);
+                    stack.push(a);
+                }
+            }
+        }
+    }
+
+    /// Forgets the nodes of a subterm that is going away.
+    pub(super) fn forget(&mut self, expr: &Expr) {
+        let mut stack = vec![expr];
+        while let Some(e) = stack.pop() {
+            match e {
+                Expr::Variable(_) => {}
+                Expr::Name(_) => {
+                    self.tags.remove(&address(e));
+                }
+                Expr::Abstr(_, b) => {
+                    self.tags.remove(&address(e));
+                    stack.push(b);
+                }
+                Expr::Appl(a, b) => {
+                    stack.push(b);
+                    stack.push(a);
+                }
+            }
+        }
+    }
+
+    /// Gives the nodes of a copy the tags of those they were copied from.
+    pub(super) fn copy(&mut self, from: &Expr, to: &Expr) {
+        let mut stack = vec![(from, to)];
+        while let Some((f, t)) = stack.pop() {
+            match (f, t) {
+                (Expr::Name(_), _) | (Expr::Abstr(..), _) => {
+                    if let Some(&tag) = self.tags.get(&address(f)) {
+                        self.tags.insert(address(t), tag);
+                    }
+                }
+                _ => {}
+            }
+            match (f, t) {
+                (Expr::Abstr(_, b), Expr::Abstr(_, c)) => stack.push((b, c)),
+                (Expr::Appl(a, b), Expr::Appl(c, d)) => {
+                    stack.push((b, d));
+                    stack.push((a, c));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Moves the tag of a node whose contents moved from `from` to `to`.
+    pub(super) fn moved(&mut self, from: usize, to: &Expr) {
+        if let Some(tag) = self.tags.remove(&from) {
+            self.tags.insert(address(to), tag);
+        }
+    }
+
+    /// Gives the result of a primitive the tag of its name, forgetting the
+    /// application it replaces.
+    pub(super) fn delta(&mut self, appl: &Expr, res: &Expr) {
+        let mut head = appl;
+        while let Expr::Appl(f, _) = head {
+            head = f;
+        }
+        let tag = self.tags.get(&address(head)).copied().unwrap_or(0);
+        self.forget(appl);
+        self.tag(res, tag);
+    }
+
+    /// Drops the tags once the term is done with.
+    pub(super) fn finish(&mut self) {
+        self.tags = HashMap::new();
+    }
+
+    fn path(&self, mut tag: Tag) -> String {
+        let mut names = vec![self.chains[tag as usize].1.as_str()];
+        while tag != 0 {
+            tag = self.chains[tag as usize].0;
+            names.push(&self.chains[tag as usize].1);
+        }
+        names.reverse();
+        names.join(";")
+    }
+
+    /// All beta reductions
+    pub fn betas(&self) -> u64 {
+        self.betas.iter().sum()
+    }
+
+    /// Beta reductions whose abstraction came from `name` directly, over all
+    /// the chains it occurs in.
+    pub fn betas_in(&self, name: &str) -> u64 {
+        self.chains
+            .iter()
+            .zip(&self.betas)
+            .filter(|((_, n), _)| n == name)
+            .map(|(_, b)| b)
+            .sum()
+    }
+
+    /// The profile in the folded stack format of flame graph tools: a line
+    /// with the chain and its count of betas for every chain that has any.
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<(String, u64)> = (0..self.chains.len())
+            .filter(|&t| self.betas[t] > 0)
+            .map(|t| (self.path(t as Tag), self.betas[t]))
+            .collect();
+        lines.sort();
+        let mut out = String::new();
+        for (path, n) in lines {
+            writeln!(out, "{} {}", path, n).unwrap();
+        }
+        out
+    }
+}
+
@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+
+use super::eval::{reduce_with, Config};
+use super::numerals::Numerals;
+use crate::arena::{Arena, Node, NodeId};
+use crate::expr::{Defs, Expr, Memo};
+
+/// A name a closed term can be read back as
+struct Candidate {
+    name: String,
+    /// The normal form as it was found, parameter names included
+    normal: Box<Expr>,
+}
+
+/// A definition `\a..b.\c..d.E` whose application to its first parameters
+/// `a..b`, once reduced, is recognized as `Name A..B`
+struct Pattern {
+    name: String,
+    /// The parameters taken by the application
+    params: Vec<u8>,
+    /// `\c..d.E`, interned with `a..b` as free variables
+    body: NodeId,
+}
+
+/// Turns normal forms back into the names of the definitions they came from.
+///
+/// Every closed subterm alpha-equivalent to the normal form of a definition
+/// is replaced by its name, and so are numerals of `Config::numerals` by
+/// their numbers.
+/// Failing that, one that is the normal form of a definition applied to
+/// closed arguments, like `\f.f 2 3` for `Pair 2 3`, becomes that
+/// application with its arguments read back in turn.
+///
+/// When several names fit, the one whose normal form is written exactly like
+/// the subterm wins, since that is where its parameter names probably came
+/// from, then the longer name, then the one first in alphabetical order.
+pub struct Readback {
+    arena: Arena,
+    /// How many binders above each node its variables reach, `u32::MAX` for
+    /// nodes with free variables
+    loose: Vec<u32>,
+    names: HashMap<NodeId, Vec<Candidate>>,
+    patterns: Vec<Pattern>,
+    numerals: Numerals,
+}
+
+impl Readback {
+    /// Learns the normal forms of `defs`, normalizing those that aren't
+    /// memoized yet within the memoization limits of `config`.
+    pub fn new(defs: &Defs, config: &Config) -> Self {
+        let memo_config = Config {
+            max_iterations: config.memo_iterations,
+            max_size: config.memo_size,
+            ..config.clone()
+        };
+        let mut readback = Readback {
+            arena: Arena::new(),
+            loose: Vec::new(),
+            names: HashMap::new(),
+            patterns: Vec::new(),
+            numerals: config.numerals,
+        };
+
+        let mut sorted: Vec<_> = defs.iter().collect();
+        sorted.sort_by(|a, b| a.0.cmp(b.0));
+        for (name, def) in sorted {
+            let normal = match &def.normal {
+                Memo::Normal(normal) => normal.clone(),
+                Memo::GaveUp => continue,
+                Memo::Pending => match reduce_with(def.value.clone(), defs, &memo_config).0 {
+                    Ok(normal) => normal,
+                    Err(_) => continue,
+                },
+            };
+            if !normal.unbounds().is_empty() || !normal.names().is_empty() {
+                continue;
+            }
+            readback.learn(name, normal);
+        }
+        readback
+    }
+
+    fn learn(&mut self, name: &str, normal: Box<Expr>) {
+        // `\a..b.\c..d.E` for every split of the parameters that leaves at
+        // least one of each, provided `a..b` all occur in `E`
+        let mut params = Vec::new();
+        let mut body: &Expr = &normal;
+        while let Expr::Abstr(p, b) = body {
+            if params.contains(p) {
+                break;
+            }
+            params.push(*p);
+            body = b;
+            if let Expr::Abstr(..) = body {
+                let unbounds = body.unbounds();
+                if params.iter().all(|p| unbounds.contains(p)) {
+                    let body = self.arena.insert(body);
+                    self.patterns.push(Pattern {
+                        name: name.to_owned(),
+                        params: params.clone(),
+                        body,
+                    });
+                }
+            }
+        }
+
+        let id = self.arena.insert(&normal);
+        self.names.entry(id).or_default().push(Candidate {
+            name: name.to_owned(),
+            normal,
+        });
+    }
+
+    /// Reads back `expr`, which should be in normal form for the names to
+    /// mean the same as the subterms they replace.
+    pub fn apply(&mut self, expr: &Expr) -> Box<Expr> {
+        use Expr::*;
+        enum Job<'a> {
+            Visit(&'a Expr, NodeId),
+            Abstr(u8),
+            /// An application of this many read back parts
+            Appl(usize),
+        }
+
+        let root = self.arena.insert(expr);
+        self.update_loose();
+        let mut jobs = vec![Job::Visit(expr, root)];
+        let mut done: Vec<Box<Expr>> = Vec::new();
+        while let Some(job) = jobs.pop() {
+            match job {
+                Job::Visit(expr, id) => {
+                    if self.loose[id.index()] == 0 {
+                        if let Some(name) = self.name(expr, id) {
+                            done.push(Box::new(Name(name)));
+                            continue;
+                        }
+                        if let Some((name, args)) = self.construct(expr, id) {
+                            done.push(Box::new(Name(name)));
+                            jobs.push(Job::Appl(args.len() + 1));
+                            for (arg, id) in args.into_iter().rev() {
+                                jobs.push(Job::Visit(arg, id));
+                            }
+                            continue;
+                        }
+                    }
+                    match (expr, self.arena.get(id)) {
+                        (Abstr(p, b), &Node::Abstr(c)) => {
+                            jobs.push(Job::Abstr(*p));
+                            jobs.push(Job::Visit(b, c));
+                        }
+                        (Appl(a, b), &Node::Appl(x, y)) => {
+                            jobs.push(Job::Appl(2));
+                            jobs.push(Job::Visit(b, y));
+                            jobs.push(Job::Visit(a, x));
+                        }
+                        (leaf, _) => done.push(Box::new(leaf.clone())),
+                    }
+                }
+                Job::Abstr(p) => {
+                    let body = done.pop().unwrap();
+                    done.push(Box::new(Abstr(p, body)));
+                }
+                Job::Appl(n) => {
+                    let parts = done.split_off(done.len() - n);
+                    let mut parts = parts.into_iter();
+                    let mut appl = parts.next().unwrap();
+                    for part in parts {
+                        appl = Box::new(Appl(appl, part));
+                    }
+                    done.push(appl);
+                }
+            }
+        }
+        done.pop().unwrap()
+    }
+
+    /// Extends `loose` to the nodes interned since the last time.
+    ///
+    /// Children are interned before their parents, so they are always
+    /// already known.
+    fn update_loose(&mut self) {
+        for node in &self.arena.nodes()[self.loose.len()..] {
+            let loose = match node {
+                Node::Bound(b) => b + 1,
+                Node::Free(_) => u32::MAX,
+                Node::Name(_) => 0,
+                &Node::Abstr(c) => match self.loose[c.index()] {
+                    u32::MAX => u32::MAX,
+                    l => l.saturating_sub(1),
+                },
+                &Node::Appl(a, b) => self.loose[a.index()].max(self.loose[b.index()]),
+            };
+            self.loose.push(loose);
+        }
+    }
+
+    /// The best name for the whole of a closed term, if any
+    fn name(&self, expr: &Expr, id: NodeId) -> Option<String> {
+        let numeral = self.numerals.decode(expr).and_then(|n| {
+            Some(Candidate {
+                name: n.to_string(),
+                normal: self.numerals.encode(n).ok()?,
+            })
+        });
+        let known = self.names.get(&id).into_iter().flatten();
+        known
+            .chain(numeral.as_ref())
+            .max_by(|a, b| {
+                let exact = |c: &Candidate| same(&c.normal, expr);
+                exact(a)
+                    .cmp(&exact(b))
+                    .then(a.name.len().cmp(&b.name.len()))
+                    .then(b.name.cmp(&a.name))
+            })
+            .map(|c| c.name.clone())
+    }
+
+    /// The best definition a closed term is an application of, with the
+    /// arguments it is applied to
+    fn construct<'a>(
+        &self,
+        expr: &'a Expr,
+        id: NodeId,
+    ) -> Option<(String, Vec<(&'a Expr, NodeId)>)> {
+        if !matches!(expr, Expr::Abstr(..)) {
+            return None;
+        }
+        self.patterns
+            .iter()
+            .filter_map(|pattern| Some((pattern, self.matches(pattern, expr, id)?)))
+            .max_by(|(a, _), (b, _)| {
+                a.params
+                    .len()
+                    .cmp(&b.params.len())
+                    .then(a.name.len().cmp(&b.name.len()))
+                    .then(b.name.cmp(&a.name))
+            })
+            .map(|(pattern, args)| (pattern.name.clone(), args))
+    }
+
+    /// The arguments `pattern` is applied to if it reduces to `expr`
+    fn matches<'a>(
+        &self,
+        pattern: &Pattern,
+        expr: &'a Expr,
+        id: NodeId,
+    ) -> Option<Vec<(&'a Expr, NodeId)>> {
+        let mut args: Vec<Option<(&Expr, NodeId)>> = vec![None; pattern.params.len()];
+        let mut stack = vec![(pattern.body, expr, id)];
+        while let Some((p, expr, id)) = stack.pop() {
+            match (self.arena.get(p), self.arena.get(id), expr) {
+                (Node::Free(v), ..) => {
+                    // Arguments can't refer to parameters inside the pattern
+                    if self.loose[id.index()] != 0 {
+                        return None;
+                    }
+                    let i = pattern.params.iter().position(|p| p == v)?;
+                    match args[i] {
+                        Some((_, bound)) if bound != id => return None,
+                        _ => args[i] = Some((expr, id)),
+                    }
+                }
+                (&Node::Abstr(pc), &Node::Abstr(c), Expr::Abstr(_, b)) => {
+                    stack.push((pc, b, c));
+                }
+                (&Node::Appl(pa, pb), &Node::Appl(a, b), Expr::Appl(ea, eb)) => {
+                    stack.push((pb, eb, b));
+                    stack.push((pa, ea, a));
+                }
+                (pnode, node, _) if pnode == node => {}
+                _ => return None,
+            }
+        }
+        args.into_iter().collect()
+    }
+}
+
+/// Reads back `expr` with the names of `defs`, see `Readback`.
+pub fn readback(expr: &Expr, defs: &Defs, config: &Config) -> Box<Expr> {
+    Readback::new(defs, config).apply(expr)
+}
+
+/// Whether two terms are written exactly alike, parameter names included
+fn same(a: &Expr, b: &Expr) -> bool {
+    use Expr::*;
+    let mut stack = vec![(a, b)];
+    while let Some(pair) = stack.pop() {
+        match pair {
+            (Variable(a), Variable(b)) if a == b => {}
+            (Name(a), Name(b)) if a == b => {}
+            (Abstr(p, a), Abstr(q, b)) if p == q => stack.push((a, b)),
+            (Appl(a, b), Appl(c, d)) => {
+                stack.push((a, c));
+                stack.push((b, d));
+            }
+            _ => return false,
+        }
+    }
+    true
+}
@@ -0,0 +1,380 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use super::eval::Config;
+use super::util::{is_primitive, numeral};
+use crate::arena::{Arena, Node, NodeId};
+use crate::expr::{Defs, Expr, Memo};
+use crate::types::{Type, Types};
+
+/// How much work the search for divergence may do. A beta reduction or an
+/// unfolding costs one, and one more for each parameter and argument around
+/// it, which the term is rebuilt with.
+const FUEL: usize = 100_000;
+
+/// Terms and definitions deeper than this aren't typed, since type inference
+/// recurses
+const MAX_DEPTH: u64 = 1000;
+
+/// What can be told about a term having a normal form without reducing it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Termination {
+    /// The term is simply typeable and uses no recursive definitions, which
+    /// guarantees a normal form
+    Normalizes(Type),
+    /// Normal order reduction comes back to the same term with nothing
+    /// consumed in between, like `(\x.xx)(\x.xx)` or `Y` applied to a
+    /// function that calls its argument first. The term that recurs is kept
+    /// when it is closed.
+    Diverges(Option<String>),
+    Unknown,
+}
+
+impl fmt::Display for Termination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Termination::Normalizes(ty) => {
+                write!(f, "has a normal form, being simply typeable as {}", ty)
+            }
+            Termination::Diverges(Some(term)) => {
+                write!(f, "has no normal form, reducing {} leads back to it", term)
+            }
+            Termination::Diverges(None) => {
+                write!(f, "has no normal form, its reduction leads back to itself")
+            }
+            Termination::Unknown => write!(f, "may or may not have a normal form"),
+        }
+    }
+}
+
+/// Tells whether `expr` obviously has a normal form or obviously has none,
+/// as best it can before it is reduced.
+///
+/// A term of a simple type normalizes, so Hindley–Milner typing settles the
+/// first. For the second, the head reduction of the term is followed for a
+/// while, and then that of the arguments its head normal form needs
+/// normalized, looking for a redex that comes back to the head before the
+/// arguments after it are used, or a term that needs its own normal form.
+pub fn termination(expr: &Expr, defs: &Defs, config: &Config) -> Termination {
+    let shallow = shallow(expr) && defs.values().all(|def| shallow(&def.value));
+    if shallow && !recursive(expr, defs) {
+        if let Ok(ty) = Types::new(defs, config).infer(expr) {
+            return Termination::Normalizes(ty);
+        }
+    }
+    let mut search = Search {
+        arena: Arena::new(),
+        defs,
+        config,
+        fuel: FUEL,
+        unfolded: HashMap::new(),
+    };
+    let root = search.arena.insert(expr);
+    match search.normalize(root) {
+        Ok(()) => Termination::Unknown,
+        Err(Found::Loop(id)) => {
+            Termination::Diverges(search.closed(id).then(|| search.arena.to_expr(id).tidy()))
+        }
+        Err(Found::Nothing) => Termination::Unknown,
+    }
+}
+
+/// Whether `expr` is no deeper than `MAX_DEPTH`, counting a numeral as deep
+/// as its value, which a unary one unfolds to
+fn shallow(expr: &Expr) -> bool {
+    let mut stack = vec![(expr, 1)];
+    while let Some((expr, depth)) = stack.pop() {
+        match expr {
+            _ if depth > MAX_DEPTH => return false,
+            Expr::Variable(_) => {}
+            Expr::Name(n) => {
+                if n.parse::<u64>().is_ok_and(|n| n > MAX_DEPTH - depth) {
+                    return false;
+                }
+            }
+            Expr::Abstr(_, b) => stack.push((b, depth + 1)),
+            Expr::Appl(a, b) => {
+                stack.push((a, depth + 1));
+                stack.push((b, depth + 1));
+            }
+        }
+    }
+    true
+}
+
+/// Whether `expr` uses a definition that uses itself, directly or not
+fn recursive(expr: &Expr, defs: &Defs) -> bool {
+    // Depth first, with the definitions being visited on the stack
+    let mut done: Vec<&str> = Vec::new();
+    let mut path: Vec<&str> = Vec::new();
+    let mut stack: Vec<(Option<&str>, Vec<&str>)> =
+        vec![(None, expr.names().into_iter().collect())];
+    while let Some((_, uses)) = stack.last_mut() {
+        match uses.pop() {
+            Some(name) => {
+                let (name, def) = match defs.get_key_value(name) {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                if path.contains(&&name[..]) {
+                    return true;
+                }
+                if !done.contains(&&name[..]) {
+                    path.push(name);
+                    stack.push((Some(name), def.value.names().into_iter().collect()));
+                }
+            }
+            None => {
+                if let (Some(name), _) = stack.pop().unwrap() {
+                    path.pop();
+                    done.push(name);
+                }
+            }
+        }
+    }
+    false
+}
+
+enum Found {
+    /// This term comes back to itself
+    Loop(NodeId),
+    /// The search ran out of fuel or reached a primitive
+    Nothing,
+}
+
+struct Search<'a> {
+    arena: Arena,
+    defs: &'a Defs,
+    config: &'a Config,
+    fuel: usize,
+    unfolded: HashMap<String, Option<NodeId>>,
+}
+
+impl Search<'_> {
+    /// Checks the parts of `root` that its normal form needs normalized too,
+    /// looking for one that needs itself normalized.
+    fn normalize(&mut self, root: NodeId) -> Result<(), Found> {
+        // The terms that need the next one normalized, each with the
+        // arguments of its head normal form left to check
+        let mut ancestors: Vec<(NodeId, Vec<NodeId>)> = Vec::new();
+        let mut on_path: HashSet<NodeId> = HashSet::new();
+        let mut next = Some(root);
+        loop {
+            if let Some(id) = next.take() {
+                if on_path.contains(&id) {
+                    return Err(Found::Loop(id));
+                }
+                match self.head_normalize(id) {
+                    Err(Found::Nothing) => {}
+                    res => {
+                        let (_, _, mut args) = self.spine(res?);
+                        args.reverse();
+                        ancestors.push((id, args));
+                        on_path.insert(id);
+                    }
+                }
+            }
+            match ancestors.last_mut() {
+                Some((_, args)) => match args.pop() {
+                    Some(arg) => next = Some(arg),
+                    None => {
+                        let (id, _) = ancestors.pop().unwrap();
+                        on_path.remove(&id);
+                    }
+                },
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Head reduces `id` to `\x..y.v A..B` for a variable or a name `v` that
+    /// can't be unfolded.
+    fn head_normalize(&mut self, mut id: NodeId) -> Result<NodeId, Found> {
+        // Redexes seen at the head and how many arguments followed them,
+        // fewer and fewer from the bottom up. Those followed by more
+        // arguments than there are now are dropped, since they can't recur
+        // without having used them.
+        let mut redexes: Vec<(NodeId, usize)> = Vec::new();
+        loop {
+            let (params, head, args) = self.spine(id);
+            match self.arena.get(head).clone() {
+                Node::Abstr(body) if !args.is_empty() => {
+                    let redex = self.arena.node(Node::Appl(head, args[0]));
+                    let rest = args.len() - 1;
+                    while redexes.last().is_some_and(|&(_, after)| after > rest) {
+                        redexes.pop();
+                    }
+                    if redexes.iter().any(|&(seen, _)| seen == redex) {
+                        return Err(Found::Loop(redex));
+                    }
+                    redexes.push((redex, rest));
+
+                    self.spend(params + args.len())?;
+                    let reduced = self.subst(body, args[0]);
+                    id = self.rebuild(params, reduced, &args[1..]);
+                }
+                Node::Name(name) => match self.unfold(&name)? {
+                    Some(value) => {
+                        // A name may unfold to itself
+                        self.spend(params + args.len())?;
+                        id = self.rebuild(params, value, &args);
+                    }
+                    None => return Ok(id),
+                },
+                _ => return Ok(id),
+            }
+        }
+    }
+
+    fn spend(&mut self, around: usize) -> Result<(), Found> {
+        let cost = around + 1;
+        if self.fuel < cost {
+            self.fuel = 0;
+            return Err(Found::Nothing);
+        }
+        self.fuel -= cost;
+        Ok(())
+    }
+
+    /// What a name unfolds to, `None` if it stays a name
+    fn unfold(&mut self, name: &str) -> Result<Option<NodeId>, Found> {
+        if let Some(&id) = self.unfolded.get(name) {
+            return Ok(id);
+        }
+        let id = match self.defs.get(name) {
+            Some(def) => Some(self.arena.insert(match &def.normal {
+                Memo::Normal(normal) => normal,
+                _ => &def.value,
+            })),
+            // Which arguments a primitive needs depends on their values
+            None if self.config.primitives && is_primitive(name) => return Err(Found::Nothing),
+            None => numeral(name, self.config).map(|n| self.arena.insert(&n)),
+        };
+        self.unfolded.insert(name.to_owned(), id);
+        Ok(id)
+    }
+
+    /// `\x..y.H A..B` as the number of parameters, `H` and `A..B`
+    fn spine(&self, mut id: NodeId) -> (usize, NodeId, Vec<NodeId>) {
+        let mut params = 0;
+        while let &Node::Abstr(body) = self.arena.get(id) {
+            params += 1;
+            id = body;
+        }
+        let mut args = Vec::new();
+        while let &Node::Appl(f, x) = self.arena.get(id) {
+            args.push(x);
+            id = f;
+        }
+        args.reverse();
+        (params, id, args)
+    }
+
+    fn rebuild(&mut self, params: usize, head: NodeId, args: &[NodeId]) -> NodeId {
+        let mut id = head;
+        for &arg in args {
+            id = self.arena.node(Node::Appl(id, arg));
+        }
+        for _ in 0..params {
+            id = self.arena.node(Node::Abstr(id));
+        }
+        id
+    }
+
+    /// `body` with `arg` in place of the variable its binder bound, and the
+    /// indices of the binders around adjusted for it going away
+    fn subst(&mut self, body: NodeId, arg: NodeId) -> NodeId {
+        self.map_leaves(body, |search, id, depth| match *search.arena.get(id) {
+            Node::Bound(i) if i == depth => search.shift(arg, depth),
+            Node::Bound(i) if i > depth => search.arena.node(Node::Bound(i - 1)),
+            _ => id,
+        })
+    }
+
+    /// Adds `by` to the indices of `id` that refer past it
+    fn shift(&mut self, id: NodeId, by: u32) -> NodeId {
+        if by == 0 || self.closed(id) {
+            return id;
+        }
+        self.map_leaves(id, |search, id, cutoff| match *search.arena.get(id) {
+            Node::Bound(i) if i >= cutoff => search.arena.node(Node::Bound(i + by)),
+            _ => id,
+        })
+    }
+
+    /// Rebuilds `id` with `leaf` of each variable or name and the number of
+    /// binders around it in its place. Shared subterms are rebuilt once for
+    /// every number of binders they are under.
+    fn map_leaves(
+        &mut self,
+        id: NodeId,
+        mut leaf: impl FnMut(&mut Self, NodeId, u32) -> NodeId,
+    ) -> NodeId {
+        enum Job {
+            Visit(NodeId, u32),
+            Abstr(NodeId, u32),
+            Appl(NodeId, u32),
+        }
+
+        let mut memo: HashMap<(NodeId, u32), NodeId> = HashMap::new();
+        let mut jobs = vec![Job::Visit(id, 0)];
+        let mut done: Vec<NodeId> = Vec::new();
+        while let Some(job) = jobs.pop() {
+            let (id, depth, res) = match job {
+                Job::Visit(id, depth) => {
+                    if let Some(&res) = memo.get(&(id, depth)) {
+                        done.push(res);
+                        continue;
+                    }
+                    match *self.arena.get(id) {
+                        Node::Abstr(b) => {
+                            jobs.push(Job::Abstr(id, depth));
+                            jobs.push(Job::Visit(b, depth + 1));
+                            continue;
+                        }
+                        Node::Appl(a, b) => {
+                            jobs.push(Job::Appl(id, depth));
+                            jobs.push(Job::Visit(b, depth));
+                            jobs.push(Job::Visit(a, depth));
+                            continue;
+                        }
+                        _ => (id, depth, leaf(self, id, depth)),
+                    }
+                }
+                Job::Abstr(id, depth) => {
+                    let b = done.pop().unwrap();
+                    (id, depth, self.arena.node(Node::Abstr(b)))
+                }
+                Job::Appl(id, depth) => {
+                    let b = done.pop().unwrap();
+                    let a = done.pop().unwrap();
+                    (id, depth, self.arena.node(Node::Appl(a, b)))
+                }
+            };
+            memo.insert((id, depth), res);
+            done.push(res);
+        }
+        done.pop().unwrap()
+    }
+
+    /// Whether no index of `id` refers past it
+    fn closed(&self, id: NodeId) -> bool {
+        let mut seen = HashSet::new();
+        let mut stack = vec![(id, 0)];
+        while let Some((id, depth)) = stack.pop() {
+            if !seen.insert((id, depth)) {
+                continue;
+            }
+            match *self.arena.get(id) {
+                Node::Bound(i) if i >= depth => return false,
+                Node::Bound(_) | Node::Free(_) | Node::Name(_) => {}
+                Node::Abstr(b) => stack.push((b, depth + 1)),
+                Node::Appl(a, b) => {
+                    stack.push((a, depth));
+                    stack.push((b, depth));
+                }
+            }
+        }
+        true
+    }
+}
@@ -2,6 +2,8 @@ use std::collections::HashMap;
 
 use super::*;
 use crate::expr::expr_aliases::*;
+use crate::debruijn::DeBruijn;
+use crate::expr::{Def, Defs, Memo};
 use crate::test::*;
 use crate::{vabstr, vappl};
 
@@ -73,3 +75,692 @@ fn stuff() {
     eprintln!("{:?}", expr);
     assert!(expr.alpha_eq(&red(vabstr!(b'c', b'a', appl(var(b'c'), var(b'a'))))));
 }
+
+#[test]
+fn renaming_under_binders() {
+    // z is renamed away from the z substituted for y, but not to a or b,
+    // which would capture it again
+    let expr = red(process(r"\z.(\yzab.yzb(ab))(f z)"));
+    let expected = process(r"\zcab.f z c b(ab)");
+    assert_eq!(DeBruijn::from_expr(&expr), DeBruijn::from_expr(&expected), "{}", expr);
+}
+
+#[test]
+fn deep_terms() {
+    // Deep enough to overflow the stack of any recursive traversal
+    let n = 100000;
+    let num = red(chnum(n));
+    assert_eq!(num.try_unchurch_num(), Some(n));
+
+    let mut nested = var(b'a');
+    for _ in 0..n {
+        nested = abstr(b'a', nested);
+    }
+    let reduced = red(nested.clone());
+    assert!(reduced.alpha_eq(&nested));
+    assert!(reduced.unbounds().is_empty());
+
+    // Parsing keeps the annotated terms too, which must not recurse either
+    let n = 200000;
+    let src = format!("{}x", r"\x.".repeat(n));
+    let parsed = crate::parse::parse_program(crate::lex::lex(src.as_bytes()).unwrap()).unwrap();
+    assert_eq!(parsed.main.unwrap().size(), n as u32 + 1);
+    assert!(parsed.annotated[0].term.to_string().ends_with(r"\x. x"));
+    let src = format!("Id = \\x.x;\nId{}", " Id".repeat(n));
+    let parsed = crate::parse::parse_program(crate::lex::lex(src.as_bytes()).unwrap()).unwrap();
+    assert!(parsed.annotated[1].term.to_string().starts_with("Id Id"));
+    let reduced = reduce(parsed.main.unwrap(), &parsed.defs).0.unwrap();
+    assert_eq!(reduced.tidy(), r"\x.x");
+}
+
+fn lib() -> Defs {
+    let src = include_bytes!("../../res/lib");
+    crate::parse::parse(crate::lex::lex(&src[..]).unwrap())
+        .unwrap()
+        .0
+}
+
+#[test]
+fn memoized_defs() {
+    let mut defs = lib();
+    let config = Config {
+        memoize: true,
+        ..Default::default()
+    };
+    memoize(&mut defs, &config);
+    assert!(
+        matches!(&defs["Succ"].normal, Memo::Normal(n) if n.alpha_eq(&process(r"\nfx.f(nfx)")))
+    );
+    assert!(matches!(defs["Y"].normal, Memo::GaveUp));
+    // Fact is normalized after Mul, Succ and P, which it uses
+    assert!(matches!(defs["Fact"].normal, Memo::Normal(_)));
+
+    let (plain, plain_stats) = reduce(process("Fact 3"), &defs);
+    let (memo, memo_stats) = reduce_with(process("Fact 3"), &defs, &config);
+    assert_eq!(memo.unwrap().try_unchurch_num(), Some(6));
+    assert_eq!(plain.unwrap().try_unchurch_num(), Some(6));
+    assert!(memo_stats.betas() < plain_stats.betas());
+}
+
+#[test]
+fn invalidated_defs() {
+    let mut defs = lib();
+    memoize(&mut defs, &Config::default());
+    invalidate(&mut defs, "Succ");
+    assert!(matches!(defs["Succ"].normal, Memo::Pending));
+    // Fact uses Succ directly, Pred' only through Wrap
+    assert!(matches!(defs["Fact"].normal, Memo::Pending));
+    assert!(matches!(defs["Sub'"].normal, Memo::Normal(_)));
+    assert!(matches!(defs["Pred'"].normal, Memo::Pending));
+    assert!(matches!(defs["Wrap"].normal, Memo::Normal(_)));
+}
+
+#[test]
+fn redefined_defs() {
+    let parse = |src: &str| {
+        crate::parse::parse(crate::lex::lex(src.as_bytes()).unwrap())
+            .unwrap()
+            .0
+    };
+    let config = Config {
+        memoize: true,
+        ..Default::default()
+    };
+    let mut defs = Defs::new();
+    define(&mut defs, parse(r"A = B; B = \x.x;"), &config);
+    define(&mut defs, parse(r"B = \xy.x;"), &config);
+    // A was first normalized with the old B
+    assert!(matches!(&defs["A"].normal, Memo::Normal(n) if n.alpha_eq(&process(r"\xy.x"))));
+}
+
+#[test]
+fn parallel_reduction() {
+    let defs = lib();
+    let parallel = Parallel {
+        threads: 4,
+        threshold: 1,
+    };
+    for src in [
+        "Fact 3",
+        "Pair (Fact 3) (Sub 7 3)",
+        r"\x.x (Mul 2 3) (Pow 2 3) x",
+        r"\fx.f (Add 2 2) x",
+        "Unknown (RecFact 3) (Eq 2 2)",
+        r"\n.n (\x.Succ 2 x)",
+    ] {
+        let (expected, seq) = reduce(process(src), &defs);
+        let (got, par) = reduce_par(process(src), &defs, &Config::default(), &parallel);
+        let (expected, got) = (expected.unwrap(), got.unwrap());
+        assert!(got.alpha_eq(&expected), "{}: {} != {}", src, got, expected);
+        assert_eq!(par.betas(), seq.betas(), "{}", src);
+        assert_eq!(par.etas(), seq.etas(), "{}", src);
+        assert_eq!(par.deltas(), seq.deltas(), "{}", src);
+        assert_eq!(par.max_depth(), seq.max_depth(), "{}", src);
+        assert_eq!(par.max_size(), seq.max_size(), "{}", src);
+        assert_eq!(par.unfolds(), seq.unfolds(), "{}", src);
+        assert_eq!(par.alphas(), seq.alphas(), "{}", src);
+        assert_eq!(par.cloned(), seq.cloned(), "{}", src);
+        assert_eq!(par.redex_depths(), seq.redex_depths(), "{}", src);
+    }
+
+    let config = Config {
+        max_iterations: 100,
+        ..Default::default()
+    };
+    let (res, _) = reduce_par(process(r"\x.x (Y I)"), &defs, &config, &parallel);
+    assert!(res.is_err());
+
+    // RecFact 3 grows to 1033 on its own and the whole term to 1037
+    let src = "Unknown (RecFact 3) (Eq 2 2)";
+    let config = Config {
+        max_size: 1035,
+        ..Default::default()
+    };
+    assert!(reduce_with(process(src), &defs, &config).0.is_err());
+    assert!(reduce_par(process(src), &defs, &config, &parallel).0.is_err());
+}
+
+#[test]
+fn deep_parallel_reduction() {
+    // Every argument is the spine of the next one, far too deep to split
+    // off on its own thread each
+    let n = 100000;
+    let expr = appl(abstr(b'y', var(b'y')), chnum(n));
+    let parallel = Parallel {
+        threads: 2,
+        threshold: 1,
+    };
+    let (got, par) = reduce_par(expr, &Defs::new(), &Config::default(), &parallel);
+    assert_eq!(got.unwrap().try_unchurch_num(), Some(n));
+    assert_eq!(par.betas(), 1);
+}
+
+#[test]
+fn cancelled() {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let defs = lib();
+    let config = Config {
+        cancel: Some(Arc::new(AtomicBool::new(true))),
+        ..Default::default()
+    };
+    let (res, stats) = reduce_with(process("Y I"), &defs, &config);
+    assert!(res.is_err());
+    assert!(stats.betas() < 10);
+    // A term that is already normal needs no second pass
+    let (res, _) = reduce_with(process(r"\x.x"), &defs, &config);
+    assert!(res.is_ok());
+
+    let config = Config {
+        timeout: Some(Duration::from_millis(50)),
+        ..Default::default()
+    };
+    let (res, stats) = reduce_with(process("Y I"), &defs, &config);
+    assert!(res.unwrap_err().to_string().contains("Time limit"));
+    assert!(stats.betas() < 10000000);
+}
+
+#[test]
+fn resumed() {
+    let defs = lib();
+    let config = Config {
+        max_iterations: 5,
+        ..Default::default()
+    };
+    let (res, _) = reduce_with(process("Fact 3"), &defs, &config);
+    let err = res.unwrap_err();
+    assert!(err.term().is_some());
+
+    let mut err = err;
+    let mut rounds = 0;
+    let normal = loop {
+        rounds += 1;
+        match resume(err, &defs, &config).0 {
+            Ok(normal) => break normal,
+            Err(e) => err = e,
+        }
+    };
+    assert!(rounds > 1);
+    assert_eq!(normal.try_unchurch_num(), Some(6));
+
+    // A term stopped part way through its arguments is whole
+    let parallel = Parallel {
+        threads: 2,
+        threshold: 1,
+    };
+    let (res, _) = reduce_par(process("x (Fact 3) (Fact 2)"), &defs, &config, &parallel);
+    let mut err = res.unwrap_err();
+    let normal = loop {
+        match resume(err, &defs, &Config::default()).0 {
+            Ok(normal) => break normal,
+            Err(e) => err = e,
+        }
+    };
+    let expected = reduce(process("x 6 2"), &defs).0.unwrap();
+    assert!(normal.alpha_eq(&expected));
+}
+
+#[test]
+fn detailed_stats() {
+    let defs = lib();
+    let (res, stats) = reduce(process("Fact 3"), &defs);
+    assert_eq!(res.unwrap().try_unchurch_num(), Some(6));
+    assert_eq!(stats.unfolds()["Fact"], 1);
+    assert!(stats.unfolds()["Mul"] >= 1);
+    assert_eq!(stats.redex_depths().values().sum::<u32>(), stats.betas());
+
+    // b would be captured by \b, and a is substituted twice
+    let (_, stats) = reduce(process(r"(\xa.\b.x x)(b a)"), &defs);
+    assert_eq!(stats.alphas(), 2);
+    assert_eq!(stats.cloned(), 2);
+
+    let json = stats.to_json();
+    assert!(json.starts_with(r#"{"betas":1,"etas":0,"#));
+    assert!(json.ends_with(r#""unfolds":{},"redex_depths":{"1":1}}"#));
+}
+
+#[test]
+fn profiled() {
+    let defs = lib();
+    let config = Config {
+        profile: true,
+        ..Default::default()
+    };
+    for src in [
+        "Fact 3",
+        "Sub 7 3",
+        "Sub' 7 3",
+        r"(\xa.\b.x x)(b a)",
+        "RecEq 2 2",
+    ] {
+        let (expected, plain) = reduce(process(src), &defs);
+        let (got, stats) = reduce_with(process(src), &defs, &config);
+        let (expected, got) = (expected.unwrap(), got.unwrap());
+        assert!(got.alpha_eq(&expected), "{}: {} != {}", src, got, expected);
+        assert_eq!(stats.betas(), plain.betas(), "{}", src);
+        assert_eq!(stats.profile().unwrap().betas(), stats.betas() as u64, "{}", src);
+    }
+    assert!(reduce(process("Fact 3"), &defs).1.profile().is_none());
+
+    let (_, stats) = reduce_with(process("Sub 7 3"), &defs, &config);
+    let profile = stats.profile().unwrap();
+    let folded = profile.folded();
+    assert!(folded.lines().any(|l| l.starts_with("main;Sub;Y ")));
+    assert!(profile.betas_in("Sub") > 0);
+    let total: u64 = folded
+        .lines()
+        .map(|l| l.rsplit(' ').next().unwrap().parse::<u64>().unwrap())
+        .sum();
+    assert_eq!(total, profile.betas());
+
+    // Both gives Twice Id, which gives \x.Id(Id x), whose inner Id then
+    // goes by an eta reduction
+    let mut defs = Defs::new();
+    defs.insert("Twice".into(), Def::new(process(r"\fx.f(fx)")));
+    defs.insert("Id".into(), Def::new(process(r"\a.a")));
+    defs.insert("Both".into(), Def::new(process(r"Twice Id")));
+    let (_, stats) = reduce_with(process(r"(\g.g) Both"), &defs, &config);
+    assert_eq!(
+        stats.profile().unwrap().folded(),
+        "main 1\nmain;Both;Id 1\nmain;Both;Twice 1\n"
+    );
+}
+
+#[test]
+fn eta_toggle() {
+    let defs = HashMap::new();
+    let no_eta = Config {
+        eta: false,
+        ..Default::default()
+    };
+    let (res, stats) = reduce_with(process(r"\x.(\y.f y) x"), &defs, &no_eta);
+    assert!(res.unwrap().alpha_eq(&process(r"\x.f x")));
+    assert_eq!(stats.etas(), 0);
+    assert!(red(process(r"\x.(\y.f y) x")).alpha_eq(&var(b'f')));
+
+    let expanded = util::eta_expand(process("a b"));
+    assert!(expanded.alpha_eq(&process(r"\c.a b c")));
+    assert!(util::eta_reduce(expanded).alpha_eq(&process("a b")));
+    // Only etas, inside out
+    let reduced = util::eta_reduce(process(r"\ab.(\x.x) a b"));
+    assert!(reduced.alpha_eq(&process(r"\x.x")));
+}
+
+#[test]
+fn equivalences() {
+    let defs = lib();
+    let config = Config::default();
+    for (a, b, expected) in [
+        (r"\x.x", r"\y.y", Equivalence::Alpha),
+        ("I I", "I", Equivalence::Beta),
+        ("Succ 1", "2", Equivalence::Beta),
+        (r"\x.f x", "f", Equivalence::Eta),
+        (r"\x.I f x", "f", Equivalence::BetaEta),
+        ("K", "I", Equivalence::Different),
+        (r"\c.c", r"\a.c", Equivalence::Different),
+    ] {
+        let got = equiv(&process(a), &process(b), &defs, &config).unwrap();
+        assert_eq!(got, expected, "{} and {}", a, b);
+    }
+    let config = Config {
+        max_iterations: 1000,
+        ..config
+    };
+    assert!(equiv(&process("Y I"), &process("I"), &defs, &config).is_err());
+}
+
+#[test]
+fn verdicts() {
+    let defs = lib();
+    let config = Config {
+        max_iterations: 1000,
+        ..Default::default()
+    };
+    let check = |a, b| equivalent(&process(a), &process(b), &defs, &config);
+    assert!(matches!(check("Mul 2 3", "6"), Verdict::Equal));
+    assert!(matches!(check(r"\x.f x", "f"), Verdict::Equal));
+    assert!(matches!(check("Mul 2 3", "5"), Verdict::NotEqual(..)));
+    assert!(matches!(check("Y I", "I"), Verdict::Unknown(_)));
+
+    let no_eta = Config {
+        eta: false,
+        ..config.clone()
+    };
+    let verdict = equivalent(&process(r"\x.f x"), &process("f"), &defs, &no_eta);
+    assert!(matches!(verdict, Verdict::NotEqual(..)));
+}
+
+#[test]
+fn normal_form_assertions() {
+    let defs = lib();
+    let config = Config::default();
+    let check = |a, b| normalizes_to(&process(a), &process(b), &defs, &config);
+    assert!(matches!(check("Is0 0", "True"), Verdict::Equal));
+    assert!(matches!(check("Pair 1 2", r"\f.f 1 2"), Verdict::Equal));
+    // Unlike `equivalent`, the right side is not reduced
+    assert!(matches!(check("I", "I I"), Verdict::NotEqual(..)));
+    match check("Not True", "True") {
+        Verdict::NotEqual(left, right) => {
+            assert!(left.alpha_eq(&process(r"\tf.f")));
+            assert!(right.alpha_eq(&process(r"\tf.t")));
+        }
+        v => panic!("{}", v),
+    }
+}
+
+#[test]
+fn lib_assertions() {
+    let mut defs = lib();
+    let src = include_bytes!("../../res/lib_test");
+    let program = crate::parse::parse_program(crate::lex::lex(&src[..]).unwrap()).unwrap();
+    defs.extend(program.defs);
+    assert!(!program.asserts.is_empty());
+    for assert in &program.asserts {
+        let verdict = check(assert, &defs, &Config::default());
+        assert!(
+            matches!(verdict, Verdict::Equal),
+            "{}:{}: {}",
+            assert.row,
+            assert.col,
+            verdict
+        );
+    }
+}
+
+#[test]
+fn read_back() {
+    let defs = lib();
+    let config = Config::default();
+    let mut readback = Readback::new(&defs, &config);
+    let mut back = |src| {
+        let normal = reduce_with(process(src), &defs, &config).0.unwrap();
+        readback.apply(&normal).tidy()
+    };
+    for (src, expected) in [
+        ("Is0 0", "True"),
+        ("Not True", "False"),
+        ("Mul 2 3", "6"),
+        ("Pair 2 3", "Pair 2 3"),
+        ("Pair (Pair 0 True) (Add 1 1)", "Pair (Pair 0 True) 2"),
+        (r"\x.x (Pair 2 3)", "Wrap (Pair 2 3)"),
+        (r"\x.x x (Pair 2 3)", r"\x.xx(Pair 2 3)"),
+    ] {
+        assert_eq!(back(src), expected, "{}", src);
+    }
+}
+
+#[test]
+fn encoded_data() {
+    let defs = lib();
+    let normal = |expr| reduce(expr, &defs).0.unwrap();
+    let fst = normal(appl(name("Fst"), pair(chnum(4), church_bool(false))));
+    assert_eq!(fst.try_unchurch_num(), Some(4));
+    let not = normal(appl(name("Not"), church_bool(true)));
+    assert!(not.alpha_eq(&church_bool(false)));
+    let built = normal(vappl!(name("Pair"), name("True"), (2, 3).encode()));
+    assert!(built.alpha_eq(&pair(church_bool(true), pair(chnum(2), chnum(3)))));
+}
+
+#[test]
+fn primitives() {
+    let defs = lib();
+    let config = Config {
+        primitives: true,
+        ..Default::default()
+    };
+    let normal = |src| reduce_with(process(src), &defs, &config).0.unwrap();
+    let int = |src| match *normal(src) {
+        Expr::Name(ref n) => n.parse::<u64>().ok(),
+        _ => None,
+    };
+    assert_eq!(int("+ 2 (* 3 4)"), Some(14));
+    assert_eq!(int("- 2 5"), Some(0));
+    assert_eq!(int("* 4294967296 4294967295"), Some(18446744069414584320));
+    assert_eq!(int("If (== 1 2) 10 20"), Some(20));
+    assert_eq!(int("(If True + *) 3 4"), Some(7));
+    // Pure code gets and gives Church numerals
+    assert_eq!(int("FromChurch (Mul (ToChurch 6) (ToChurch 7))"), Some(42));
+    assert!(normal("Not (== 2 2)").alpha_eq(&church_bool(false)));
+    // Overflows and ill-typed arguments are left alone
+    assert!(int("* 18446744073709551615 2").is_none());
+    assert!(int(r"+ 1 \x.x").is_none());
+
+    let (res, stats) = reduce_with(process("+ 1 (+ 2 3)"), &defs, &config);
+    assert_eq!(res.unwrap().tidy(), "6");
+    assert_eq!(stats.deltas(), 2);
+    // Definitions come first, and without the option numerals are Church
+    let mut hidden = lib();
+    hidden.insert("+".into(), Def::new(process("Add")));
+    let (sum, stats) = reduce_with(process("+ 2 3"), &hidden, &config);
+    assert_ne!(sum.unwrap().tidy(), "5");
+    assert_eq!(stats.unfolds().get("+"), Some(&1));
+    let sum = reduce_with(process("+ 2 3"), &defs, &Config::default())
+        .0
+        .unwrap();
+    assert!(sum.tidy().starts_with('+'));
+
+    // And are profiled alike
+    let src = process("If (== (+ 1 1) 2) (Fst (Pair 5 6)) 0");
+    let profiled = Config {
+        profile: true,
+        ..config.clone()
+    };
+    let (res, stats) = reduce_with(src.clone(), &defs, &profiled);
+    let (expected, _) = reduce_with(src, &defs, &config);
+    assert_eq!(res.unwrap().tidy(), expected.unwrap().tidy());
+    assert_eq!(stats.profile().unwrap().betas(), stats.betas() as u64);
+}
+
+#[test]
+fn numeral_encodings() {
+    use crate::expr::decode::{BinaryNum, Decoder, Value};
+    use crate::parse::parse_program;
+
+    let program = |src: &[u8]| parse_program(crate::lex::lex(src).unwrap()).unwrap();
+    let configured = |program: &crate::parse::Program| {
+        let mut config = Config::default();
+        for pragma in &program.pragmas {
+            config.pragma(pragma).unwrap();
+        }
+        config
+    };
+    let num = |program: crate::parse::Program| {
+        let config = configured(&program);
+        let main = program.main.unwrap();
+        let normal = reduce_with(main, &program.defs, &config).0.unwrap();
+        config.numerals.decode(&normal)
+    };
+
+    // Literals follow the pragma of the file they are in
+    let scott = include_bytes!("../../res/scott");
+    let mut scott = program(&scott[..]);
+    assert_eq!(configured(&scott).numerals, Numerals::Scott);
+    scott.main = Some(process("Add 2 3"));
+    assert_eq!(num(scott), Some(5));
+    let prgt = include_bytes!("../../res/prgt");
+    let mut prgt = program(&prgt[..]);
+    prgt.main = Some(process("Mul 2 3"));
+    assert_eq!(num(prgt), Some(6));
+    assert_eq!(
+        num(program(b"#pragma numerals user\nZero = Z; Succ = S; 2")),
+        None
+    );
+    let user = reduce_with(
+        process("2"),
+        &Defs::new(),
+        &Config {
+            numerals: Numerals::User,
+            ..Default::default()
+        },
+    );
+    assert_eq!(user.0.unwrap().tidy(), "Succ (Succ Zero)");
+
+    // Binary numerals aren't limited to 32 bits
+    for &n in &[0, 1, 6, 1 << 40, u64::MAX] {
+        let encoded = Numerals::Binary.encode(n).unwrap();
+        assert_eq!(BinaryNum.decode(&encoded), Some(Value::Num(n)));
+        assert_eq!(Numerals::Binary.decode(&encoded), Some(n));
+    }
+    assert!(Numerals::Church.encode(1 << 40).is_err());
+    let big = program(b"#pragma numerals binary\n1099511627776");
+    assert_eq!(num(big), Some(1 << 40));
+
+    // Bad pragmas are reported where they are
+    let err = program(b"Id = \\x.x;\n#pragma numerals roman\n");
+    let err = configured_err(&err);
+    assert!(
+        err.contains("roman") && err.contains("church, scott"),
+        "{}",
+        err
+    );
+    let err = program(b"#pragma numbers church\n");
+    assert!(configured_err(&err).contains("Unknown pragma"));
+    assert!("peano".parse::<Numerals>().is_err());
+    assert_eq!("parigot".parse::<Numerals>(), Ok(Numerals::Parigot));
+}
+
+#[test]
+fn numerals_per_file() {
+    use crate::parse::parse_program;
+
+    // Each file's literals keep its own encoding, whichever file uses them
+    let files: [&[u8]; 2] = [
+        b"#pragma numerals scott\nTwo = 2;",
+        b"Three = 3;\nassert Two => \\zs.s(\\zs.s(\\zs.z));\nassert Three => \\fx.f(f(fx));",
+    ];
+    let config = Config::default();
+    let mut defs = Defs::new();
+    for src in files {
+        let mut program = parse_program(crate::lex::lex(src).unwrap()).unwrap();
+        let mut own = config.clone();
+        for pragma in &program.pragmas {
+            own.pragma(pragma).unwrap();
+        }
+        resolve_numerals(&mut program, &defs, &own).unwrap();
+        define(&mut defs, program.defs, &own);
+        for assert in &program.asserts {
+            assert!(matches!(check(assert, &defs, &own), Verdict::Equal));
+        }
+    }
+    assert!(defs["Two"].value.alpha_eq(&scott_num(2)));
+
+    // Defined names aren't numerals
+    let src = b"#pragma numerals scott\n3 = Two; 3";
+    let mut program = parse_program(crate::lex::lex(&src[..]).unwrap()).unwrap();
+    let scott = Config {
+        numerals: Numerals::Scott,
+        ..Default::default()
+    };
+    resolve_numerals(&mut program, &defs, &scott).unwrap();
+    assert_eq!(program.main.unwrap().tidy(), "3");
+    assert!(program.defs["3"].value.alpha_eq(&name("Two")));
+
+    // Literals too large to unfold are reported
+    let src = b"Id 4294967296";
+    let mut program = parse_program(crate::lex::lex(&src[..]).unwrap()).unwrap();
+    let err = resolve_numerals(&mut program, &defs, &scott).unwrap_err();
+    let err = err.to_string();
+    assert!(err.contains("4294967296") && err.contains("scott"), "{}", err);
+}
+
+fn configured_err(program: &crate::parse::Program) -> String {
+    let mut config = Config::default();
+    program
+        .pragmas
+        .iter()
+        .map(|pragma| config.pragma(pragma))
+        .find_map(Result::err)
+        .unwrap()
+        .to_string()
+}
+
+#[test]
+fn termination_analysis() {
+    let defs = lib();
+    let config = Config::default();
+    let check = |src| termination(&process(src), &defs, &config);
+
+    assert_eq!(
+        check(r"(\x.xx)(\x.xx)"),
+        Termination::Diverges(Some(r"(\a.aa)(\a.aa)".into()))
+    );
+    assert!(matches!(check("Y I"), Termination::Diverges(Some(_))));
+    assert!(matches!(check(r"Y (\rx.r x)"), Termination::Diverges(_)));
+    // The recursive call is needed even though it isn't at the head
+    assert!(matches!(check("Y Succ"), Termination::Diverges(_)));
+    assert!(matches!(check(r"\f.f(Y Succ)"), Termination::Diverges(_)));
+
+    // Recursion that stops, or divergence that is thrown away
+    assert_eq!(check("RecFact 3"), Termination::Unknown);
+    assert_eq!(check(r"(\x.y)((\x.xx)(\x.xx))"), Termination::Unknown);
+    assert_eq!(check(r"Fst (Pair I (Y I))"), Termination::Unknown);
+    assert!(reduce_with(process("RecFact 3"), &defs, &config).0.is_ok());
+
+    match check("Add 2 3") {
+        Termination::Normalizes(ty) => assert_eq!(ty.to_string(), "(a -> a) -> a -> a"),
+        other => panic!("{:?}", other),
+    }
+    // Typeable, but through a definition that calls itself
+    let mut defs = defs;
+    defs.insert("Loop".into(), Def::new(process("Loop")));
+    assert_eq!(
+        termination(&process("Loop"), &defs, &config),
+        Termination::Unknown
+    );
+
+    // Deep terms are neither typed nor searched recursively
+    let n = 200000;
+    let mut nested = var(b'a');
+    for _ in 0..n {
+        nested = abstr(b'a', nested);
+    }
+    assert_eq!(termination(&nested, &defs, &config), Termination::Unknown);
+    let mut ids = name("I");
+    for _ in 0..n {
+        ids = appl(ids, name("I"));
+    }
+    assert_eq!(termination(&ids, &defs, &config), Termination::Unknown);
+    let big = appl(name("I"), name("100000"));
+    assert_eq!(termination(&big, &defs, &config), Termination::Unknown);
+}
+
+#[test]
+fn cycle_detection() {
+    let defs = lib();
+    let config = Config {
+        cycles: Some(8),
+        ..Default::default()
+    };
+    let omega = process(r"(\x.xx)(\x.xx)");
+
+    let (res, stats) = reduce_with(omega.clone(), &defs, &config);
+    let err = res.unwrap_err();
+    let (passes, term) = err.cycle().unwrap();
+    assert_eq!(passes, 1);
+    assert!(term.alpha_eq(&omega));
+    assert!(err.term().is_none());
+    assert_eq!(stats.betas(), 1);
+
+    // The repeating term of `Y I` only comes back every other pass
+    let (res, _) = reduce_with(process("Y I"), &defs, &config);
+    assert_eq!(res.unwrap_err().cycle().unwrap().0, 2);
+
+    let parallel = Parallel {
+        threads: 2,
+        threshold: 1,
+    };
+    let (res, _) = reduce_par(process(r"\x.x (Y I)"), &defs, &config, &parallel);
+    assert!(res.unwrap_err().cycle().is_some());
+
+    // Growing terms never repeat, and reductions that end are unaffected
+    let config = Config {
+        max_iterations: 100,
+        ..config
+    };
+    let (res, _) = reduce_with(process(r"(\x.xxx)(\x.xxx)"), &defs, &config);
+    assert!(res.unwrap_err().cycle().is_none());
+    let (res, _) = reduce_with(process("Fact 3"), &defs, &config);
+    assert_eq!(res.unwrap().try_unchurch_num(), Some(6));
+}
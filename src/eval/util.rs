@@ -1,68 +1,265 @@
-use crate::expr::Expr;
-use empty_box::EmptyBox;
+use super::eval::{Config, Stats};
+use super::profile::address;
+use crate::expr::decode::{ChurchBool, ChurchNum, Decoder, Value};
+use crate::expr::expr_aliases::*;
+use crate::expr::{Defs, Expr, Unpacked};
 use std::collections::HashSet;
-use std::mem;
-use std::ptr;
+use std::convert::TryFrom;
 
-pub fn beta_reduce(expr: Box<Expr>, from: u8, to: Box<Expr>) -> Box<Expr> {
+#[allow(clippy::boxed_local)]
+pub fn beta_reduce(mut expr: Box<Expr>, from: u8, to: Box<Expr>, st: &mut Stats) -> Box<Expr> {
+    use Expr::*;
     let mut unbounds_to = to.unbounds();
     unbounds_to.insert(from);
 
-    enum Linear {
-        Move(Box<Expr>),
-        Clone(*const Expr),
-    }
-    use Linear::*;
-
     //println!("beta_reduce: {}", expr);
     //println!("  from: {}", ascii::escape_default(from).to_string());
     //println!("  to  : {}", to);
 
-    //print!("  unbound in to: ");
-    //for &a in unbounds_to.iter() {
-    //    print!("{}", ascii::escape_default(a).to_string())
-    //}
-    //println!();
-
-    fn beta(expr: Box<Expr>, from: u8, to: &mut Linear, to_unb: &HashSet<u8>) -> Box<Expr> {
-        use Expr::*;
-        let (ex, eb) = EmptyBox::take(expr);
-        match ex {
-            Name(_) => eb.put(ex),
-            Appl(a, b) => eb.put(Appl(beta(a, from, to, to_unb), beta(b, from, to, to_unb))),
-            Abstr(v, b) => eb.put(if v == from {
-                Abstr(v, b)
-            } else if to_unb.contains(&v) {
-                let (v, e) = alpha(v, b, to_unb);
-                Abstr(v, beta(e, from, to, to_unb))
-            } else {
-                Abstr(v, beta(b, from, to, to_unb))
-            }),
-            Variable(v) => {
-                if v == from {
-                    match to {
-                        // Safety:
-                        // Cto is never null. It always gets assigned a valid reference
-                        // It doesn't get invalidated, because everything is mutated once only
-                        Clone(cto) => unsafe { eb.put((**cto).clone()) },
-                        Move(_) => {
-                            let oto = mem::replace(to, Clone(ptr::null()));
-                            if let Move(a) = oto {
-                                *to = Clone(a.as_ref() as *const Expr); // Here
-                                a
-                            } else {
-                                unreachable!()
-                            }
-                        }
+    // Substitution happens in place: every free occurrence of `from` is
+    // collected, renaming abstractions that would capture `to` on the way
+    let mut sites: Vec<&mut Expr> = Vec::new();
+    let mut stack: Vec<&mut Expr> = vec![&mut expr];
+    while let Some(e) = stack.pop() {
+        match *e {
+            Name(_) => {}
+            Appl(ref mut a, ref mut b) => {
+                stack.push(b);
+                stack.push(a);
+            }
+            Abstr(v, _) if v == from => {}
+            Abstr(ref mut v, ref mut b) => {
+                if unbounds_to.contains(v) {
+                    let mut taken = b.unbounds();
+                    taken.extend(unbounds_to.iter());
+                    taken.extend(binders_around(b, *v));
+                    let unused = alpha_next(&taken);
+                    replace_var_in(b, *v, unused);
+                    *v = unused;
+                    st.alphas += 1;
+                }
+                stack.push(b);
+            }
+            Variable(v) if v == from => sites.push(e),
+            Variable(_) => {}
+        }
+    }
+
+    // The last occurrence gets `to` itself, the rest get copies
+    match sites.pop() {
+        Some(last) => {
+            if !sites.is_empty() {
+                st.cloned += to.size() as u64 * sites.len() as u64;
+            }
+            for site in sites {
+                *site = (*to).clone();
+                if let Some(profile) = &mut st.profile {
+                    profile.copy(&to, site);
+                }
+            }
+            let from = address(&to);
+            *last = *to;
+            if let Some(profile) = &mut st.profile {
+                profile.moved(from, last);
+            }
+        }
+        None => {
+            if let Some(profile) = &mut st.profile {
+                profile.forget(&to);
+            }
+        }
+    }
+    expr
+}
+
+/// `\a.E a` for an `a` that is not free in `E`
+pub fn eta_expand(expr: Box<Expr>) -> Box<Expr> {
+    use Expr::*;
+    let var = alpha_next(&expr.unbounds());
+    Box::new(Abstr(var, Box::new(Appl(expr, Box::new(Variable(var))))))
+}
+
+/// Performs every eta reduction in the term, and nothing else.
+pub fn eta_reduce(expr: Box<Expr>) -> Box<Expr> {
+    use Expr::*;
+    enum Job {
+        Visit(Box<Expr>),
+        Abstr(u8),
+        Appl,
+    }
+
+    // Bodies first, so that reducing one can only expose a redex above it
+    let mut jobs = vec![Job::Visit(expr)];
+    let mut done: Vec<Box<Expr>> = Vec::new();
+    while let Some(job) = jobs.pop() {
+        match job {
+            Job::Visit(e) => match e.unpack() {
+                Unpacked::Variable(v) => done.push(Box::new(Variable(v))),
+                Unpacked::Name(n) => done.push(Box::new(Name(n))),
+                Unpacked::Abstr(var, body) => {
+                    jobs.push(Job::Abstr(var));
+                    jobs.push(Job::Visit(body));
+                }
+                Unpacked::Appl(a, b) => {
+                    jobs.push(Job::Appl);
+                    jobs.push(Job::Visit(b));
+                    jobs.push(Job::Visit(a));
+                }
+            },
+            Job::Abstr(var) => {
+                let body = done.pop().unwrap();
+                let eta = matches!(&*body, Appl(rest, box Variable(last))
+                    if *last == var && !rest.unbounds().contains(&var));
+                if eta {
+                    match body.unpack() {
+                        Unpacked::Appl(rest, _) => done.push(rest),
+                        _ => unreachable!(),
                     }
                 } else {
-                    eb.put(ex)
+                    done.push(Box::new(Abstr(var, body)));
+                }
+            }
+            Job::Appl => {
+                let b = done.pop().unwrap();
+                let a = done.pop().unwrap();
+                done.push(Box::new(Appl(a, b)));
+            }
+        }
+    }
+    done.pop().unwrap()
+}
+
+/// Unfolds every definition and numeral, without reducing anything.
+///
+/// A definition that would unfold into itself is left as a name there, and
+/// numerals stay literals with `config.primitives`.
+pub fn unfold(expr: &Expr, defs: &Defs, config: &Config) -> Box<Expr> {
+    use Expr::*;
+    enum Job<'a> {
+        Visit(&'a Expr),
+        /// Done with the innermost definition being unfolded
+        Leave,
+        Abstr(u8),
+        Appl,
+    }
+
+    let mut unfolding: Vec<&str> = Vec::new();
+    let mut jobs = vec![Job::Visit(expr)];
+    let mut done: Vec<Box<Expr>> = Vec::new();
+    while let Some(job) = jobs.pop() {
+        match job {
+            Job::Visit(Variable(v)) => done.push(Box::new(Variable(*v))),
+            Job::Visit(Name(n)) => match defs.get_key_value(n) {
+                Some((name, def)) if !unfolding.contains(&name.as_str()) => {
+                    unfolding.push(name);
+                    jobs.push(Job::Leave);
+                    jobs.push(Job::Visit(&def.value));
                 }
+                Some(_) => done.push(Box::new(Name(n.clone()))),
+                None => done.push(match numeral(n, config) {
+                    Some(num) => num,
+                    None => Box::new(Name(n.clone())),
+                }),
+            },
+            Job::Visit(Abstr(v, b)) => {
+                jobs.push(Job::Abstr(*v));
+                jobs.push(Job::Visit(b));
+            }
+            Job::Visit(Appl(a, b)) => {
+                jobs.push(Job::Appl);
+                jobs.push(Job::Visit(b));
+                jobs.push(Job::Visit(a));
+            }
+            Job::Leave => {
+                unfolding.pop();
+            }
+            Job::Abstr(v) => {
+                let body = done.pop().unwrap();
+                done.push(Box::new(Abstr(v, body)));
+            }
+            Job::Appl => {
+                let b = done.pop().unwrap();
+                let a = done.pop().unwrap();
+                done.push(Box::new(Appl(a, b)));
             }
         }
     }
+    done.pop().unwrap()
+}
+
+/// What a numeral unfolds to under `config`, if `name` is one and doesn't
+/// stay a literal
+pub(super) fn numeral(name: &str, config: &Config) -> Option<Box<Expr>> {
+    if config.primitives {
+        return None;
+    }
+    config.numerals.encode(name.parse().ok()?).ok()
+}
+
+/// The primitives of `Config::primitives` and how many arguments they take
+const PRIMITIVES: [(&str, usize); 7] = [
+    ("+", 2),
+    ("*", 2),
+    ("-", 2),
+    ("==", 2),
+    ("If", 3),
+    ("ToChurch", 1),
+    ("FromChurch", 1),
+];
 
-    beta(expr, from, &mut Move(to), &unbounds_to)
+pub(super) fn is_primitive(name: &str) -> bool {
+    PRIMITIVES.iter().any(|&(p, _)| p == name)
+}
+
+/// The result of a primitive applied to `expr`'s arguments, if it is one
+/// given enough arguments of the right kind.
+///
+/// Integers are literals, and overflowing ones stay unreduced. Booleans are
+/// Church booleans, and numerals given to `ToChurch` must fit in a `u32`.
+pub(super) fn delta(expr: &Expr, defs: &Defs) -> Option<Box<Expr>> {
+    use Expr::*;
+    let mut head = expr;
+    let mut args = Vec::new();
+    while let Appl(f, x) = head {
+        args.push(&**x);
+        head = f;
+    }
+    args.reverse();
+    let op = match head {
+        Name(op) if !defs.contains_key(op) => op.as_str(),
+        _ => return None,
+    };
+    let &(_, arity) = PRIMITIVES.iter().find(|&&(p, _)| p == op)?;
+    if args.len() < arity {
+        return None;
+    }
+
+    let int = |e: &Expr| match e {
+        Name(n) => n.parse::<u64>().ok(),
+        _ => None,
+    };
+    let literal = |n: u64| name(n.to_string());
+    let (given, rest) = args.split_at(arity);
+    let mut res = match (op, given) {
+        ("+", [a, b]) => literal(int(a)?.checked_add(int(b)?)?),
+        ("*", [a, b]) => literal(int(a)?.checked_mul(int(b)?)?),
+        ("-", [a, b]) => literal(int(a)?.saturating_sub(int(b)?)),
+        ("==", [a, b]) => church_bool(int(a)? == int(b)?),
+        ("If", [cond, then, other]) => match ChurchBool.decode(cond)? {
+            Value::Bool(true) => Box::new((*then).clone()),
+            _ => Box::new((*other).clone()),
+        },
+        ("ToChurch", [n]) => Expr::church_num(u32::try_from(int(n)?).ok()?),
+        ("FromChurch", [n]) => match ChurchNum.decode(n)? {
+            Value::Num(n) => literal(n),
+            _ => return None,
+        },
+        _ => unreachable!(),
+    };
+    for arg in rest {
+        res = appl(res, Box::new((*arg).clone()));
+    }
+    Some(res)
 }
 
 pub fn alpha_next(taken: &HashSet<u8>) -> u8 {
@@ -77,29 +274,70 @@ pub fn alpha_next(taken: &HashSet<u8>) -> u8 {
 pub fn alpha(par: u8, body: Box<Expr>, to_taken: &HashSet<u8>) -> (u8, Box<Expr>) {
     let mut taken = body.unbounds();
     taken.extend(to_taken.iter());
+    taken.extend(binders_around(&body, par));
     let unused = alpha_next(&taken);
     (unused, replace_var(body, par, unused))
 }
 
-pub fn replace_var(expr: Box<Expr>, from: u8, to: u8) -> Box<Expr> {
+/// The parameters of the abstractions in `expr` around free occurrences of
+/// `var`, which `var` can't be renamed to without them capturing it
+fn binders_around(expr: &Expr, var: u8) -> HashSet<u8> {
     use Expr::*;
-    let (ex, eb) = EmptyBox::take(expr);
-    eb.put(match ex {
-        Name(_) => ex,
-        Appl(a, b) => Appl(replace_var(a, from, to), replace_var(b, from, to)),
-        Abstr(v, b) => {
-            if v == from {
-                Abstr(v, b)
-            } else {
-                Abstr(v, replace_var(b, from, to))
+    enum Job<'a> {
+        Visit(&'a Expr),
+        Leave,
+    }
+
+    let mut set = HashSet::new();
+    let mut around: Vec<u8> = Vec::new();
+    let mut jobs = vec![Job::Visit(expr)];
+    while let Some(job) = jobs.pop() {
+        match job {
+            Job::Visit(Variable(v)) if *v == var => set.extend(around.iter()),
+            Job::Visit(Variable(_)) | Job::Visit(Name(_)) => {}
+            Job::Visit(Abstr(v, _)) if *v == var => {}
+            Job::Visit(Abstr(v, b)) => {
+                around.push(*v);
+                jobs.push(Job::Leave);
+                jobs.push(Job::Visit(b));
+            }
+            Job::Visit(Appl(a, b)) => {
+                jobs.push(Job::Visit(b));
+                jobs.push(Job::Visit(a));
+            }
+            Job::Leave => {
+                around.pop();
             }
         }
-        Variable(v) => {
-            if v == from {
-                Variable(to)
-            } else {
-                ex
+    }
+    set
+}
+
+pub fn replace_var(mut expr: Box<Expr>, from: u8, to: u8) -> Box<Expr> {
+    replace_var_in(&mut expr, from, to);
+    expr
+}
+
+fn replace_var_in(expr: &mut Expr, from: u8, to: u8) {
+    use Expr::*;
+    let mut stack = vec![expr];
+    while let Some(e) = stack.pop() {
+        match e {
+            Name(_) => {}
+            Appl(a, b) => {
+                stack.push(b);
+                stack.push(a);
+            }
+            Abstr(v, b) => {
+                if *v != from {
+                    stack.push(b)
+                }
+            }
+            Variable(v) => {
+                if *v == from {
+                    *v = to
+                }
             }
         }
-    })
+    }
 }
@@ -1,10 +1,14 @@
 use std::{
     ascii,
-    collections::{HashMap, HashSet},
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
     fmt,
+    hash::{Hash, Hasher},
+    mem::{self, ManuallyDrop},
+    ptr,
 };
 
-#[derive(Clone, Debug)]
+use crate::debruijn::DeBruijn;
+
 pub enum Expr {
     Variable(u8),
     Name(String),
@@ -12,9 +16,40 @@ pub enum Expr {
     Appl(Box<Expr>, Box<Expr>),
 }
 
+/// The fields of an `Expr`, moved out by `Expr::unpack`.
+///
+/// `Expr` implements `Drop`, so patterns cannot move out of it.
+pub enum Unpacked {
+    Variable(u8),
+    Name(String),
+    Abstr(u8, Box<Expr>),
+    Appl(Box<Expr>, Box<Expr>),
+}
+
+pub mod decode;
+
 pub type Defs = HashMap<String, Def>;
 pub struct Def {
     pub value: Box<Expr>,
+    pub normal: Memo,
+}
+
+/// What is known about the normal form of a definition
+pub enum Memo {
+    /// Not normalized yet, or invalidated since
+    Pending,
+    Normal(Box<Expr>),
+    /// No normal form was found within the limits
+    GaveUp,
+}
+
+impl Def {
+    pub fn new(value: Box<Expr>) -> Self {
+        Def {
+            value,
+            normal: Memo::Pending,
+        }
+    }
 }
 
 #[macro_use]
@@ -63,46 +98,177 @@ pub mod expr_aliases {
             abstr($p, vabstr!($($rest),+))
         };
     }
+
+    // Encoders of data, each the inverse of a decoder in `decode`. Items
+    // of lists and pairs are expected to be closed terms.
+
+    /// `\tf.t` or `\tf.f`
+    pub fn church_bool(b: bool) -> Box<Expr> {
+        vabstr!(b't', b'f', var(if b { b't' } else { b'f' }))
+    }
+
+    /// `\f.f A B`
+    pub fn pair(a: Box<Expr>, b: Box<Expr>) -> Box<Expr> {
+        abstr(b'f', vappl!(var(b'f'), a, b))
+    }
+
+    /// `\cn.c A (c B (.. n))`
+    pub fn church_list(items: impl IntoIterator<Item = Box<Expr>>) -> Box<Expr> {
+        let items: Vec<_> = items.into_iter().collect();
+        let mut list = var(b'n');
+        for item in items.into_iter().rev() {
+            list = vappl!(var(b'c'), item, list);
+        }
+        vabstr!(b'c', b'n', list)
+    }
+
+    /// `\nc.c A (\nc.c B (.. \nc.n))`
+    pub fn scott_list(items: impl IntoIterator<Item = Box<Expr>>) -> Box<Expr> {
+        let items: Vec<_> = items.into_iter().collect();
+        let mut list = vabstr!(b'n', b'c', var(b'n'));
+        for item in items.into_iter().rev() {
+            list = vabstr!(b'n', b'c', vappl!(var(b'c'), item, list));
+        }
+        list
+    }
+
+    /// `\zs.s N` for the predecessor `N`, down to `\zs.z`
+    pub fn scott_num(n: u32) -> Box<Expr> {
+        let mut num = vabstr!(b'z', b's', var(b'z'));
+        for _ in 0..n {
+            num = vabstr!(b'z', b's', appl(var(b's'), num));
+        }
+        num
+    }
+
+    /// `\zs.s N (N z s)` for the predecessor `N`, down to `\zs.z`
+    pub fn parigot_num(n: u32) -> Box<Expr> {
+        // `N z s` reduces to the body of `N`
+        let mut num = vabstr!(b'z', b's', var(b'z'));
+        let mut body = var(b'z');
+        for _ in 0..n {
+            body = vappl!(var(b's'), num, body);
+            num = vabstr!(b'z', b's', body.clone());
+        }
+        num
+    }
+
+    /// The bits of `n` from the lowest, each `\zot.o N` for 0 or `\zot.t N`
+    /// for 1 followed by the rest `N`, and ending in `\zot.z`
+    pub fn binary_num(n: u64) -> Box<Expr> {
+        let mut num = vabstr!(b'z', b'o', b't', var(b'z'));
+        let bits = 64 - n.leading_zeros();
+        for i in (0..bits).rev() {
+            let bit = if n >> i & 1 == 1 { b't' } else { b'o' };
+            num = vabstr!(b'z', b'o', b't', appl(var(bit), num));
+        }
+        num
+    }
+
+    /// A Church list of the characters of `s` as Church numerals
+    pub fn church_string(s: &str) -> Box<Expr> {
+        church_list(s.chars().map(|c| chnum(c as u32)))
+    }
+
+    /// Rust values that have a canonical encoding as terms
+    pub trait Encode {
+        fn encode(&self) -> Box<Expr>;
+    }
+
+    impl Encode for bool {
+        fn encode(&self) -> Box<Expr> {
+            church_bool(*self)
+        }
+    }
+
+    impl Encode for u32 {
+        fn encode(&self) -> Box<Expr> {
+            chnum(*self)
+        }
+    }
+
+    impl Encode for str {
+        fn encode(&self) -> Box<Expr> {
+            church_string(self)
+        }
+    }
+
+    impl Encode for String {
+        fn encode(&self) -> Box<Expr> {
+            church_string(self)
+        }
+    }
+
+    impl<A: Encode, B: Encode> Encode for (A, B) {
+        fn encode(&self) -> Box<Expr> {
+            pair(self.0.encode(), self.1.encode())
+        }
+    }
+
+    impl<T: Encode> Encode for [T] {
+        fn encode(&self) -> Box<Expr> {
+            church_list(self.iter().map(Encode::encode))
+        }
+    }
+
+    impl<T: Encode> Encode for Vec<T> {
+        fn encode(&self) -> Box<Expr> {
+            self.as_slice().encode()
+        }
+    }
 }
 
 impl Expr {
     pub fn alpha_eq(&self, other: &Self) -> bool {
         use Expr::*;
-        match (self, other) {
-            (Variable(v), Variable(w)) => v == w,
-            (Name(n), Name(m)) => n == m,
-            (Abstr(a_var, box a_body), Abstr(b_var, box b_body)) => {
-                if a_var == b_var {
-                    a_body.alpha_eq(b_body)
-                } else {
-                    let mut map = HashMap::new();
-                    map.insert(*b_var, *a_var);
-                    Expr::alpha_eq_mapped(a_body, b_body, map)
-                }
-            }
-            (Appl(a_f, a_x), Appl(b_f, b_x)) => a_f.alpha_eq(b_f) && a_x.alpha_eq(b_x),
-            _ => false,
+        enum Job<'a> {
+            Compare(&'a Expr, &'a Expr),
+            Leave(u8, u8),
         }
-    }
 
-    fn alpha_eq_mapped(&self, other: &Self, mut other_to_self: HashMap<u8, u8>) -> bool {
-        use Expr::*;
-        let conv = |c| other_to_self.get(c).unwrap_or(c);
-        match (self, other) {
-            (Variable(v), Expr::Variable(w)) => v == conv(w),
-            (Name(n), Expr::Name(m)) => n == m,
-            (Abstr(v, box b), Expr::Abstr(w, box c)) => {
-                if v != w {
-                    other_to_self.insert(*w, *v);
+        // Binding levels of the parameters in scope on either side,
+        // innermost last. Bound variables are the same if bound at the same
+        // level, free ones if they have the same name.
+        let mut scopes = [vec![Vec::new(); 256], vec![Vec::new(); 256]];
+        let mut level: u32 = 0;
+        let mut jobs = vec![Job::Compare(self, other)];
+        while let Some(job) = jobs.pop() {
+            match job {
+                Job::Compare(Variable(v), Variable(w)) => {
+                    let bound = (scopes[0][*v as usize].last(), scopes[1][*w as usize].last());
+                    let same = match bound {
+                        (None, None) => v == w,
+                        (l, m) => l == m,
+                    };
+                    if !same {
+                        return false;
+                    }
+                }
+                Job::Compare(Name(n), Name(m)) => {
+                    if n != m {
+                        return false;
+                    }
+                }
+                Job::Compare(Abstr(v, b), Abstr(w, c)) => {
+                    scopes[0][*v as usize].push(level);
+                    scopes[1][*w as usize].push(level);
+                    level += 1;
+                    jobs.push(Job::Leave(*v, *w));
+                    jobs.push(Job::Compare(b, c));
+                }
+                Job::Compare(Appl(f, x), Appl(g, y)) => {
+                    jobs.push(Job::Compare(x, y));
+                    jobs.push(Job::Compare(f, g));
+                }
+                Job::Compare(..) => return false,
+                Job::Leave(v, w) => {
+                    scopes[0][v as usize].pop();
+                    scopes[1][w as usize].pop();
+                    level -= 1;
                 }
-                Expr::alpha_eq_mapped(b, c, other_to_self)
-            }
-            (Appl(f, x), Expr::Appl(g, y)) => {
-                Expr::alpha_eq_mapped(f, g, other_to_self.clone())
-                    && Expr::alpha_eq_mapped(x, y, other_to_self)
             }
-            _ => false,
         }
+        true
     }
 
     pub fn size(&self) -> u32 {
@@ -130,26 +296,121 @@ impl Expr {
 
     pub fn unbounds(&self) -> HashSet<u8> {
         use Expr::*;
-        match self {
-            Variable(v) => {
-                let mut set = HashSet::new();
-                set.insert(*v);
-                set
+        enum Job<'a> {
+            Visit(&'a Expr),
+            Unbind(u8),
+        }
+
+        let mut set = HashSet::new();
+        let mut bound = [0u32; 256];
+        let mut jobs = vec![Job::Visit(self)];
+        while let Some(job) = jobs.pop() {
+            match job {
+                Job::Visit(Variable(v)) => {
+                    if bound[*v as usize] == 0 {
+                        set.insert(*v);
+                    }
+                }
+                Job::Visit(Name(_)) => {}
+                Job::Visit(Abstr(v, b)) => {
+                    bound[*v as usize] += 1;
+                    jobs.push(Job::Unbind(*v));
+                    jobs.push(Job::Visit(b));
+                }
+                Job::Visit(Appl(a, b)) => {
+                    jobs.push(Job::Visit(b));
+                    jobs.push(Job::Visit(a));
+                }
+                Job::Unbind(v) => bound[v as usize] -= 1,
             }
-            Name(_) => HashSet::new(),
-            Abstr(v, b) => {
-                let mut set = b.unbounds();
-                set.remove(v);
-                set
+        }
+        set
+    }
+
+    /// A hash of the structure of the expression, the same for expressions
+    /// that are `alpha_eq`
+    pub fn alpha_hash(&self) -> u64 {
+        use Expr::*;
+        enum Job<'a> {
+            Visit(&'a Expr),
+            /// Leave the binder of a variable, restoring the depth of the
+            /// binder it shadowed
+            Unbind(u8, u32),
+        }
+
+        // Bound variables hash as their de Bruijn index, free ones as
+        // themselves
+        let mut hasher = DefaultHasher::new();
+        let mut binders = [0u32; 256];
+        let mut depth = 0;
+        let mut jobs = vec![Job::Visit(self)];
+        while let Some(job) = jobs.pop() {
+            match job {
+                Job::Visit(Variable(v)) => match binders[*v as usize] {
+                    0 => (0u8, *v as u32).hash(&mut hasher),
+                    at => (1u8, depth - at).hash(&mut hasher),
+                },
+                Job::Visit(Name(n)) => (2u8, n).hash(&mut hasher),
+                Job::Visit(Abstr(v, b)) => {
+                    3u8.hash(&mut hasher);
+                    depth += 1;
+                    jobs.push(Job::Unbind(*v, binders[*v as usize]));
+                    binders[*v as usize] = depth;
+                    jobs.push(Job::Visit(b));
+                }
+                Job::Visit(Appl(a, b)) => {
+                    4u8.hash(&mut hasher);
+                    jobs.push(Job::Visit(b));
+                    jobs.push(Job::Visit(a));
+                }
+                Job::Unbind(v, shadowed) => {
+                    depth -= 1;
+                    binders[v as usize] = shadowed;
+                }
             }
-            Appl(a, b) => {
-                let mut set = a.unbounds();
-                for item in b.unbounds() {
-                    set.insert(item);
+        }
+        hasher.finish()
+    }
+
+    /// Every name referenced by the expression
+    pub fn names(&self) -> HashSet<&str> {
+        use Expr::*;
+        let mut set = HashSet::new();
+        let mut stack = vec![self];
+        while let Some(e) = stack.pop() {
+            match e {
+                Variable(_) => {}
+                Name(n) => {
+                    set.insert(n.as_str());
+                }
+                Abstr(_, b) => stack.push(b),
+                Appl(a, b) => {
+                    stack.push(b);
+                    stack.push(a);
                 }
-                set
             }
         }
+        set
+    }
+
+    /// Moves the fields out of the expression.
+    pub fn unpack(self) -> Unpacked {
+        let mut this = ManuallyDrop::new(self);
+        // Safety: every field is read exactly once, and `this` is never
+        // dropped afterwards, so nothing is freed twice
+        unsafe {
+            match &mut *this {
+                Expr::Variable(v) => Unpacked::Variable(*v),
+                Expr::Name(n) => Unpacked::Name(ptr::read(n)),
+                Expr::Abstr(p, b) => Unpacked::Abstr(*p, ptr::read(b)),
+                Expr::Appl(a, b) => Unpacked::Appl(ptr::read(a), ptr::read(b)),
+            }
+        }
+    }
+
+    /// Takes the expression, leaving a variable behind.
+    pub fn take(&mut self) -> Expr {
+        mem::replace(self, Expr::Variable(0))
     }
 
     pub fn church_num(mut n: u32) -> Box<Expr> {
@@ -163,6 +424,20 @@ impl Expr {
         ret
     }
 
+    /// The term as `Display` writes it, but with the spaces around names
+    /// only where they are needed
+    pub fn tidy(&self) -> String {
+        let padded = self.to_string();
+        let mut tidy = String::with_capacity(padded.len());
+        for word in padded.split_whitespace() {
+            if !tidy.is_empty() && !tidy.ends_with(['(', '.']) && !word.starts_with(')') {
+                tidy.push(' ');
+            }
+            tidy.push_str(word);
+        }
+        tidy
+    }
+
     pub fn try_unchurch_num(&self) -> Option<u32> {
         use Expr::*;
         match self {
@@ -185,10 +460,118 @@ impl Expr {
     }
 }
 
+impl Clone for Expr {
+    fn clone(&self) -> Self {
+        use Expr::*;
+        enum Job<'a> {
+            Visit(&'a Expr),
+            Abstr(u8),
+            Appl,
+        }
+
+        let mut jobs = vec![Job::Visit(self)];
+        let mut done: Vec<Expr> = Vec::new();
+        while let Some(job) = jobs.pop() {
+            match job {
+                Job::Visit(Variable(v)) => done.push(Variable(*v)),
+                Job::Visit(Name(n)) => done.push(Name(n.clone())),
+                Job::Visit(Abstr(p, b)) => {
+                    jobs.push(Job::Abstr(*p));
+                    jobs.push(Job::Visit(b));
+                }
+                Job::Visit(Appl(a, b)) => {
+                    jobs.push(Job::Appl);
+                    jobs.push(Job::Visit(b));
+                    jobs.push(Job::Visit(a));
+                }
+                Job::Abstr(p) => {
+                    let body = done.pop().unwrap();
+                    done.push(Abstr(p, Box::new(body)));
+                }
+                Job::Appl => {
+                    let b = done.pop().unwrap();
+                    let a = done.pop().unwrap();
+                    done.push(Appl(Box::new(a), Box::new(b)));
+                }
+            }
+        }
+        done.pop().unwrap()
+    }
+}
+
+impl Drop for Expr {
+    fn drop(&mut self) {
+        use Expr::*;
+        fn is_leaf(e: &Expr) -> bool {
+            matches!(e, Variable(_) | Name(_))
+        }
+        // Children are moved onto a stack and their boxes freed with leaves
+        // in them, so dropping never recurses more than one level
+        let mut stack: Vec<Expr> = Vec::new();
+        let mut next = match self {
+            Abstr(_, b) if !is_leaf(b) => b.take(),
+            Appl(a, b) if !is_leaf(a) || !is_leaf(b) => {
+                stack.push(b.take());
+                a.take()
+            }
+            _ => return,
+        };
+        loop {
+            match &mut next {
+                Abstr(_, b) => stack.push(b.take()),
+                Appl(a, b) => {
+                    stack.push(a.take());
+                    stack.push(b.take());
+                }
+                Variable(_) | Name(_) => {}
+            }
+            next = match stack.pop() {
+                Some(e) => e,
+                None => break,
+            };
+        }
+    }
+}
+
+impl fmt::Debug for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Expr::*;
+        enum Job<'a> {
+            Visit(&'a Expr),
+            Str(&'static str),
+        }
+
+        let mut jobs = vec![Job::Visit(self)];
+        while let Some(job) = jobs.pop() {
+            match job {
+                Job::Visit(Variable(v)) => write!(f, "Variable({})", v)?,
+                Job::Visit(Name(n)) => write!(f, "Name({:?})", n)?,
+                Job::Visit(Abstr(p, b)) => {
+                    write!(f, "Abstr({}, ", p)?;
+                    jobs.push(Job::Str(")"));
+                    jobs.push(Job::Visit(b));
+                }
+                Job::Visit(Appl(a, b)) => {
+                    write!(f, "Appl(")?;
+                    jobs.push(Job::Str(")"));
+                    jobs.push(Job::Visit(b));
+                    jobs.push(Job::Str(", "));
+                    jobs.push(Job::Visit(a));
+                }
+                Job::Str(s) => f.write_str(s)?,
+            }
+        }
+        Ok(())
+    }
+}
+
 impl fmt::Display for Expr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use Expr::*;
-        if !f.alternate() {
+        if f.sign_plus() {
+            // With de Bruijn indices, `{:+}`
+            write!(f, "{}", DeBruijn::from_expr(self))?;
+        } else if !f.alternate() {
             // Valid lambda expression
             let mut now = self;
             let mut depth = 0;
@@ -268,15 +651,14 @@ impl fmt::Display for Expr {
             }
         } else {
             // A tree representing the expression
-            fn tree(
-                f: &mut fmt::Formatter<'_>,
-                head_prepend: String,
-                mut rest_prepend: String,
-                expr: &Expr,
-            ) -> fmt::Result {
+            let mut stack: Vec<(String, String, &Expr)> =
+                vec![(String::new(), String::new(), self)];
+            while let Some((head_prepend, mut rest_prepend, expr)) = stack.pop() {
                 match expr {
-                    Variable(v) => writeln!(f, "{}Var {}", head_prepend, ascii::escape_default(*v)),
-                    Name(n) => writeln!(f, "{}Name {}", head_prepend, n),
+                    Variable(v) => {
+                        writeln!(f, "{}Var {}", head_prepend, ascii::escape_default(*v))?
+                    }
+                    Name(n) => writeln!(f, "{}Name {}", head_prepend, n)?,
                     Abstr(p, b) => {
                         write!(f, "{}Abstr", head_prepend)?;
                         let mut body = b.as_ref();
@@ -288,31 +670,30 @@ impl fmt::Display for Expr {
                         writeln!(f)?;
                         let rest = rest_prepend.clone() + "  ";
                         rest_prepend.push_str("`-");
-                        tree(f, rest_prepend, rest, body)
+                        stack.push((rest_prepend, rest, body));
                     }
                     Appl(a, b) => {
                         writeln!(f, "{}.", head_prepend)?;
-                        let mut stack: Vec<&Expr> = vec![b];
                         let mut current_a = a.as_ref();
+                        let mut args: Vec<&Expr> = vec![b];
                         while let Appl(aa, ab) = current_a {
-                            stack.push(ab.as_ref());
+                            args.push(ab.as_ref());
                             current_a = aa.as_ref();
                         }
-                        stack.push(current_a);
 
+                        // Pushed last to first, so printed first to last
+                        let last_rest = rest_prepend.clone() + "  ";
                         let head = rest_prepend.clone() + "|-";
                         let rest = rest_prepend.clone() + "| ";
-                        while stack.len() > 1 {
-                            tree(f, head.clone(), rest.clone(), stack.pop().unwrap())?;
-                        }
-
-                        let last_rest = rest_prepend.clone() + "  ";
                         rest_prepend.push_str("`-");
-                        tree(f, rest_prepend, last_rest, stack.pop().unwrap())
+                        stack.push((rest_prepend, last_rest, args[0]));
+                        for arg in &args[1..] {
+                            stack.push((head.clone(), rest.clone(), arg));
+                        }
+                        stack.push((head, rest, current_a));
                     }
                 }
             }
-            tree(f, String::new(), String::new(), self)?;
         };
         Ok(())
     }
@@ -346,7 +727,7 @@ mod tests {
     #[test]
     fn alpha_eq_1() -> Result<(), Box<dyn Error>> {
         let e1 = process(r#"\abc.ba(\b.cb)(\ac.ba)(\ap.caa)"#);
-        let e2 = process(r#"\bap.ab(\a.ca)(\bc.ab)(\vc.pvv)"#);
+        let e2 = process(r#"\bap.ab(\a.pa)(\bc.ab)(\vc.pvv)"#);
         assert!(e1.alpha_eq(&e2));
         Ok(())
     }
@@ -372,11 +753,33 @@ mod tests {
     #[test]
     fn alpha_eq_4() -> Result<(), Box<dyn Error>> {
         let e1 = process(r#"\abc.ba(\b.cb)(\ac.ba)(\ap.caa)"#);
-        let e2 = process(r#"\bap.ab(\a.ca)(\bc.ab)(\va.pvv)"#);
+        let e2 = process(r#"\bap.ab(\a.pa)(\bc.ab)(\va.pvv)"#);
         assert!(e1.alpha_eq(&e2));
         Ok(())
     }
 
+    #[test]
+    fn alpha_eq_shadowing() {
+        // The inner b shadows the outer one on one side only
+        assert!(!process(r"\ab.a").alpha_eq(&process(r"\bb.b")));
+        assert!(!process(r"\bb.b").alpha_eq(&process(r"\ab.a")));
+        assert!(process(r"\ab.b").alpha_eq(&process(r"\bb.b")));
+        // A bound variable is not the free one of the same name
+        assert!(!process(r"\x.x").alpha_eq(&process(r"\y.x")));
+    }
+
+    #[test]
+    fn alpha_hash() {
+        let e1 = process(r#"\abc.ba(\b.cb)(\ac.ba)(\ap.caa)d"#);
+        let e2 = process(r#"\bap.ab(\a.pa)(\bp.ab)(\vc.pvv)d"#);
+        assert!(e1.alpha_eq(&e2));
+        assert_eq!(e1.alpha_hash(), e2.alpha_hash());
+        // Same shape, but free variables and names stay apart
+        assert_ne!(process(r"\a.ab").alpha_hash(), process(r"\a.ac").alpha_hash());
+        assert_ne!(process(r"\a.ab").alpha_hash(), process(r"\a.aa").alpha_hash());
+        assert_ne!(process("A").alpha_hash(), process("B").alpha_hash());
+    }
+
     #[test]
     fn church_nums_0() {
         let zero = Expr::church_num(0);
@@ -395,4 +798,16 @@ mod tests {
     fn church_nums_7() {
         assert_eq!(Expr::church_num(7).try_unchurch_num(), Some(7));
     }
+
+    #[test]
+    fn deep_traversals() {
+        let n = 100000;
+        let num = Expr::church_num(n);
+        let copy = num.clone();
+        assert!(num.alpha_eq(&copy));
+        assert_eq!(copy.size(), n + 3);
+        assert_eq!(copy.unbounds().len(), 0);
+        assert!(format!("{}", copy).ends_with(&("x".to_owned() + &")".repeat(n as usize - 1))));
+        assert!(format!("{:?}", copy).starts_with("Abstr(102, Abstr(120, Appl("));
+    }
 }
@@ -0,0 +1,451 @@
+//! Reading data out of normal forms.
+//!
+//! Every `Decoder` recognizes one encoding. Most normal forms fit several of
+//! them, `\ab.b` being `false`, `0` and the empty list alike, so it is up to
+//! the caller which interpretations to show.
+
+use std::convert::TryFrom;
+use std::fmt;
+
+use super::Expr;
+
+/// A piece of data read out of a term
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Bool(bool),
+    Num(u64),
+    Pair(Box<Value>, Box<Value>),
+    List(Vec<Value>),
+    Str(String),
+    /// Part of the data that isn't itself a boolean or Church numeral
+    Term(String),
+}
+
+impl Value {
+    /// Reads an element of a pair or list, which is a Church numeral, a
+    /// Church boolean or otherwise kept as a term.
+    pub fn element(expr: &Expr) -> Value {
+        ChurchNum
+            .decode(expr)
+            .or_else(|| ChurchBool.decode(expr))
+            .unwrap_or_else(|| Value::Term(expr.tidy()))
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Num(n) => write!(f, "{}", n),
+            Value::Pair(a, b) => write!(f, "({}, {})", a, b),
+            Value::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Value::Str(s) => write!(f, "{:?}", s),
+            Value::Term(t) => write!(f, "{}", t),
+        }
+    }
+}
+
+/// An encoding of data as lambda terms
+pub trait Decoder {
+    /// What the encoding is called on the command line
+    fn name(&self) -> &'static str;
+    /// The data `expr` encodes, if it is a normal form of this encoding.
+    ///
+    /// Eta-reduced normal forms are recognized as well.
+    fn decode(&self, expr: &Expr) -> Option<Value>;
+}
+
+/// `\tf.t` and `\tf.f`
+pub struct ChurchBool;
+
+/// `\fx.f (.. (f x))`, including `\f.f` for 1
+pub struct ChurchNum;
+
+/// `\f.f A B`, as built by `Pair` in `res/lib`
+pub struct Pair;
+
+/// `\cn.c A (c B (.. n))`, a right fold
+pub struct ChurchList;
+
+/// A Church list of Church numerals, each the code point of a character
+pub struct ChurchString;
+
+/// `\nc.c A (\nc.c B (.. \nc.n))`
+pub struct ScottList;
+
+/// `\zs.s N` with `N` the predecessor, and `\zs.z` for 0, as in `res/scott`
+pub struct ScottNum;
+
+/// `\zs.s N (N z s)` with `N` the predecessor, and `\zs.z` for 0, as in
+/// `res/prgt`
+pub struct ParigotNum;
+
+/// `\zot.o N` or `\zot.t N` for a lowest bit of 0 or 1 and the rest `N`,
+/// down to `\zot.z`
+pub struct BinaryNum;
+
+/// Every built-in decoder
+pub fn decoders() -> Vec<Box<dyn Decoder>> {
+    vec![
+        Box::new(ChurchNum),
+        Box::new(ChurchBool),
+        Box::new(Pair),
+        Box::new(ChurchList),
+        Box::new(ChurchString),
+        Box::new(ScottList),
+        Box::new(ScottNum),
+        Box::new(ParigotNum),
+        Box::new(BinaryNum),
+    ]
+}
+
+/// The built-in decoder called `name`
+pub fn decoder(name: &str) -> Option<Box<dyn Decoder>> {
+    decoders().into_iter().find(|d| d.name() == name)
+}
+
+impl Decoder for ChurchBool {
+    fn name(&self) -> &'static str {
+        "church-bool"
+    }
+
+    fn decode(&self, expr: &Expr) -> Option<Value> {
+        let (ps, body) = params(expr, 2)?;
+        match body {
+            Expr::Variable(v) if *v == ps[0] => Some(Value::Bool(true)),
+            Expr::Variable(v) if *v == ps[1] => Some(Value::Bool(false)),
+            _ => None,
+        }
+    }
+}
+
+impl Decoder for ChurchNum {
+    fn name(&self) -> &'static str {
+        "church-num"
+    }
+
+    fn decode(&self, expr: &Expr) -> Option<Value> {
+        if let Some(n) = expr.try_unchurch_num() {
+            return Some(Value::Num(n.into()));
+        }
+        // `\fx.fx` with the `x` reduced away
+        match params(expr, 1)? {
+            (ps, Expr::Variable(v)) if *v == ps[0] => Some(Value::Num(1)),
+            _ => None,
+        }
+    }
+}
+
+impl Decoder for Pair {
+    fn name(&self) -> &'static str {
+        "pair"
+    }
+
+    fn decode(&self, expr: &Expr) -> Option<Value> {
+        let (ps, body) = params(expr, 1)?;
+        let (head, args) = spine(body);
+        match (head, &args[..]) {
+            (Expr::Variable(f), [a, b]) if *f == ps[0] && closed(a) && closed(b) => Some(
+                Value::Pair(Box::new(Value::element(a)), Box::new(Value::element(b))),
+            ),
+            _ => None,
+        }
+    }
+}
+
+impl Decoder for ChurchList {
+    fn name(&self) -> &'static str {
+        "church-list"
+    }
+
+    fn decode(&self, expr: &Expr) -> Option<Value> {
+        let (ps, mut rest) = params(expr, 2)?;
+        let (c, n) = (ps[0], ps[1]);
+        let mut items = Vec::new();
+        loop {
+            let (head, args) = spine(rest);
+            match (head, &args[..]) {
+                (Expr::Variable(v), []) if *v == n => return Some(Value::List(items)),
+                (Expr::Variable(v), [item, tail]) if *v == c && closed(item) => {
+                    items.push(Value::element(item));
+                    rest = *tail;
+                }
+                _ => return None,
+            }
+        }
+    }
+}
+
+impl Decoder for ChurchString {
+    fn name(&self) -> &'static str {
+        "church-string"
+    }
+
+    fn decode(&self, expr: &Expr) -> Option<Value> {
+        let items = match ChurchList.decode(expr)? {
+            Value::List(items) => items,
+            _ => return None,
+        };
+        let chars = items.into_iter().map(|item| match item {
+            Value::Num(n) => char::from_u32(u32::try_from(n).ok()?),
+            _ => None,
+        });
+        Some(Value::Str(chars.collect::<Option<_>>()?))
+    }
+}
+
+impl Decoder for ScottList {
+    fn name(&self) -> &'static str {
+        "scott-list"
+    }
+
+    fn decode(&self, mut expr: &Expr) -> Option<Value> {
+        let mut items = Vec::new();
+        loop {
+            let (ps, body) = params(expr, 2)?;
+            let (head, args) = spine(body);
+            match (head, &args[..]) {
+                (Expr::Variable(v), []) if *v == ps[0] => return Some(Value::List(items)),
+                (Expr::Variable(v), [item, tail]) if *v == ps[1] && closed(item) => {
+                    items.push(Value::element(item));
+                    expr = *tail;
+                }
+                _ => return None,
+            }
+        }
+    }
+}
+
+impl Decoder for ScottNum {
+    fn name(&self) -> &'static str {
+        "scott-num"
+    }
+
+    fn decode(&self, mut expr: &Expr) -> Option<Value> {
+        let mut n: u64 = 0;
+        loop {
+            let (ps, body) = params(expr, 2)?;
+            let (head, args) = spine(body);
+            match (head, &args[..]) {
+                (Expr::Variable(v), []) if *v == ps[0] => return Some(Value::Num(n)),
+                (Expr::Variable(v), [pred]) if *v == ps[1] => {
+                    n = n.checked_add(1)?;
+                    expr = *pred;
+                }
+                _ => return None,
+            }
+        }
+    }
+}
+
+impl Decoder for ParigotNum {
+    fn name(&self) -> &'static str {
+        "parigot-num"
+    }
+
+    fn decode(&self, expr: &Expr) -> Option<Value> {
+        // The body is `s N (s M (.. z))` for the predecessors `N`, `M`, ..,
+        // each of which must be a numeral one below the previous
+        let (ps, mut rest) = params(expr, 2)?;
+        let (z, s) = (ps[0], ps[1]);
+        let mut preds = Vec::new();
+        loop {
+            let (head, args) = spine(rest);
+            match (head, &args[..]) {
+                (Expr::Variable(v), []) if *v == z => break,
+                (Expr::Variable(v), [pred, tail]) if *v == s && closed(pred) => {
+                    preds.push(*pred);
+                    rest = *tail;
+                }
+                _ => return None,
+            }
+        }
+        let n = preds.len() as u64;
+        for (i, pred) in preds.into_iter().enumerate() {
+            if self.decode(pred) != Some(Value::Num(n - 1 - i as u64)) {
+                return None;
+            }
+        }
+        Some(Value::Num(n))
+    }
+}
+
+impl Decoder for BinaryNum {
+    fn name(&self) -> &'static str {
+        "binary-num"
+    }
+
+    fn decode(&self, mut expr: &Expr) -> Option<Value> {
+        let mut n: u64 = 0;
+        let mut bit: u32 = 0;
+        loop {
+            let (ps, body) = params(expr, 3)?;
+            let (head, args) = spine(body);
+            match (head, &args[..]) {
+                (Expr::Variable(v), []) if *v == ps[0] => return Some(Value::Num(n)),
+                (Expr::Variable(v), [rest]) if (*v == ps[1] || *v == ps[2]) && bit < 64 => {
+                    if *v == ps[2] {
+                        n |= 1 << bit;
+                    }
+                    bit += 1;
+                    expr = *rest;
+                }
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// The body under exactly `n` distinct parameters
+fn params(mut expr: &Expr, n: usize) -> Option<(Vec<u8>, &Expr)> {
+    let mut ps = Vec::with_capacity(n);
+    while ps.len() < n {
+        match expr {
+            Expr::Abstr(p, body) if !ps.contains(p) => {
+                ps.push(*p);
+                expr = body;
+            }
+            _ => return None,
+        }
+    }
+    if let Expr::Abstr(..) = expr {
+        return None;
+    }
+    Some((ps, expr))
+}
+
+/// The head of an application and its arguments
+fn spine(mut expr: &Expr) -> (&Expr, Vec<&Expr>) {
+    let mut args = Vec::new();
+    while let Expr::Appl(f, x) = expr {
+        args.push(&**x);
+        expr = f;
+    }
+    args.reverse();
+    (expr, args)
+}
+
+fn closed(expr: &Expr) -> bool {
+    expr.unbounds().is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::expr_aliases::*;
+    use crate::test::*;
+
+    fn decoded(src: &'static str) -> Vec<String> {
+        let expr = process(src);
+        decoders()
+            .iter()
+            .filter_map(|d| Some(format!("{}: {}", d.name(), d.decode(&expr)?)))
+            .collect()
+    }
+
+    #[test]
+    fn interpretations() {
+        assert_eq!(
+            decoded(r"\tf.t"),
+            [
+                "church-bool: true",
+                "scott-list: []",
+                "scott-num: 0",
+                "parigot-num: 0"
+            ]
+        );
+        assert_eq!(
+            decoded(r"\ab.b"),
+            [
+                "church-num: 0",
+                "church-bool: false",
+                "church-list: []",
+                "church-string: \"\""
+            ]
+        );
+        assert_eq!(decoded(r"\f.f"), ["church-num: 1"]);
+        assert_eq!(decoded(r"\f.f (\fx.f(f x)) (\tf.t)"), ["pair: (2, true)"]);
+        assert_eq!(
+            decoded(r"\cn.c (\fx.x) (c (\ab.a) (c (\x.x x) n))"),
+            ["church-list: [0, true, \\x.xx]"]
+        );
+        assert_eq!(
+            decoded(r"\nc.c (\fx.f(fx)) (\nc.c (\fx.f x) (\nc.n))"),
+            ["scott-list: [2, 1]"]
+        );
+    }
+
+    #[test]
+    fn numerals() {
+        let scott = r"\zs.s (\zs.s (\zs.z))";
+        assert_eq!(ScottNum.decode(&process(scott)), Some(Value::Num(2)));
+        assert_eq!(ParigotNum.decode(&process(scott)), None);
+
+        let two = r"\zs.s (\zs.s (\zs.z) z) (s (\zs.z) z)";
+        assert_eq!(ParigotNum.decode(&process(two)), Some(Value::Num(2)));
+        assert_eq!(ScottNum.decode(&process(two)), None);
+        // The predecessors have to count down
+        let wrong = r"\zs.s (\zs.z) (s (\zs.z) z)";
+        assert_eq!(ParigotNum.decode(&process(wrong)), None);
+    }
+
+    #[test]
+    fn encoders_round_trip() {
+        let num = |n| Some(Value::Num(n));
+        let list = |items: &[u32]| {
+            Some(Value::List(
+                items.iter().map(|&n| Value::Num(n.into())).collect(),
+            ))
+        };
+        assert_eq!(
+            ChurchBool.decode(&church_bool(true)),
+            Some(Value::Bool(true))
+        );
+        assert_eq!(
+            ChurchBool.decode(&church_bool(false)),
+            Some(Value::Bool(false))
+        );
+        assert_eq!(ChurchNum.decode(&chnum(7)), num(7));
+        assert_eq!(ScottNum.decode(&scott_num(7)), num(7));
+        assert_eq!(ParigotNum.decode(&parigot_num(4)), num(4));
+        assert_eq!(
+            Pair.decode(&pair(chnum(2), church_bool(true))),
+            Some(Value::Pair(
+                Box::new(Value::Num(2)),
+                Box::new(Value::Bool(true))
+            ))
+        );
+        assert_eq!(
+            ChurchList.decode(&church_list(vec![chnum(1), chnum(2)])),
+            list(&[1, 2])
+        );
+        assert_eq!(
+            ScottList.decode(&scott_list(vec![chnum(1), chnum(2)])),
+            list(&[1, 2])
+        );
+        assert_eq!(ScottList.decode(&scott_list(vec![])), list(&[]));
+        assert_eq!(
+            ChurchString.decode(&church_string("λx")),
+            Some(Value::Str("λx".into()))
+        );
+
+        assert!(true.encode().alpha_eq(&church_bool(true)));
+        assert_eq!(
+            Pair.decode(&(3, vec![1, 2]).encode()).unwrap().to_string(),
+            r"(3, \cn.c(\fx.fx)(c(\fx.f(fx))n))"
+        );
+        assert_eq!(
+            ChurchString.decode(&"hi".encode()),
+            Some(Value::Str("hi".into()))
+        );
+    }
+}
@@ -0,0 +1,591 @@
+//! Writing source files back in a canonical layout, keeping their comments.
+//!
+//! Definitions in a block of lines are aligned on their `=` and `:`, nested
+//! abstractions like `\a.\b.` become `\ab.`, and applications are spaced the
+//! way `Expr` is displayed: variables and parenthesized terms next to each
+//! other are written together, and names are set apart. Statements longer
+//! than the width are broken between the arguments of their applications.
+
+use std::error::Error;
+
+use crate::lex::{self, Token, TokenPos};
+use crate::parse;
+
+/// The width lines are wrapped at unless told otherwise
+pub const WIDTH: usize = 80;
+
+/// `src` formatted, if it parses
+pub fn format(src: &[u8], width: usize) -> Result<String, Box<dyn Error>> {
+    parse::parse_program(lex::lex(src)?)?;
+    let toks = lex::lex_with_comments(src)?;
+    let items = items(&toks)?;
+
+    let mut out = String::new();
+    let mut i = 0;
+    while i < items.len() {
+        // A block of definitions and signatures, aligned together
+        let mut end = i + 1;
+        while end < items.len() && items[i].named() && items[end].named() && !items[end].blank {
+            end += 1;
+        }
+        let align = items[i..end]
+            .iter()
+            .filter_map(|item| item.name.as_ref())
+            .map(|name| name.chars().count())
+            .max()
+            .unwrap_or(0);
+        for item in &items[i..end] {
+            if item.blank && !out.is_empty() {
+                out.push('\n');
+            }
+            let mut pieces = item.pieces.clone();
+            if let Some(name) = &item.name {
+                let pad = align - name.chars().count();
+                let head = format!("{}{} {} ", name, " ".repeat(pad), item.sign);
+                pieces.insert(0, Piece::Text(head));
+            }
+            render(&pieces, width, &mut out);
+            out.push('\n');
+        }
+        i = end;
+    }
+    Ok(out)
+}
+
+/// A comment, pragma or statement on lines of its own
+struct Item {
+    /// Whether a blank line comes before it
+    blank: bool,
+    /// The name of a definition or signature, written before `sign`
+    name: Option<String>,
+    sign: &'static str,
+    pieces: Vec<Piece>,
+}
+
+impl Item {
+    fn named(&self) -> bool {
+        self.name.is_some()
+    }
+}
+
+/// What statements are laid out with
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Piece {
+    Text(String),
+    /// A space, or a line break if its group doesn't fit
+    Line,
+    /// Nothing, or a line break if its group doesn't fit
+    Soft,
+    /// A line break
+    Hard,
+    /// Starts a group, which is broken at all its `Line`s and `Soft`s or
+    /// none of them
+    Open,
+    Close,
+    /// Indents the lines broken until the matching `Dedent` by this much more
+    Indent(usize),
+    Dedent,
+}
+
+/// Splits `toks` into items, laying out each statement.
+fn items(toks: &[TokenPos]) -> Result<Vec<Item>, Box<dyn Error>> {
+    let mut items: Vec<Item> = Vec::new();
+    let mut last_row = None;
+    let mut i = 0;
+    while i < toks.len() {
+        let TokenPos { tok, row, .. } = &toks[i];
+        let blank = last_row.is_some_and(|last| *row > last + 1);
+        let item = |pieces, name, sign| Item {
+            blank,
+            name,
+            sign,
+            pieces,
+        };
+        match tok {
+            Token::Comment(text) if last_row == Some(*row) => {
+                // Trails the statement before it
+                let last = items.last_mut().unwrap();
+                last.pieces
+                    .push(Piece::Text(format!(" #{}", text.trim_end())));
+                i += 1;
+            }
+            Token::Comment(text) => {
+                items.push(item(
+                    vec![Piece::Text(format!("#{}", text.trim_end()))],
+                    None,
+                    "",
+                ));
+                i += 1;
+            }
+            Token::Pragma(words) => {
+                let pragma = format!("#pragma {}", words.join(" "));
+                items.push(item(vec![Piece::Text(pragma)], None, ""));
+                i += 1;
+            }
+            _ => {
+                let mut end = i;
+                while end < toks.len() && toks[end].tok != Token::Semicolon {
+                    end += 1;
+                }
+                if end < toks.len() {
+                    end += 1;
+                } else {
+                    // Comments on lines after the main term are items of
+                    // their own
+                    while matches!(toks[end - 1].tok, Token::Comment(_))
+                        && toks[end - 1].row != toks[end - 2].row
+                    {
+                        end -= 1;
+                    }
+                }
+                let stmt = &toks[i..end];
+                let (name, sign, body) = match (&stmt[0].tok, stmt.get(1).map(|t| &t.tok)) {
+                    (Token::Capitalized(name), Some(Token::Equals)) => (Some(name.clone()), "=", 2),
+                    (Token::Capitalized(name), Some(Token::Colon)) => (Some(name.clone()), ":", 2),
+                    _ => (None, "", 0),
+                };
+                let pieces = if sign == ":" {
+                    let mut at = body;
+                    let ty = ty(stmt, &mut at)?;
+                    let mut pieces = vec![Piece::Text(ty)];
+                    if at < stmt.len() {
+                        pieces.push(Piece::Text(";".into()));
+                    }
+                    pieces
+                } else {
+                    statement(stmt, body)?
+                };
+                items.push(item(pieces, name, sign));
+                i = end;
+            }
+        }
+        last_row = Some(toks[i - 1].row);
+    }
+    Ok(items)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    /// A lowercase variable
+    Var,
+    /// A name, operator or type argument
+    Word,
+    Paren,
+    Abstr,
+}
+
+/// Whether `prev` and `next` are written together in an application
+fn tight(prev: Kind, next: Kind) -> bool {
+    use Kind::*;
+    matches!(prev, Var | Paren) && matches!(next, Var | Paren | Abstr)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Level {
+    Top,
+    Paren,
+    Abstr,
+}
+
+/// An application being laid out, and the last of its terms
+struct Frame {
+    level: Level,
+    prev: Option<Kind>,
+}
+
+/// Lays out the terms of a statement from `toks[start]`, after its name, with
+/// `assert` and its `==` or `=>`, and the `;` it ends with.
+fn statement(toks: &[TokenPos], start: usize) -> Result<Vec<Piece>, Box<dyn Error>> {
+    let mut pieces = vec![Piece::Open, Piece::Indent(4)];
+    let mut frames = vec![Frame {
+        level: Level::Top,
+        prev: None,
+    }];
+    pieces.extend([Piece::Open, Piece::Indent(0)]);
+
+    fn item(frames: &mut [Frame], pieces: &mut Vec<Piece>, kind: Kind) {
+        let frame = frames.last_mut().unwrap();
+        match frame.prev {
+            Some(prev) if tight(prev, kind) => pieces.push(Piece::Soft),
+            Some(_) => pieces.push(Piece::Line),
+            None => {}
+        }
+        frame.prev = Some(kind);
+    }
+    fn close_abstrs(frames: &mut Vec<Frame>, pieces: &mut Vec<Piece>) {
+        while frames.last().map(|f| f.level) == Some(Level::Abstr) {
+            frames.pop();
+            pieces.extend([Piece::Dedent, Piece::Close, Piece::Dedent, Piece::Close]);
+        }
+    }
+
+    let mut i = start;
+    while i < toks.len() {
+        let TokenPos { tok, row, .. } = &toks[i];
+        i += 1;
+        match tok {
+            Token::Char(c) => {
+                item(&mut frames, &mut pieces, Kind::Var);
+                pieces.push(Piece::Text((*c as char).to_string()));
+            }
+            Token::Capitalized(s) => {
+                item(&mut frames, &mut pieces, Kind::Word);
+                pieces.push(Piece::Text(s.clone()));
+            }
+            Token::Operator(c) => {
+                item(&mut frames, &mut pieces, Kind::Word);
+                pieces.push(Piece::Text((*c as char).to_string()));
+            }
+            Token::OpBracket => {
+                let ty = ty(toks, &mut i)?;
+                i += 1;
+                item(&mut frames, &mut pieces, Kind::Word);
+                pieces.push(Piece::Text(format!("[{}]", ty)));
+            }
+            Token::Assert => pieces.push(Piece::Text("assert ".into())),
+            Token::DoubleEquals if frames.last().unwrap().prev.is_none() => {
+                // The primitive, with nothing on its left
+                item(&mut frames, &mut pieces, Kind::Word);
+                pieces.push(Piece::Text("==".into()));
+            }
+            Token::DoubleEquals | Token::FatArrow => {
+                close_abstrs(&mut frames, &mut pieces);
+                frames.last_mut().unwrap().prev = None;
+                pieces.extend([Piece::Dedent, Piece::Close, Piece::Line]);
+                pieces.push(Piece::Text(format!("{} ", tok)));
+                pieces.extend([Piece::Open, Piece::Indent(0)]);
+            }
+            Token::OpParen => {
+                item(&mut frames, &mut pieces, Kind::Paren);
+                pieces.push(Piece::Text("(".into()));
+                frames.push(Frame {
+                    level: Level::Paren,
+                    prev: None,
+                });
+                pieces.extend([Piece::Open, Piece::Indent(2)]);
+            }
+            Token::ClParen => {
+                close_abstrs(&mut frames, &mut pieces);
+                frames.pop();
+                pieces.extend([Piece::Dedent, Piece::Close]);
+                pieces.push(Piece::Text(")".into()));
+            }
+            Token::Backslash => {
+                let (params, typed) = params(toks, &mut i)?;
+                item(&mut frames, &mut pieces, Kind::Abstr);
+                pieces.extend([Piece::Open, Piece::Text(format!("\\{}.", params))]);
+                pieces.push(Piece::Indent(2));
+                pieces.push(if typed { Piece::Line } else { Piece::Soft });
+                frames.push(Frame {
+                    level: Level::Abstr,
+                    prev: None,
+                });
+                pieces.extend([Piece::Open, Piece::Indent(2)]);
+            }
+            Token::Comment(text) if toks[i - 2].row == *row => {
+                // Kept right after what it follows, before any line break
+                let comment = Piece::Text(format!(" #{}", text.trim_end()));
+                let at = pieces.iter().rposition(|p| matches!(p, Piece::Text(_)));
+                let at = at.map_or(0, |at| at + 1);
+                pieces.splice(at..at, [comment, Piece::Hard]);
+            }
+            Token::Comment(text) => {
+                pieces.push(Piece::Hard);
+                pieces.push(Piece::Text(format!("#{}", text.trim_end())));
+                pieces.push(Piece::Hard);
+            }
+            Token::Semicolon => {
+                close_abstrs(&mut frames, &mut pieces);
+                pieces.extend([Piece::Dedent, Piece::Close, Piece::Dedent, Piece::Close]);
+                pieces.push(Piece::Text(";".into()));
+                return Ok(pieces);
+            }
+            tok => return Err(format!("Can't format {} in a term", tok).into()),
+        }
+    }
+    // The main term, without a `;`
+    close_abstrs(&mut frames, &mut pieces);
+    pieces.extend([Piece::Dedent, Piece::Close, Piece::Dedent, Piece::Close]);
+    Ok(pieces)
+}
+
+/// The parameters of an abstraction starting at `toks[*i]`, and those of
+/// the abstractions right in its body while none has type annotations.
+/// Gives back whether any has a type annotation or parameter, which are
+/// written with spaces.
+fn params(toks: &[TokenPos], i: &mut usize) -> Result<(String, bool), Box<dyn Error>> {
+    let mut out = String::new();
+    let mut spaced = false;
+    let mut annotated = false;
+    loop {
+        let tok = &toks.get(*i).ok_or("Abstraction without a body")?.tok;
+        *i += 1;
+        match tok {
+            Token::Char(c) => {
+                if spaced {
+                    out.push(' ');
+                }
+                out.push(*c as char);
+                spaced = false;
+            }
+            Token::OpBracket => {
+                if !out.is_empty() {
+                    out.push(' ');
+                }
+                out.push_str(&format!("[{}]", ty(toks, i)?));
+                *i += 1;
+                spaced = true;
+            }
+            Token::Colon => {
+                out.push_str(&format!(" : {}", ty(toks, i)?));
+                spaced = true;
+                annotated = true;
+            }
+            Token::Dot => {
+                let next_annotated = || {
+                    toks[*i + 1..]
+                        .iter()
+                        .take_while(|t| t.tok != Token::Dot)
+                        .any(|t| t.tok == Token::Colon)
+                };
+                match toks.get(*i) {
+                    Some(TokenPos {
+                        tok: Token::Backslash,
+                        ..
+                    }) if !annotated && !next_annotated() => *i += 1,
+                    _ => {
+                        let typed = annotated || out.contains('[');
+                        return Ok((out, typed));
+                    }
+                }
+            }
+            tok => return Err(format!("Can't format {} among parameters", tok).into()),
+        }
+    }
+}
+
+/// A type starting at `toks[*i]`, with `*i` left after it
+fn ty(toks: &[TokenPos], i: &mut usize) -> Result<String, Box<dyn Error>> {
+    let mut next = || {
+        let tok = toks.get(*i).map(|t| &t.tok);
+        *i += 1;
+        tok
+    };
+    let left = match next() {
+        Some(Token::Capitalized(all)) if all == "All" => {
+            let mut vars = Vec::new();
+            while let Some(Token::Capitalized(v)) = next() {
+                vars.push(v.as_str());
+            }
+            return Ok(format!("All {}. {}", vars.join(" "), ty(toks, i)?));
+        }
+        Some(Token::Capitalized(name)) => name.clone(),
+        Some(Token::OpParen) => {
+            let inner = ty(toks, i)?;
+            *i += 1;
+            format!("({})", inner)
+        }
+        tok => return Err(format!("Can't format {:?} in a type", tok).into()),
+    };
+    match toks.get(*i).map(|t| &t.tok) {
+        Some(Token::Arrow) => {
+            *i += 1;
+            Ok(format!("{} -> {}", left, ty(toks, i)?))
+        }
+        _ => Ok(left),
+    }
+}
+
+/// Writes `pieces` to `out`, breaking the groups that don't fit in `width`.
+fn render(pieces: &[Piece], width: usize, out: &mut String) {
+    // A width no line reaches, for what a line break must follow
+    const UNFIT: usize = 1 << 32;
+
+    // How wide everything before every piece is, written on one line
+    let mut before = Vec::with_capacity(pieces.len() + 1);
+    let mut total = 0;
+    for piece in pieces {
+        before.push(total);
+        total += match piece {
+            Piece::Text(s) => s.chars().count(),
+            Piece::Line => 1,
+            Piece::Hard => UNFIT,
+            _ => 0,
+        };
+    }
+    before.push(total);
+    // How wide each group is, with the text right after it
+    let mut ends = vec![0; pieces.len()];
+    let mut open = Vec::new();
+    for (i, piece) in pieces.iter().enumerate() {
+        match piece {
+            Piece::Open => open.push(i),
+            Piece::Close => ends[open.pop().unwrap()] = i,
+            _ => {}
+        }
+    }
+    let mut after = vec![0; pieces.len() + 1];
+    for i in (0..pieces.len()).rev() {
+        after[i] = match &pieces[i] {
+            Piece::Text(s) => s.chars().count() + after[i + 1],
+            Piece::Close | Piece::Dedent | Piece::Indent(_) => after[i + 1],
+            _ => 0,
+        };
+    }
+
+    let mut col = 0;
+    // Nothing but the indentation of this much is on the line yet
+    let mut fresh = (out.is_empty() || out.ends_with('\n')).then_some(0);
+    let mut line_indent = 0;
+    let mut indents = vec![0];
+    let mut broken = vec![true];
+    for (i, piece) in pieces.iter().enumerate() {
+        let indent = *indents.last().unwrap();
+        match piece {
+            Piece::Text(s) => {
+                if let Some(indent) = fresh.take() {
+                    out.push_str(&" ".repeat(indent));
+                    col = indent;
+                }
+                out.push_str(s);
+                col += s.chars().count();
+            }
+            Piece::Line if !broken.last().unwrap() => {
+                if fresh.is_none() {
+                    out.push(' ');
+                    col += 1;
+                }
+            }
+            Piece::Soft if !broken.last().unwrap() => {}
+            Piece::Line | Piece::Soft | Piece::Hard => {
+                if fresh.is_none() {
+                    out.push('\n');
+                    col = indent;
+                    line_indent = indent;
+                    fresh = Some(indent);
+                }
+            }
+            Piece::Open => {
+                let end = ends[i];
+                let wide = before[end] - before[i] + after[end + 1];
+                broken.push(*broken.last().unwrap() && col + wide > width);
+            }
+            Piece::Close => {
+                broken.pop();
+            }
+            // Relative to the line, so what opens on one line together is
+            // indented once
+            Piece::Indent(by) => indents.push(indent.max(line_indent + by)),
+            Piece::Dedent => {
+                indents.pop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lex::lex;
+    use crate::parse::parse_program;
+
+    fn fmt(src: &str) -> String {
+        format(src.as_bytes(), WIDTH).unwrap()
+    }
+
+    #[test]
+    fn canonical() {
+        let src = r"# Booleans
+True=\t.\f.t;   # the first
+False = \t f . f;
+Not=\b.b False   True;
+
+
+Xor = \a b.a (Not b)b;
+Pair = \a.\b.\f.f a b;
+#pragma numerals church
+assert   Not True==False;
+Succ : (All A. (A->A) -> A->A)->All A.(A->A)->A->A;
+Succ = \n[A]f x . f(n [A] f x);
+Pair (Succ 1)(\x.\y : A.x)
+";
+        let expected = r"# Booleans
+True  = \tf.t; # the first
+False = \tf.f;
+Not   = \b.b False True;
+
+Xor  = \ab.a(Not b)b;
+Pair = \abf.fab;
+#pragma numerals church
+assert Not True == False;
+Succ : (All A. (A -> A) -> A -> A) -> All A. (A -> A) -> A -> A;
+Succ = \n [A] fx. f(n [A] fx);
+Pair (Succ 1)(\x.\y : A. x)
+";
+        assert_eq!(fmt(src), expected);
+        assert_eq!(fmt(expected), expected);
+    }
+
+    #[test]
+    fn wrapping() {
+        let src = r"Fact = Y (\rn.If (Is0 n) 1 (Mul (r (Pred n)) n)); # recursive
+Fact 3";
+        let narrow = format(src.as_bytes(), 30).unwrap();
+        for line in narrow.lines() {
+            assert!(line.len() <= 30 || line.contains('#'), "{}", narrow);
+        }
+        assert!(narrow.lines().count() > 2);
+        assert!(narrow.contains("# recursive"));
+        assert_eq!(format(narrow.as_bytes(), 30).unwrap(), narrow);
+        assert_eq!(
+            format(narrow.as_bytes(), WIDTH).unwrap(),
+            "Fact = Y (\\rn.If (Is0 n) 1 (Mul (r(Pred n))n)); # recursive\nFact 3\n"
+        );
+
+        // A comment inside a term stays where it was
+        let src = "Id = \\x. # the identity\n  x;\n";
+        assert_eq!(fmt(src), "Id = \\x. # the identity\n    x;\n");
+        assert_eq!(fmt(&fmt(src)), fmt(src));
+    }
+
+    #[test]
+    fn keeps_meaning() {
+        for file in [
+            &include_bytes!("../res/lib")[..],
+            include_bytes!("../res/lib_test"),
+            include_bytes!("../res/systemf"),
+            include_bytes!("../res/recfact_inline"),
+            include_bytes!("../res/div"),
+            include_bytes!("../res/primfact"),
+            include_bytes!("../res/prgt"),
+        ] {
+            for width in [WIDTH, 20] {
+                let formatted = format(file, width).unwrap();
+                assert_eq!(format(formatted.as_bytes(), width).unwrap(), formatted);
+
+                let before = parse_program(lex(file).unwrap()).unwrap();
+                let after = parse_program(lex(formatted.as_bytes()).unwrap()).unwrap();
+                assert_eq!(before.defs.len(), after.defs.len());
+                for (name, def) in &before.defs {
+                    assert!(def.value.alpha_eq(&after.defs[name].value), "{}", name);
+                }
+                match (&before.main, &after.main) {
+                    (Some(a), Some(b)) => assert!(a.alpha_eq(b)),
+                    (a, b) => assert_eq!(a.is_none(), b.is_none()),
+                }
+                assert_eq!(before.asserts.len(), after.asserts.len());
+                assert_eq!(before.pragmas.len(), after.pragmas.len());
+                assert_eq!(before.signatures.len(), after.signatures.len());
+                let comments = |src: &[u8]| {
+                    lex::lex_with_comments(src)
+                        .unwrap()
+                        .into_iter()
+                        .filter(|t| matches!(t.tok, Token::Comment(_)))
+                        .count()
+                };
+                assert_eq!(comments(file), comments(formatted.as_bytes()));
+            }
+        }
+        assert!(format(b"Id = \\x.;", WIDTH).is_err());
+    }
+}
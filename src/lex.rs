@@ -9,12 +9,29 @@ use std::io::Read;
 pub enum Token {
     Char(u8),
     Capitalized(String),
+    /// `+`, `*` or `-`, the names of primitives
+    Operator(u8),
     Backslash,
     Dot,
     OpParen,
     ClParen,
     Equals,
+    DoubleEquals,
+    FatArrow,
     Semicolon,
+    /// `:` before a type
+    Colon,
+    /// `->` in a type
+    Arrow,
+    /// `[` around a type parameter or argument
+    OpBracket,
+    ClBracket,
+    /// `assert` or `Assert` at the start of a statement
+    Assert,
+    /// `#pragma` and the words after it on its line
+    Pragma(Vec<String>),
+    /// What follows `#` on a line, kept only by `lex_with_comments`
+    Comment(String),
 }
 use Token::*;
 
@@ -34,6 +51,16 @@ pub struct LexError {
 
 
 pub fn lex(input: impl Read) -> Result<Vec<TokenPos>, Box<dyn Error>> {
+    lex_all(input, false)
+}
+
+/// Lexes like `lex`, but keeps comments as `Comment` tokens, for tools that
+/// write the source back.
+pub fn lex_with_comments(input: impl Read) -> Result<Vec<TokenPos>, Box<dyn Error>> {
+    lex_all(input, true)
+}
+
+fn lex_all(input: impl Read, comments: bool) -> Result<Vec<TokenPos>, Box<dyn Error>> {
     let mut p = BufReader::new(input).bytes().peekable();
 
     let mut vec = Vec::new();
@@ -79,21 +106,106 @@ pub fn lex(input: impl Read) -> Result<Vec<TokenPos>, Box<dyn Error>> {
                 row,
             }),
             b'.' => vec.push(TokenPos { tok: Dot, col, row }),
-            b'=' => vec.push(TokenPos {
-                tok: Equals,
+            b'=' => {
+                if let Some(Ok(c @ (b'=' | b'>'))) = p.peek() {
+                    let tok = if *c == b'=' { DoubleEquals } else { FatArrow };
+                    p.next();
+                    vec.push(TokenPos { tok, col, row });
+                    col += 1;
+                } else {
+                    vec.push(TokenPos {
+                        tok: Equals,
+                        col,
+                        row,
+                    });
+                }
+            }
+            b'-' if matches!(p.peek(), Some(Ok(b'>'))) => {
+                p.next();
+                vec.push(TokenPos {
+                    tok: Arrow,
+                    col,
+                    row,
+                });
+                col += 1;
+            }
+            b'+' | b'*' | b'-' => vec.push(TokenPos {
+                tok: Operator(c),
                 col,
                 row,
             }),
+            // A comment to the end of the line, or a pragma
+            b'#' => {
+                let mut line = Vec::new();
+                while let Some(Ok(c)) = p.peek() {
+                    if let b'\n' | b'\r' = c {
+                        break;
+                    }
+                    line.push(*c);
+                    p.next();
+                }
+                let line = String::from_utf8_lossy(&line);
+                let mut words = line.split_whitespace().map(String::from);
+                if line.starts_with("pragma") && words.next().as_deref() == Some("pragma") {
+                    vec.push(TokenPos {
+                        tok: Pragma(words.collect()),
+                        col,
+                        row,
+                    });
+                } else if comments {
+                    vec.push(TokenPos {
+                        tok: Comment(line.to_string()),
+                        col,
+                        row,
+                    });
+                }
+                col += line.len() as u32;
+            }
             b';' => vec.push(TokenPos {
                 tok: Semicolon,
                 col,
                 row,
             }),
-            b'a'..=b'z' => vec.push(TokenPos {
-                tok: Char(c),
+            b':' => vec.push(TokenPos {
+                tok: Colon,
                 col,
                 row,
             }),
+            b'[' => vec.push(TokenPos {
+                tok: OpBracket,
+                col,
+                row,
+            }),
+            b']' => vec.push(TokenPos {
+                tok: ClBracket,
+                col,
+                row,
+            }),
+            // Every letter is a variable, unless the run of them is
+            // `assert` starting a statement
+            b'a'..=b'z' => {
+                let mut word = vec![c];
+                while let Some(Ok(c @ b'a'..=b'z')) = p.peek() {
+                    word.push(*c);
+                    p.next();
+                }
+                if word == b"assert" && statement_start(&vec) {
+                    vec.push(TokenPos {
+                        tok: Assert,
+                        col,
+                        row,
+                    });
+                } else {
+                    for (i, c) in word.iter().enumerate() {
+                        vec.push(TokenPos {
+                            tok: Char(*c),
+                            col: col + i as u32,
+                            row,
+                        });
+                    }
+                }
+                col += word.len() as u32 - 1;
+            }
             b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'\'' => {
                 let mut s = (c as char).to_string();
                 let scol = col;
@@ -123,20 +235,50 @@ pub fn lex(input: impl Read) -> Result<Vec<TokenPos>, Box<dyn Error>> {
         return err!("IO error: {:?}", e);
     }
 
+    keywords(&mut vec);
     Ok(vec)
 }
 
+/// Whether the next token starts a statement.
+fn statement_start(vec: &[TokenPos]) -> bool {
+    let before = vec.iter().rev().find(|t| !matches!(t.tok, Comment(_)));
+    before.is_none_or(|t| matches!(t.tok, Semicolon | Pragma(_)))
+}
+
+/// Turns `Assert` starting a statement into the keyword, unless it is
+/// defined there.
+fn keywords(vec: &mut [TokenPos]) {
+    for i in 0..vec.len() {
+        if vec[i].tok == Capitalized("Assert".into())
+            && statement_start(&vec[..i])
+            && !matches!(vec.get(i + 1), Some(TokenPos { tok: Equals, .. }))
+        {
+            vec[i].tok = Assert;
+        }
+    }
+}
+
 impl fmt::Display for Token {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Char(c) => f.write_str(&ascii::escape_default(*c).to_string()),
             Capitalized(s) => f.write_str(s),
+            Operator(c) => write!(f, "{}", *c as char),
             Backslash => write!(f, "\\"),
             OpParen => write!(f, "("),
             ClParen => write!(f, ")"),
             Dot => write!(f, "."),
             Equals => write!(f, "="),
+            DoubleEquals => write!(f, "=="),
+            FatArrow => write!(f, "=>"),
+            Assert => write!(f, "assert"),
+            Pragma(words) => write!(f, "#pragma {}", words.join(" ")),
+            Comment(text) => write!(f, "#{}", text),
             Semicolon => write!(f, ";"),
+            Colon => write!(f, ":"),
+            Arrow => write!(f, "->"),
+            OpBracket => write!(f, "["),
+            ClBracket => write!(f, "]"),
         }
     }
 }
@@ -220,9 +362,47 @@ mod tests {
         assert!(lex(src.as_bytes()).is_ok());
         let src = r"asdl\.\(()\)asfd fda \. a.\sd)()()l agsAS DF ((  ))";
         assert!(lex(src.as_bytes()).is_ok());
-        let src = r" ahgsdfiphgp *jhl3((((((((40218u fgSDFG as\\..\. \a\. ";
+        let src = r" ahgsdfiphgp %jhl3((((((((40218u fgSDFG as\\..\. \a\. ";
         assert!(lex(src.as_bytes()).is_err());
         let src = r" ahgsdfiphgp jhl3((((((((40218u fgSDFG as\\./.\. \a\. ";
         assert!(lex(src.as_bytes()).is_err());
     }
+
+    #[test]
+    fn lex3() {
+        let src = r"Assert a == b; A = B";
+        let toks: Vec<Token> = lex(src.as_bytes()).unwrap().into_iter().map(|t| t.tok).collect();
+        assert_eq!(toks[0], Assert);
+        assert_eq!(toks[2], DoubleEquals);
+        assert_eq!(toks[6], Equals);
+        let src = r"assert a => b; a s s e r t; asserts; Assert = x; b assert";
+        let toks: Vec<Token> = lex(src.as_bytes()).unwrap().into_iter().map(|t| t.tok).collect();
+        assert_eq!(toks[..4], [Assert, Char(b'a'), FatArrow, Char(b'b')]);
+        assert_eq!(toks.iter().filter(|t| **t == Assert).count(), 1);
+        let src = "# A comment (\\\n#pragma numerals  scott\n#pragmatic\nA";
+        let toks: Vec<Token> = lex(src.as_bytes()).unwrap().into_iter().map(|t| t.tok).collect();
+        let words = vec!["numerals".to_string(), "scott".to_string()];
+        assert_eq!(toks, [Pragma(words), Capitalized("A".into())]);
+        let toks: Vec<Token> = lex_with_comments(src.as_bytes())
+            .unwrap()
+            .into_iter()
+            .map(|t| t.tok)
+            .collect();
+        assert_eq!(toks[0], Comment(" A comment (\\".into()));
+        assert_eq!(toks[2], Comment("pragmatic".into()));
+        let src = "#pragma numerals scott\n# A comment\nassert A == B;";
+        let toks = lex_with_comments(src.as_bytes()).unwrap();
+        assert_eq!(toks[2].tok, Assert);
+        let src = r"+ 1 (* 2 3) - == If";
+        let toks: Vec<Token> = lex(src.as_bytes()).unwrap().into_iter().map(|t| t.tok).collect();
+        assert_eq!(toks[0], Operator(b'+'));
+        assert_eq!(toks[3], Operator(b'*'));
+        assert_eq!(toks[7..9], [Operator(b'-'), DoubleEquals]);
+        let src = r"F : All A. A -> A; \[A] x:A. x [A]";
+        let toks: Vec<Token> = lex(src.as_bytes()).unwrap().into_iter().map(|t| t.tok).collect();
+        assert_eq!(toks[1], Colon);
+        assert_eq!(toks[6], Arrow);
+        assert_eq!(toks[10..13], [OpBracket, Capitalized("A".into()), ClBracket]);
+        assert_eq!(toks[toks.len() - 3..], toks[10..13]);
+    }
 }
@@ -1,11 +1,18 @@
 #![feature(box_patterns)]
 
 pub mod repl;
+pub mod arena;
+pub mod blc;
+pub mod combinators;
+pub mod debruijn;
 pub mod eval;
 #[macro_use]
 pub mod expr;
+pub mod format;
 pub mod lex;
 pub mod parse;
+pub mod types;
+pub mod vm;
 
 #[cfg(test)]
 pub mod test;
@@ -1,13 +1,24 @@
+use rustlambda::arena::Arena;
+use rustlambda::debruijn::DeBruijn;
+use rustlambda::expr::{decode, Defs, Expr};
+use rustlambda::types::typed;
 #[allow(unused_imports)]
-use rustlambda::{eval, expr, lex, parse, repl};
+use rustlambda::{blc, combinators, eval, expr, format, lex, parse, repl, types};
 
-use std::env;
+use std::collections::HashMap;
 use std::error::Error;
-use std::fs::File;
-use std::io;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::LazyLock;
+use std::time::Duration;
 use structopt::StructOpt;
 
+/// The names of the built-in decoders, for `--decode`
+static DECODERS: LazyLock<Vec<&str>> =
+    LazyLock::new(|| decode::decoders().iter().map(|d| d.name()).collect());
+
 #[derive(Debug, StructOpt)]
 #[structopt(about = "Lambda calculus evaluator and more")]
 enum Opt {
@@ -15,16 +26,160 @@ enum Opt {
     Repl {
         #[structopt(parse(from_os_str))]
         files: Vec<PathBuf>,
+        #[structopt(flatten)]
+        eval: EvalOpts,
     },
     #[structopt(alias = "e")]
     Eval {
         #[structopt(parse(from_os_str))]
         files: Vec<PathBuf>,
+        #[structopt(flatten)]
+        eval: EvalOpts,
+        /// Reduce independent subterms on this many threads, 0 for one per CPU
+        #[structopt(long)]
+        threads: Option<usize>,
+        /// How to print the statistics of the reduction
+        #[structopt(long, default_value = "text", possible_values = &["text", "json"])]
+        stats: String,
+        /// Also write which definitions the beta reductions came from to
+        /// this file, as folded stacks for flame graph tools
+        #[structopt(long, parse(from_os_str), conflicts_with = "threads")]
+        profile: Option<PathBuf>,
+        /// Encodings to read the result as, all of them by default
+        #[structopt(
+            long,
+            use_delimiter = true,
+            possible_values = &DECODERS
+        )]
+        decode: Vec<String>,
+        /// Before evaluating, tell whether the term obviously has a normal
+        /// form or obviously has none, if it can
+        #[structopt(long)]
+        termination: bool,
+        /// Read every file as a single term written with de Bruijn indices,
+        /// like `\\2(12)`, and print its normal form that way as well
+        #[structopt(long)]
+        debruijn: bool,
+    },
+    /// Check the assertions in files, each with the definitions of those
+    /// before it
+    #[structopt(alias = "t")]
+    Test {
+        #[structopt(parse(from_os_str))]
+        files: Vec<PathBuf>,
+        #[structopt(flatten)]
+        eval: EvalOpts,
+    },
+    /// Infer the types of the definitions and main terms in files, each
+    /// with the definitions of those before it
+    #[structopt(alias = "c")]
+    Check {
+        #[structopt(parse(from_os_str))]
+        files: Vec<PathBuf>,
+        /// Type numerals as integers and provide + * - == If ToChurch FromChurch
+        #[structopt(long)]
+        primitives: bool,
+        /// What numerals are typed as, unless a file says otherwise with
+        /// `#pragma numerals <encoding>`
+        #[structopt(long, default_value = "church", possible_values = &eval::Numerals::NAMES)]
+        numerals: eval::Numerals,
+        /// Check the type annotations in this typed lambda calculus instead
+        /// of inferring types
+        #[structopt(long, possible_values = &typed::Calculus::NAMES)]
+        typed: Option<typed::Calculus>,
+    },
+    /// Translate the definitions and main terms in files to combinators,
+    /// each with the definitions of those before it
+    Convert {
+        #[structopt(parse(from_os_str))]
+        files: Vec<PathBuf>,
+        /// The combinators to translate to, or `jot` for bits of Jot, which
+        /// has every definition unfolded
+        #[structopt(
+            long,
+            default_value = "ski",
+            possible_values = &["ski", "skibc", "bckw", "iota", "jot"]
+        )]
+        to: String,
+        /// Also reduce main terms to their normal forms by combinator graph
+        /// reduction
+        #[structopt(long)]
+        reduce: bool,
+    },
+    /// Run a program of binary lambda calculus on the bits of stdin, read as
+    /// a list of booleans, and print the list of booleans it reduces to
+    Blc {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// Print the bits of the main term of a lambda calculus file instead,
+        /// with every definition unfolded
+        #[structopt(long, conflicts_with = "decode")]
+        encode: bool,
+        /// Print the lambda term of a program instead
+        #[structopt(long)]
+        decode: bool,
+        /// Read and write bits packed into bytes rather than as text of 0 and
+        /// 1
+        #[structopt(long)]
+        bytes: bool,
+        /// Give up on the program after this many seconds
+        #[structopt(long)]
+        timeout: Option<f64>,
+    },
+    /// Rewrite files in a canonical layout, or stdin to stdout
+    Fmt {
+        #[structopt(parse(from_os_str))]
+        files: Vec<PathBuf>,
+        /// Only list the files that would change, failing if any would
+        #[structopt(long)]
+        check: bool,
+        /// The width to wrap lines at
+        #[structopt(long, default_value = "80")]
+        width: usize,
     },
     #[structopt(alias = "h")]
     Help,
 }
 
+// How `repl`, `eval` and `test` reduce terms
+#[derive(Debug, StructOpt)]
+struct EvalOpts {
+    /// Normalize definitions once and unfold them to their normal forms
+    #[structopt(long)]
+    memoize: bool,
+    /// Find beta normal forms, leaving out eta reductions
+    #[structopt(long)]
+    no_eta: bool,
+    /// Keep numerals as integers and provide + * - == If ToChurch FromChurch
+    #[structopt(long)]
+    primitives: bool,
+    /// What numerals unfold to, unless a file says otherwise with
+    /// `#pragma numerals <encoding>`
+    #[structopt(long, default_value = "church", possible_values = &eval::Numerals::NAMES)]
+    numerals: eval::Numerals,
+    /// Give up on an evaluation or assertion after this many seconds
+    #[structopt(long)]
+    timeout: Option<f64>,
+    /// Stop a reduction that comes back to a term of one of its last
+    /// this many passes
+    #[structopt(long)]
+    cycles: Option<usize>,
+}
+
+impl EvalOpts {
+    fn to_config(&self) -> eval::Config {
+        eval::Config {
+            memoize: self.memoize,
+            eta: !self.no_eta,
+            timeout: self.timeout.map(Duration::from_secs_f64),
+            cycles: self.cycles,
+            primitives: self.primitives,
+            numerals: self.numerals,
+            ..Default::default()
+        }
+    }
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     use Opt::*;
     let opt = Opt::from_args();
@@ -33,17 +188,41 @@ fn main() -> Result<(), Box<dyn Error>> {
             Opt::clap().print_long_help()?;
             println!();
         }
-        Eval { mut files } => {
+        Eval {
+            mut files,
+            eval: eval_opts,
+            threads,
+            stats: stats_format,
+            profile,
+            decode,
+            termination,
+            debruijn,
+        } => {
+            let config = eval::Config {
+                profile: profile.is_some(),
+                ..eval_opts.to_config()
+            };
+            let decoders = if decode.is_empty() {
+                decode::decoders()
+            } else {
+                decode
+                    .iter()
+                    .filter_map(|name| decode::decoder(name))
+                    .collect()
+            };
+            let mut profile_out = match profile {
+                Some(path) => Some(File::create(path)?),
+                None => None,
+            };
             let filecount = files.len();
             if filecount == 0 {
                 files.push("-".into());
             }
 
             for (i, file) in files.into_iter().enumerate() {
-                let lexed = if file == Path::new("-") {
+                let input: Box<dyn Read> = if file == Path::new("-") {
                     eprintln!("Processing from stdin ({}/{}):", i + 1, filecount);
-                    eprintln!("Lexing...");
-                    lex::lex(io::stdin())
+                    Box::new(io::stdin())
                 } else {
                     eprintln!(
                         "Processing {} ({}/{}):",
@@ -51,14 +230,30 @@ fn main() -> Result<(), Box<dyn Error>> {
                         i + 1,
                         filecount
                     );
+                    Box::new(File::open(file)?)
+                };
+                let program = if debruijn {
+                    eprintln!("Parsing...");
+                    let mut src = String::new();
+                    { input }.read_to_string(&mut src)?;
+                    parse::Program {
+                        main: Some(DeBruijn::parse(&src)?.to_expr()),
+                        ..Default::default()
+                    }
+                } else {
                     eprintln!("Lexing...");
-                    lex::lex(File::open(file)?)
-                }?;
-                //eprintln!("{:?}", lexed);
+                    let lexed = lex::lex(input)?;
+                    //eprintln!("{:?}", lexed);
 
-                eprintln!("Parsing...");
-                let (defs, m_expr) = parse::parse(lexed)?;
-                let expr = m_expr.ok_or("No main body to evaluate")?;
+                    eprintln!("Parsing...");
+                    parse::parse_program(lexed)?
+                };
+                let mut config = config.clone();
+                for pragma in &program.pragmas {
+                    config.pragma(pragma)?;
+                }
+                let mut defs = program.defs;
+                let expr = program.main.ok_or("No main body to evaluate")?;
                 //eprintln!("{:#}\n", expr);
                 //eprintln!("{:?}\n", expr);
                 for (k, v) in defs.iter() {
@@ -66,20 +261,394 @@ fn main() -> Result<(), Box<dyn Error>> {
                 }
                 eprintln!("{}\n", expr);
 
+                if config.memoize {
+                    eprintln!("Normalizing definitions...");
+                    eval::memoize(&mut defs, &config);
+                }
+
+                if termination {
+                    match eval::termination(&expr, &defs, &config) {
+                        eval::Termination::Unknown => {}
+                        t @ eval::Termination::Normalizes(_) => eprintln!("Note: the term {}", t),
+                        t @ eval::Termination::Diverges(_) => eprintln!("Warning: the term {}", t),
+                    }
+                }
+
                 eprintln!("Evaluating...");
-                let (eval_res, stats) = eval::reduce(expr, &defs);
-                let evaluated = eval_res?;
+                let (eval_res, stats) = match threads {
+                    Some(threads) => {
+                        let parallel = eval::Parallel {
+                            threads,
+                            ..Default::default()
+                        };
+                        eval::reduce_par(expr, &defs, &config, &parallel)
+                    }
+                    None => eval::reduce_with(expr, &defs, &config),
+                };
+                let mut report = || -> io::Result<()> {
+                    if let (Some(out), Some(profile)) = (&mut profile_out, stats.profile()) {
+                        out.write_all(profile.folded().as_bytes())?;
+                    }
+                    if stats_format == "json" {
+                        eprintln!("{}", stats.to_json());
+                    } else {
+                        eprintln!("{}\n", stats);
+                    }
+                    Ok(())
+                };
+                let evaluated = match eval_res {
+                    Ok(evaluated) => evaluated,
+                    Err(err) => {
+                        if let Some(term) = err.term() {
+                            eprintln!("Stopped at: {}\n", term);
+                        }
+                        report()?;
+                        return Err(err);
+                    }
+                };
 
+                if debruijn {
+                    println!("{:+}", evaluated);
+                }
                 println!("{}\n", evaluated);
-                if let Some(num) = evaluated.try_unchurch_num() {
-                    eprintln!("Church num!: {}", num)
+                let read_back = eval::readback(&evaluated, &defs, &config);
+                if read_back.tidy() != evaluated.tidy() {
+                    eprintln!("Read back: {}", read_back.tidy());
+                }
+                for decoder in &decoders {
+                    if let Some(value) = decoder.decode(&evaluated) {
+                        eprintln!("As {}: {}", decoder.name(), value);
+                    }
                 }
-                eprintln!("{}\n", stats);
+                report()?;
                 // eprintln!("{:#}\n", evaluated);
                 // eprintln!("{:?}\n", evaluated);
             }
         }
-        Repl { files } => repl::repl(files)?,
+        Repl {
+            files,
+            eval: eval_opts,
+        } => {
+            let config = eval_opts.to_config();
+            repl::repl(files, config)?
+        }
+        Test {
+            files,
+            eval: eval_opts,
+        } => {
+            let config = eval_opts.to_config();
+            let (mut passed, mut failed, mut unknown) = (0, 0, 0);
+            let mut all_defs = Defs::new();
+            for file in files {
+                let name = file.to_string_lossy().into_owned();
+                let mut program = parse::parse_program(lex::lex(File::open(&file)?)?)?;
+                let mut config = config.clone();
+                for pragma in &program.pragmas {
+                    config.pragma(pragma)?;
+                }
+                eval::resolve_numerals(&mut program, &all_defs, &config)?;
+                eval::define(&mut all_defs, program.defs, &config);
+
+                for assert in program.asserts {
+                    let at = format!("{}:{}:{}", name, assert.row, assert.col);
+                    let op = match assert.kind {
+                        parse::AssertKind::Equivalent => "==",
+                        parse::AssertKind::Normalizes => "=>",
+                    };
+                    match eval::check(&assert, &all_defs, &config) {
+                        eval::Verdict::Equal => {
+                            passed += 1;
+                            println!("ok {}", at);
+                        }
+                        eval::Verdict::NotEqual(left, right) => {
+                            failed += 1;
+                            println!(
+                                "FAIL {}: {} {} {}",
+                                at,
+                                assert.left.tidy(),
+                                op,
+                                assert.right.tidy()
+                            );
+                            print_diff(&left, &right);
+                        }
+                        eval::Verdict::Unknown(e) => {
+                            unknown += 1;
+                            println!(
+                                "UNKNOWN {}: {} {} {}",
+                                at,
+                                assert.left.tidy(),
+                                op,
+                                assert.right.tidy()
+                            );
+                            println!("    {}", e);
+                        }
+                    }
+                }
+            }
+            println!(
+                "\n{} passed, {} failed, {} unknown",
+                passed, failed, unknown
+            );
+            if failed + unknown > 0 {
+                process::exit(1);
+            }
+        }
+        Check {
+            files,
+            typed: Some(calculus),
+            ..
+        } => {
+            let mut untyped = 0;
+            let mut known = HashMap::new();
+            for file in files {
+                let program = parse::parse_program(lex::lex(File::open(&file)?)?)?;
+                println!("{}:", file.to_string_lossy());
+                for (name, ty) in typed::check_program(&program, calculus, &mut known) {
+                    match ty {
+                        Ok(ty) => println!("    {} : {}", name, ty),
+                        Err(err) => {
+                            untyped += 1;
+                            println!("    {}", err);
+                        }
+                    }
+                }
+            }
+            if untyped > 0 {
+                process::exit(1);
+            }
+        }
+        Check {
+            files,
+            primitives,
+            numerals,
+            typed: None,
+        } => {
+            let config = eval::Config {
+                primitives,
+                numerals,
+                ..Default::default()
+            };
+            let mut untyped = 0;
+            let mut all_defs = Defs::new();
+            for file in files {
+                let mut program = parse::parse_program(lex::lex(File::open(&file)?)?)?;
+                let mut config = config.clone();
+                for pragma in &program.pragmas {
+                    config.pragma(pragma)?;
+                }
+                // Shown as written, before its numerals are unfolded
+                let written = program.main.as_ref().map(|main| main.tidy());
+                eval::resolve_numerals(&mut program, &all_defs, &config)?;
+                let mut names: Vec<_> = program.defs.keys().cloned().collect();
+                names.sort();
+                all_defs.extend(program.defs);
+
+                println!("{}:", file.to_string_lossy());
+                let types = types::Types::new(&all_defs, &config);
+                let mut show = |name: &str, ty: &Result<types::Type, types::TypeError>| match ty {
+                    Ok(ty) => println!("    {} : {}", name, ty),
+                    Err(err) => {
+                        untyped += 1;
+                        println!("    {} has no type: {}", name, err);
+                    }
+                };
+                for name in names {
+                    show(&name, types.get(&name).unwrap());
+                }
+                if let (Some(written), Some(main)) = (written, program.main) {
+                    show(&written, &types.infer(&main));
+                }
+            }
+            if untyped > 0 {
+                process::exit(1);
+            }
+        }
+        Convert { files, to, reduce } => {
+            let jot = to == "jot";
+            let basis: combinators::Basis = if jot { "ski" } else { &to }.parse()?;
+            let mut failed = 0;
+            let mut all_defs = Defs::new();
+            for file in files {
+                let mut program = parse::parse_program(lex::lex(File::open(&file)?)?)?;
+                let mut config = eval::Config::default();
+                for pragma in &program.pragmas {
+                    config.pragma(pragma)?;
+                }
+                eval::resolve_numerals(&mut program, &all_defs, &config)?;
+                let mut names: Vec<_> = program.defs.keys().cloned().collect();
+                names.sort();
+                all_defs.extend(program.defs);
+
+                // Jot has no names, so everything is unfolded for it
+                let translate = |expr: &Expr| {
+                    if jot {
+                        combinators::convert(&eval::util::unfold(expr, &all_defs, &config), basis)
+                    } else {
+                        combinators::convert(expr, basis)
+                    }
+                };
+                let show = |comb: &combinators::Comb| {
+                    if jot {
+                        combinators::to_jot(comb)
+                    } else {
+                        Ok(comb.to_string())
+                    }
+                };
+                println!("{}:", file.to_string_lossy());
+                for name in names {
+                    match show(&translate(&all_defs[&name].value)) {
+                        Ok(comb) => println!("    {} = {};", name, comb),
+                        Err(err) => {
+                            failed += 1;
+                            println!("    {}: {}", name, err);
+                        }
+                    }
+                }
+                if let Some(main) = program.main {
+                    let comb = translate(&main);
+                    match show(&comb) {
+                        Ok(comb) => println!("    {}", comb),
+                        Err(err) => {
+                            failed += 1;
+                            println!("    {}: {}", main.tidy(), err);
+                        }
+                    }
+                    if reduce {
+                        let mut graph = combinators::Graph::new(&all_defs, basis);
+                        graph.numerals = config.numerals;
+                        let normal = graph.normalize(&comb);
+                        match normal.map_err(Into::into).and_then(|normal| show(&normal)) {
+                            Ok(normal) => println!("    => {}", normal),
+                            Err(err) => {
+                                failed += 1;
+                                println!("    => {}", err);
+                            }
+                        }
+                    }
+                }
+            }
+            if failed > 0 {
+                process::exit(1);
+            }
+        }
+        Blc {
+            file,
+            encode,
+            decode,
+            bytes,
+            timeout,
+        } => {
+            let read_bits = |raw: &[u8]| {
+                if bytes {
+                    Ok(blc::unpack(raw))
+                } else {
+                    blc::bits(&String::from_utf8_lossy(raw))
+                }
+            };
+            let write_bits = |bits: &str| {
+                if bytes {
+                    io::stdout().write_all(&blc::pack(bits))
+                } else {
+                    writeln!(io::stdout(), "{}", bits)
+                }
+            };
+
+            if encode {
+                let program = parse::parse_program(lex::lex(File::open(&file)?)?)?;
+                let mut config = eval::Config::default();
+                for pragma in &program.pragmas {
+                    config.pragma(pragma)?;
+                }
+                let main = program.main.ok_or("No main body to encode")?;
+                let bits = blc::encode(&eval::util::unfold(&main, &program.defs, &config))?;
+                eprintln!("{} bits", bits.len());
+                write_bits(&bits)?;
+                return Ok(());
+            }
+
+            let bits = read_bits(&fs::read(&file)?)?;
+            let (program, mut rest) = blc::decode(&bits)?;
+            if decode {
+                println!("{}", program.tidy());
+                eprintln!("{} bits", bits.len() - rest.len());
+                return Ok(());
+            }
+            if bytes {
+                rest = blc::unpadded(rest);
+            }
+
+            // Bits after the program are input ahead of stdin
+            let mut stdin = Vec::new();
+            io::stdin().read_to_end(&mut stdin)?;
+            let input = rest.to_owned() + &read_bits(&stdin)?;
+            let config = eval::Config {
+                timeout: timeout.map(Duration::from_secs_f64),
+                ..Default::default()
+            };
+            let applied = Box::new(Expr::Appl(program, blc::bit_list(&input)));
+            let (normal, _) = eval::reduce_with(applied, &Defs::new(), &config);
+            let normal = normal?;
+            match blc::read_bit_list(&normal) {
+                Some(output) => write_bits(&output)?,
+                None => {
+                    eprintln!("Output is not a list of booleans: {}", normal.tidy());
+                    process::exit(1);
+                }
+            }
+        }
+        Fmt {
+            files,
+            check,
+            width,
+        } => {
+            if files.is_empty() {
+                let mut src = Vec::new();
+                io::stdin().read_to_end(&mut src)?;
+                let formatted = format::format(&src, width)?;
+                if check && formatted.as_bytes() != src {
+                    eprintln!("stdin is not formatted");
+                    process::exit(1);
+                } else if !check {
+                    print!("{}", formatted);
+                }
+                return Ok(());
+            }
+            let mut unformatted = 0;
+            for file in files {
+                let src = fs::read(&file)?;
+                let formatted = format::format(&src, width)
+                    .map_err(|err| format!("{}: {}", file.to_string_lossy(), err))?;
+                if formatted.as_bytes() == src {
+                    continue;
+                }
+                if check {
+                    println!("{}", file.to_string_lossy());
+                    unformatted += 1;
+                } else {
+                    fs::write(&file, formatted)?;
+                }
+            }
+            if unformatted > 0 {
+                process::exit(1);
+            }
+        }
     };
     Ok(())
 }
+
+/// Prints two normal forms with the same parameter names, marking the first
+/// place they differ.
+fn print_diff(left: &Expr, right: &Expr) {
+    let mut arena = Arena::new();
+    let (left, right) = (arena.insert(left), arena.insert(right));
+    let left = arena.to_expr(left).to_string();
+    let right = arena.to_expr(right).to_string();
+    let same = left
+        .chars()
+        .zip(right.chars())
+        .take_while(|(l, r)| l == r)
+        .count();
+    println!("    left:  {}", left);
+    println!("    right: {}", right);
+    println!("           {}^", " ".repeat(same));
+}
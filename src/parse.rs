@@ -1,4 +1,3 @@
-use std::collections::HashMap;
 use std::error::{self, Error};
 use std::fmt;
 use std::iter::Peekable;
@@ -6,13 +5,80 @@ use std::vec;
 
 use crate::expr::{Def, Defs, Expr};
 use crate::lex::*;
+use crate::types::typed::{Term, Ty};
 
 #[derive(Clone, Debug)]
 enum Atom {
-    E(Box<Expr>),
-    AbstrParam(u8),
+    E(Box<Term>),
+    /// A parameter and its type, if annotated
+    AbstrParam(u8, Option<Ty>),
+    /// `[A]` among the parameters of an abstraction
+    TypeParam(String),
     Definition(String),
     ParenStart,
+    /// `Assert` and its position
+    AssertStart(u32, u32),
+    /// The left side of an assertion, its kind and position
+    AssertLeft(Box<Expr>, AssertKind, u32, u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AssertKind {
+    /// `==`: both sides have the same normal form
+    Equivalent,
+    /// `=>`: the left side normalizes to the right one as written, with
+    /// only its definitions unfolded
+    Normalizes,
+}
+
+/// `assert left == right;` or `assert left => right;`
+#[derive(Clone, Debug)]
+pub struct Assertion {
+    pub left: Box<Expr>,
+    pub right: Box<Expr>,
+    pub kind: AssertKind,
+    pub row: u32,
+    pub col: u32,
+}
+
+/// `#pragma name args..` between statements
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pragma {
+    pub words: Vec<String>,
+    pub row: u32,
+    pub col: u32,
+}
+
+/// A definition, or the main term if `name` is `None`, as written with its
+/// type annotations
+#[derive(Clone, Debug)]
+pub struct Annotated {
+    pub name: Option<String>,
+    pub term: Box<Term>,
+    pub row: u32,
+    pub col: u32,
+}
+
+/// `Name : Type;`, the type a definition is meant to have
+#[derive(Clone, Debug)]
+pub struct Signature {
+    pub name: String,
+    pub ty: Ty,
+    pub row: u32,
+    pub col: u32,
+}
+
+/// Everything in a source
+#[derive(Default)]
+pub struct Program {
+    pub defs: Defs,
+    pub main: Option<Box<Expr>>,
+    pub asserts: Vec<Assertion>,
+    pub pragmas: Vec<Pragma>,
+    /// The definitions and main term before type annotations were erased,
+    /// in the order they are written
+    pub annotated: Vec<Annotated>,
+    pub signatures: Vec<Signature>,
 }
 
 #[derive(Clone)]
@@ -31,31 +97,112 @@ enum State {
 }
 
 type TokPeekable = Peekable<vec::IntoIter<TokenPos>>;
+type Parsed = (Defs, Option<Box<Expr>>);
 
-pub fn parse(tokps: Vec<TokenPos>) -> Result<(Defs, Option<Box<Expr>>), Box<dyn Error>> {
+/// Parses definitions and the main expression, skipping assertions.
+pub fn parse(tokps: Vec<TokenPos>) -> Result<Parsed, Box<dyn Error>> {
+    let program = parse_program(tokps)?;
+    Ok((program.defs, program.main))
+}
+
+pub fn parse_program(tokps: Vec<TokenPos>) -> Result<Program, Box<dyn Error>> {
     parse_pkbl(&mut tokps.into_iter().peekable())
 }
 
-fn append(stack: &mut Vec<Atom>, expr: Box<Expr>) {
+fn append(stack: &mut Vec<Atom>, term: Box<Term>) {
     use Atom::*;
-    use Expr::*;
     if matches!(stack.last(), Some(E(_))) {
         if let Some(E(before)) = stack.pop() {
-            stack.push(E(Box::new(Appl(before, expr))))
+            stack.push(E(Box::new(Term::Appl(before, term))))
         }
     } else {
-        stack.push(E(expr));
+        stack.push(E(term));
+    }
+}
+
+/// Parses `All A B. T`, `T -> T`, a name or `(T)`, starting after the
+/// token at `row` and `col`.
+fn parse_type(pkbl: &mut TokPeekable, row: u32, col: u32) -> Result<Ty, Box<dyn Error>> {
+    use crate::lex::Token::*;
+    let TokenPos { tok, row, col } = pkbl
+        .next()
+        .ok_or_else(|| ParseError::boxed("Input ended where a type was expected", row, col))?;
+    let left = match tok {
+        Capitalized(s) if s == "All" => {
+            let mut vars = Vec::new();
+            loop {
+                match pkbl.next() {
+                    Some(TokenPos {
+                        tok: Capitalized(v),
+                        ..
+                    }) => vars.push(v),
+                    Some(TokenPos { tok: Dot, row, col }) if !vars.is_empty() => {
+                        let body = parse_type(pkbl, row, col)?;
+                        return Ok(vars
+                            .into_iter()
+                            .rev()
+                            .fold(body, |body, v| Ty::Forall(v, Box::new(body))));
+                    }
+                    _ => {
+                        return Err(ParseError::boxed(
+                            "All should be followed by type variables and a dot",
+                            row,
+                            col,
+                        ))
+                    }
+                }
+            }
+        }
+        Capitalized(s) => Ty::Con(s),
+        OpParen => {
+            let ty = parse_type(pkbl, row, col)?;
+            match pkbl.next() {
+                Some(TokenPos { tok: ClParen, .. }) => ty,
+                _ => return Err(ParseError::boxed("A type with an unclosed parenthesis", row, col)),
+            }
+        }
+        t => {
+            return Err(ParseError::boxed(
+                format!("Unexpected token in a type: {}", t),
+                row,
+                col,
+            ))
+        }
+    };
+    match pkbl.peek() {
+        Some(&TokenPos {
+            tok: Arrow,
+            row,
+            col,
+        }) => {
+            pkbl.next();
+            let right = parse_type(pkbl, row, col)?;
+            Ok(Ty::Arrow(Box::new(left), Box::new(right)))
+        }
+        _ => Ok(left),
+    }
+}
+
+/// Parses the rest of `[T]` or `[A]` after the opening bracket
+fn parse_bracket(pkbl: &mut TokPeekable, row: u32, col: u32) -> Result<Ty, Box<dyn Error>> {
+    use crate::lex::Token::*;
+    let ty = parse_type(pkbl, row, col)?;
+    match pkbl.next() {
+        Some(TokenPos { tok: ClBracket, .. }) => Ok(ty),
+        _ => Err(ParseError::boxed("A type with an unclosed bracket", row, col)),
     }
 }
 
-fn parse_pkbl(pkbl: &mut TokPeekable) -> Result<(Defs, Option<Box<Expr>>), Box<dyn Error>> {
+fn parse_pkbl(pkbl: &mut TokPeekable) -> Result<Program, Box<dyn Error>> {
     use Atom::*;
-    use Expr::*;
     use State::*;
 
     let mut state = Start;
     let mut stack: Vec<Atom> = Vec::new();
-    let mut defs: Defs = HashMap::new();
+    let mut program = Program::default();
+    // Where the statement being parsed starts
+    let mut srow: u32 = 0;
+    let mut scol: u32 = 0;
 
     let mut gcol: u32 = 0;
     let mut grow: u32 = 0;
@@ -69,22 +216,75 @@ fn parse_pkbl(pkbl: &mut TokPeekable) -> Result<(Defs, Option<Box<Expr>>), Box<d
         let TokenPos { tok, row, col } = tokp;
         gcol = col;
         grow = row;
+        if state == Start && stack.is_empty() {
+            srow = row;
+            scol = col;
+        }
         match (&state, tok) {
             (InExpr | Start, Char(v)) => {
-                append(&mut stack, Box::new(Variable(v)));
+                append(&mut stack, Box::new(Term::Var(v)));
+                state = InExpr;
+            }
+            (InExpr | Start, Operator(o)) => {
+                append(&mut stack, Box::new(Term::Name((o as char).to_string())));
+                state = InExpr;
+            }
+            // With nothing on its left `==` is the primitive, not an assertion
+            (InExpr | Start, DoubleEquals) if !matches!(stack.last(), Some(E(_))) => {
+                append(&mut stack, Box::new(Term::Name("==".into())));
                 state = InExpr;
             }
             (Start, Capitalized(s)) => {
-                if let Some(TokenPos { tok: Equals, .. }) = pkbl.peek() {
-                    pkbl.next();
-                    stack.push(Definition(s));
-                } else {
-                    append(&mut stack, Box::new(Name(s)));
+                match pkbl.peek() {
+                    Some(TokenPos { tok: Equals, .. }) => {
+                        pkbl.next();
+                        stack.push(Definition(s));
+                    }
+                    Some(&TokenPos { tok: Colon, row, col }) if stack.is_empty() => {
+                        pkbl.next();
+                        let ty = parse_type(pkbl, row, col)?;
+                        match pkbl.next() {
+                            Some(TokenPos { tok: Semicolon, .. }) | None => {}
+                            Some(TokenPos { tok, row, col }) => {
+                                return Err(ParseError::boxed(
+                                    format!("Unexpected token after the signature of {}: {}", s, tok),
+                                    row,
+                                    col,
+                                ))
+                            }
+                        }
+                        program.signatures.push(Signature {
+                            name: s,
+                            ty,
+                            row: srow,
+                            col: scol,
+                        });
+                        continue;
+                    }
+                    _ => append(&mut stack, Box::new(Term::Name(s))),
                 }
                 state = InExpr;
             }
             (InExpr, Capitalized(s)) => {
-                append(&mut stack, Box::new(Name(s)));
+                append(&mut stack, Box::new(Term::Name(s)));
+                state = InExpr;
+            }
+            (InExpr, OpBracket) => {
+                let ty = parse_bracket(pkbl, row, col)?;
+                match stack.pop() {
+                    Some(E(term)) => stack.push(E(Box::new(Term::TyAppl(term, ty)))),
+                    _ => {
+                        return Err(ParseError::boxed(
+                            "A type argument with nothing to apply it to",
+                            row,
+                            col,
+                        ))
+                    }
+                }
+            }
+            (Start, Pragma(words)) => program.pragmas.push(self::Pragma { words, row, col }),
+            (Start, Assert) => {
+                stack.push(AssertStart(row, col));
                 state = InExpr;
             }
             (InExpr | Start, OpParen) => {
@@ -101,14 +301,14 @@ fn parse_pkbl(pkbl: &mut TokPeekable) -> Result<(Defs, Option<Box<Expr>>), Box<d
                             col,
                         ))
                     }
-                    Some(AbstrParam(_)) => {
+                    Some(AbstrParam(..) | TypeParam(_)) => {
                         return Err(ParseError::boxed(
                             "Attempt to close an abstraction with an empty body",
                             row,
                             col,
                         ))
                     }
-                    None | Some(Definition(_)) => {
+                    None | Some(Definition(_) | AssertStart(..) | AssertLeft(..)) => {
                         return Err(ParseError::boxed(
                             "Expression starts with a closing parenthesis",
                             row,
@@ -121,9 +321,10 @@ fn parse_pkbl(pkbl: &mut TokPeekable) -> Result<(Defs, Option<Box<Expr>>), Box<d
                     // ..or nothing is left
                     top = match stack.pop() {
                         Some(ParenStart) => break,
-                        Some(AbstrParam(p)) => Box::new(Abstr(p, top)),
-                        Some(E(expr)) => Box::new(Appl(expr, top)),
-                        Some(Definition(_)) | None => {
+                        Some(AbstrParam(p, ty)) => Box::new(Term::Abstr(p, ty, top)),
+                        Some(TypeParam(a)) => Box::new(Term::TyAbstr(a, top)),
+                        Some(E(expr)) => Box::new(Term::Appl(expr, top)),
+                        Some(Definition(_) | AssertStart(..) | AssertLeft(..)) | None => {
                             return Err(ParseError::boxed(
                                 "Closing parenthesis has no opening parenthesis",
                                 row,
@@ -138,16 +339,71 @@ fn parse_pkbl(pkbl: &mut TokPeekable) -> Result<(Defs, Option<Box<Expr>>), Box<d
             (InExpr | Start, Backslash) => {
                 state = AbstrInit;
             }
-            (AbstrInit, Char(v)) => {
-                stack.push(AbstrParam(v));
+            (AbstrInit | AbstrParams, Char(v)) => {
+                stack.push(AbstrParam(v, None));
                 state = AbstrParams;
             }
-            (AbstrParams, Char(v)) => {
-                stack.push(AbstrParam(v));
+            (AbstrInit | AbstrParams, OpBracket) => match parse_bracket(pkbl, row, col)? {
+                Ty::Con(a) => {
+                    stack.push(TypeParam(a));
+                    state = AbstrParams;
+                }
+                _ => return Err(ParseError::boxed("A type parameter should be a name", row, col)),
+            },
+            (AbstrParams, Colon) => {
+                let ty = parse_type(pkbl, row, col)?;
+                match stack.last_mut() {
+                    Some(AbstrParam(_, annotation @ None)) => *annotation = Some(ty),
+                    _ => {
+                        return Err(ParseError::boxed(
+                            "A type annotation should follow a parameter",
+                            row,
+                            col,
+                        ))
+                    }
+                }
             }
             (AbstrParams, Dot) => {
                 state = InExpr;
             }
+            (InExpr, tok @ (DoubleEquals | FatArrow)) => {
+                let kind = if tok == DoubleEquals {
+                    AssertKind::Equivalent
+                } else {
+                    AssertKind::Normalizes
+                };
+                let mut top = match stack.pop() {
+                    Some(E(expr)) => expr,
+                    _ => {
+                        return Err(ParseError::boxed(
+                            format!("Missing the left side of {}", tok),
+                            row,
+                            col,
+                        ))
+                    }
+                };
+                loop {
+                    top = match stack.pop() {
+                        Some(AbstrParam(p, ty)) => Box::new(Term::Abstr(p, ty, top)),
+                        Some(TypeParam(a)) => Box::new(Term::TyAbstr(a, top)),
+                        Some(E(expr)) => Box::new(Term::Appl(expr, top)),
+                        Some(AssertStart(arow, acol)) => {
+                            stack.push(AssertLeft(top.erase(), kind, arow, acol));
+                            break;
+                        }
+                        Some(ParenStart) => {
+                            return Err(ParseError::boxed("An unclosed parenthesis", row, col))
+                        }
+                        Some(Definition(_) | AssertLeft(..)) | None => {
+                            return Err(ParseError::boxed(
+                                format!("{} outside of an assertion", tok),
+                                row,
+                                col,
+                            ))
+                        }
+                    }
+                }
+            }
             (InExpr, Semicolon) => {
                 let mut top = match stack.pop() {
                     Some(ParenStart) => {
@@ -157,7 +413,7 @@ fn parse_pkbl(pkbl: &mut TokPeekable) -> Result<(Defs, Option<Box<Expr>>), Box<d
                             gcol,
                         ))
                     }
-                    Some(AbstrParam(_)) => {
+                    Some(AbstrParam(..) | TypeParam(_)) => {
                         // unreachable?
                         return Err(ParseError::boxed(
                             "Statement ended with an open abstraction",
@@ -165,7 +421,7 @@ fn parse_pkbl(pkbl: &mut TokPeekable) -> Result<(Defs, Option<Box<Expr>>), Box<d
                             gcol,
                         ));
                     }
-                    None | Some(Definition(_)) => {
+                    None | Some(Definition(_) | AssertStart(..) | AssertLeft(..)) => {
                         return Err(ParseError::boxed("Empty Expression", grow, gcol))
                     }
                     Some(E(expr)) => expr,
@@ -177,13 +433,27 @@ fn parse_pkbl(pkbl: &mut TokPeekable) -> Result<(Defs, Option<Box<Expr>>), Box<d
                         Some(ParenStart) => {
                             return Err(ParseError::boxed("An unclosed parenthesis", grow, gcol))
                         }
-                        Some(AbstrParam(p)) => Box::new(Abstr(p, top)),
-                        Some(E(expr)) => Box::new(Appl(expr, top)),
+                        Some(AbstrParam(p, ty)) => Box::new(Term::Abstr(p, ty, top)),
+                        Some(TypeParam(a)) => Box::new(Term::TyAbstr(a, top)),
+                        Some(E(expr)) => Box::new(Term::Appl(expr, top)),
                         Some(Definition(s)) => {
                             assert!(stack.is_empty(), "Def should be the first element");
-                            defs.insert(s, Def { value: top });
+                            program.define(s, top, srow, scol);
+                            break;
+                        }
+                        Some(AssertLeft(left, kind, row, col)) => {
+                            program.asserts.push(Assertion {
+                                left,
+                                right: top.erase(),
+                                kind,
+                                row,
+                                col,
+                            });
                             break;
                         }
+                        Some(AssertStart(..)) => {
+                            return Err(ParseError::boxed("Assertion without ==", grow, gcol))
+                        }
                         None => break,
                     }
                 }
@@ -208,7 +478,7 @@ fn parse_pkbl(pkbl: &mut TokPeekable) -> Result<(Defs, Option<Box<Expr>>), Box<d
                     gcol,
                 ))
             }
-            Some(AbstrParam(_)) => {
+            Some(AbstrParam(..) | TypeParam(_)) => {
                 // unreachable?
                 return Err(ParseError::boxed(
                     "Input ended with an open abstraction",
@@ -217,9 +487,10 @@ fn parse_pkbl(pkbl: &mut TokPeekable) -> Result<(Defs, Option<Box<Expr>>), Box<d
                 ));
             }
             Some(Definition(_)) => return Err(ParseError::boxed("Empty definition", grow, gcol)),
-            None => {
-                return Ok((defs, None));
+            Some(AssertStart(..) | AssertLeft(..)) => {
+                return Err(ParseError::boxed("Empty assertion", grow, gcol))
             }
+            None => return Ok(program),
             Some(E(expr)) => expr,
         };
         loop {
@@ -229,17 +500,41 @@ fn parse_pkbl(pkbl: &mut TokPeekable) -> Result<(Defs, Option<Box<Expr>>), Box<d
                 Some(ParenStart) => {
                     return Err(ParseError::boxed("An unclosed parenthesis", grow, gcol))
                 }
-                Some(AbstrParam(p)) => Box::new(Abstr(p, top)),
-                Some(E(expr)) => Box::new(Appl(expr, top)),
+                Some(AbstrParam(p, ty)) => Box::new(Term::Abstr(p, ty, top)),
+                Some(TypeParam(a)) => Box::new(Term::TyAbstr(a, top)),
+                Some(E(expr)) => Box::new(Term::Appl(expr, top)),
                 Some(Definition(s)) => {
                     assert!(stack.is_empty(), "Def should be the first element");
-                    defs.insert(s, Def { value: top });
-                    return Ok((defs, None));
+                    program.define(s, top, srow, scol);
+                    return Ok(program);
+                }
+                Some(AssertLeft(left, kind, row, col)) => {
+                    program.asserts.push(Assertion {
+                        left,
+                        right: top.erase(),
+                        kind,
+                        row,
+                        col,
+                    });
+                    return Ok(program);
+                }
+                Some(AssertStart(..)) => {
+                    return Err(ParseError::boxed("Assertion without ==", grow, gcol))
                 }
                 None => break,
             }
         }
-        Ok((defs, Some(top)))
+        program.main = Some(top.erase());
+        program.annotated.push(Annotated {
+            name: None,
+            term: top,
+            row: srow,
+            col: scol,
+        });
+        Ok(program)
+    } else if state == Start {
+        // Every statement was ended with `;`
+        Ok(program)
     } else {
         Err(ParseError::boxed(
             "Input ended with an unfinished abstraction",
@@ -249,8 +544,20 @@ fn parse_pkbl(pkbl: &mut TokPeekable) -> Result<(Defs, Option<Box<Expr>>), Box<d
     }
 }
 
+impl Program {
+    fn define(&mut self, name: String, term: Box<Term>, row: u32, col: u32) {
+        self.defs.insert(name.clone(), Def::new(term.erase()));
+        self.annotated.push(Annotated {
+            name: Some(name),
+            term,
+            row,
+            col,
+        });
+    }
+}
+
 impl ParseError {
-    fn boxed(msg: impl Into<String>, row: u32, col: u32) -> Box<Self> {
+    pub(crate) fn boxed(msg: impl Into<String>, row: u32, col: u32) -> Box<Self> {
         Box::new(ParseError {
             msg: msg.into(),
             row,
@@ -310,15 +617,15 @@ mod tests {
 
         let p = process("Name");
         assert!(matches!(
-           p, box Name(n) if n == "Name"
+           p, box Name(ref n) if n == "Name"
         ));
         let p = process("1234");
         assert!(matches!(
-           p, box Name(n) if n == "1234"
+           p, box Name(ref n) if n == "1234"
         ));
         let p = process("_23asdf_dfs");
         assert!(matches!(
-           p, box Name(n) if n == "_23asdf_dfs"
+           p, box Name(ref n) if n == "_23asdf_dfs"
         ));
 
         let ok = [
@@ -367,4 +674,78 @@ mod tests {
             .map(|a| a.and_then(parse))
             .all(|a| a.is_err()));
     }
+
+    #[test]
+    fn parse_assert() {
+        let src = "A = \\x.x;\nAssert A b == (\\y.y) b;\n  assert A => A\n";
+        let program = parse_program(lex(src.as_bytes()).unwrap()).unwrap();
+        assert!(program.defs.contains_key("A"));
+        assert!(program.main.is_none());
+        assert_eq!(program.asserts.len(), 2);
+        let first = &program.asserts[0];
+        assert!(first.left.alpha_eq(&process("A b")));
+        assert!(first.right.alpha_eq(&process("(\\y.y) b")));
+        assert_eq!((first.row, first.col), (2, 1));
+        assert_eq!(first.kind, AssertKind::Equivalent);
+        assert_eq!((program.asserts[1].row, program.asserts[1].col), (3, 3));
+        assert_eq!(program.asserts[1].kind, AssertKind::Normalizes);
+        // Assertions are left out of the old interface
+        assert!(parse(lex(src.as_bytes()).unwrap()).is_ok());
+        // A file of statements alone
+        let program = parse_program(lex(b"A = B;\nassert A == B;\n" as &[u8]).unwrap()).unwrap();
+        assert!(program.main.is_none());
+        assert_eq!(program.asserts.len(), 1);
+
+        // `==` with nothing on its left is a primitive
+        let src = "assert == 1 (+ 2 3) == (==) 1";
+        let program = parse_program(lex(src.as_bytes()).unwrap()).unwrap();
+        let first = &program.asserts[0];
+        assert_eq!(first.left.tidy(), "== 1 (+ 2 3)");
+        assert_eq!(first.right.tidy(), "== 1");
+
+        let err = ["Assert a;", "Assert == a;", "Assert a ==;", "a == b;", "Assert a == b == c;"];
+        for src in err {
+            assert!(parse_program(lex(src.as_bytes()).unwrap()).is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn parse_annotations() {
+        let src = "Id : All A. A -> A;\nId = \\[A] x : A. x;\nK = \\x:(Nat -> Nat) y. x;\nId [Nat -> Nat] (K Id)";
+        let program = parse_program(lex(src.as_bytes()).unwrap()).unwrap();
+        // Evaluation sees the untyped terms
+        assert!(program.defs["Id"].value.alpha_eq(&process("\\x.x")));
+        assert!(program.defs["K"].value.alpha_eq(&process("\\xy.x")));
+        assert!(program.main.unwrap().alpha_eq(&process("Id (K Id)")));
+
+        assert_eq!(program.signatures.len(), 1);
+        assert_eq!(program.signatures[0].ty.to_string(), "All A. A -> A");
+        let annotated: Vec<_> = program
+            .annotated
+            .iter()
+            .map(|a| (a.name.clone(), a.term.to_string(), a.row, a.col))
+            .collect();
+        assert_eq!(
+            annotated,
+            [
+                (Some("Id".into()), "\\[A]. \\x : A. x".into(), 2, 1),
+                (Some("K".into()), "\\x : Nat -> Nat. \\y. x".into(), 3, 1),
+                (None, "Id [Nat -> Nat] (K Id)".into(), 4, 1),
+            ]
+        );
+
+        let err = [
+            "\\x : . x",
+            "\\x : Nat",
+            "\\x : All. x",
+            "\\[A -> A]. x",
+            "\\[A. x",
+            "[Nat] x",
+            "\\x : Nat : Nat. x",
+            "Id : Nat Nat;",
+        ];
+        for src in err {
+            assert!(parse_program(lex(src.as_bytes()).unwrap()).is_err(), "{}", src);
+        }
+    }
 }
@@ -1,21 +1,46 @@
-use crate::expr::Defs;
-use crate::{eval, expr, lex, parse};
+use crate::debruijn::DeBruijn;
+use crate::expr::{Defs, Expr};
+use crate::{eval, expr, lex, parse, types};
 
 use std::error::Error;
 use std::fs;
 use std::io::{self, Write};
 use std::path::PathBuf;
-use std::{error, process};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+pub fn repl(files: Vec<PathBuf>, mut config: eval::Config) -> Result<(), Box<dyn Error>> {
+    // Ctrl-C cancels the line being evaluated, or quits at the prompt
+    let cancel = Arc::new(AtomicBool::new(false));
+    let busy = Arc::new(AtomicBool::new(false));
+    {
+        let (cancel, busy) = (cancel.clone(), busy.clone());
+        ctrlc::set_handler(move || {
+            if busy.load(Ordering::SeqCst) {
+                cancel.store(true, Ordering::SeqCst);
+            } else {
+                process::exit(130);
+            }
+        })?;
+    }
+    config.cancel = Some(cancel.clone());
 
-pub fn repl(files: Vec<PathBuf>) -> Result<(), Box<dyn Error>> {
     let mut buf: String = "".into();
+    // The last evaluation that stopped at a limit, for `:continue`
+    let mut stopped: Option<Box<eval::EvalError>> = None;
     let mut all_defs = expr::Defs::new();
+    // Names to read results back with, learned again after definitions change
+    let mut readback: Option<eval::Readback> = None;
     for f in files {
         let lexed = lex::lex(fs::File::open(f)?)?;
-        let (defs, _) = parse::parse(lexed)?;
-        for (k, v) in defs {
-            all_defs.insert(k, v);
+        let mut program = parse::parse_program(lexed)?;
+        let mut own = config.clone();
+        for pragma in &program.pragmas {
+            own.pragma(pragma)?;
         }
+        eval::resolve_numerals(&mut program, &all_defs, &own)?;
+        eval::define(&mut all_defs, program.defs, &own);
     }
     println!();
     loop {
@@ -24,27 +49,62 @@ pub fn repl(files: Vec<PathBuf>) -> Result<(), Box<dyn Error>> {
             io::stdout().flush().expect("flush failed");
 
             buf.clear();
-            io::stdin().read_line(&mut buf)?;
-
-            match command(&buf, &all_defs) {
-                Some(Ok(())) => return Ok(()),
-                Some(err) => err?,
-                None => {}
+            busy.store(false, Ordering::SeqCst);
+            if io::stdin().read_line(&mut buf)? == 0 {
+                // End of input
+                println!();
+                process::exit(0);
             }
+            cancel.store(false, Ordering::SeqCst);
+            busy.store(true, Ordering::SeqCst);
 
-            let lexed = lex::lex(buf.as_bytes())?;
-            let (defs, maybe_expr) = parse::parse(lexed)?;
-            for (k, v) in defs {
-                all_defs.insert(k, v);
-            }
-            match maybe_expr {
-                Some(e) => {
-                    let (evaled, _) = eval::reduce(e, &all_defs);
-                    let evaled = evaled?;
-                    println!("{}", evaled);
+            let trimmed = buf.trim();
+            let (evaled, _) = if trimmed == ":continue" || trimmed == ":cont" {
+                let err = stopped.take().ok_or("No stopped evaluation to continue")?;
+                eval::resume(err, &all_defs, &config)
+            } else {
+                match command(&buf, &all_defs, &config) {
+                    Some(Ok(())) => return Ok(()),
+                    Some(err) => err?,
+                    None => {}
+                }
+
+                let lexed = lex::lex(buf.as_bytes())?;
+                let mut program = parse::parse_program(lexed)?;
+                // Pragmas only apply to the line they are on
+                let mut own = config.clone();
+                for pragma in &program.pragmas {
+                    own.pragma(pragma)?;
+                }
+                eval::resolve_numerals(&mut program, &all_defs, &own)?;
+                if !program.defs.is_empty() {
+                    readback = None;
+                }
+                eval::define(&mut all_defs, program.defs, &config);
+                for assert in program.asserts {
+                    println!("Assert: {}", eval::check(&assert, &all_defs, &config));
+                }
+                match program.main {
+                    Some(e) => eval::reduce_with(e, &all_defs, &config),
+                    None => return Ok(()),
                 }
-                None => {}
             };
+            match evaled {
+                Ok(evaled) => {
+                    println!("{}", evaled);
+                    let read_back = readback
+                        .get_or_insert_with(|| eval::Readback::new(&all_defs, &config))
+                        .apply(&evaled);
+                    if read_back.tidy() != evaled.tidy() {
+                        println!("= {}", read_back.tidy());
+                    }
+                }
+                Err(err) if err.term().is_some() => {
+                    println!("{} (:continue to go on)", err);
+                    stopped = Some(err);
+                }
+                Err(err) => return Err(err),
+            }
             Ok(())
         };
         match cycle() {
@@ -56,12 +116,17 @@ pub fn repl(files: Vec<PathBuf>) -> Result<(), Box<dyn Error>> {
     }
 }
 
-fn command(line: impl AsRef<str>, defs: &Defs) -> Option<Result<(), Box<dyn Error>>> {
+fn command(
+    line: impl AsRef<str>,
+    defs: &Defs,
+    config: &eval::Config,
+) -> Option<Result<(), Box<dyn Error>>> {
     let trimmed = line.as_ref().trim();
-    if trimmed.starts_with(":") {
-        let mut rest = &trimmed[1..];
-        if let Some((c, _)) = rest.split_once(' ') {
-            rest = c
+    if let Some(mut rest) = trimmed.strip_prefix(':') {
+        let mut args = "";
+        if let Some((c, a)) = rest.split_once(' ') {
+            rest = c;
+            args = a;
         }
 
         match rest {
@@ -73,7 +138,7 @@ fn command(line: impl AsRef<str>, defs: &Defs) -> Option<Result<(), Box<dyn Erro
             }
             "names" => {
                 let mut a = "";
-                for (k, _) in defs {
+                for k in defs.keys() {
                     print!("{}", a);
                     print!("{}", k);
                     a = ", ";
@@ -81,6 +146,9 @@ fn command(line: impl AsRef<str>, defs: &Defs) -> Option<Result<(), Box<dyn Erro
                 println!();
             }
             "clear" | "cl" => print!("\x1B[2J\x1B[H"),
+            "eq" => return Some(equate(args, defs, config)),
+            "type" | "t" => return Some(type_of(args, defs, config)),
+            "debruijn" | "db" => return Some(indexed(args, defs, config)),
             _ => println!("Unknown command: {}", rest),
         }
         Some(Ok(()))
@@ -88,3 +156,70 @@ fn command(line: impl AsRef<str>, defs: &Defs) -> Option<Result<(), Box<dyn Erro
         None
     }
 }
+
+/// `:eq A == B`
+fn equate(args: &str, defs: &Defs, config: &eval::Config) -> Result<(), Box<dyn Error>> {
+    let (a, b) = equation(args)?;
+    println!("{}", eval::equivalent(&a, &b, defs, config));
+    Ok(())
+}
+
+/// The sides of `A == B`, read like those of `assert A == B`
+fn equation(args: &str) -> Result<(Box<Expr>, Box<Expr>), Box<dyn Error>> {
+    let mut tokens = lex::lex(args.as_bytes())?;
+    let assert = lex::TokenPos {
+        tok: lex::Token::Assert,
+        row: 1,
+        col: 0,
+    };
+    tokens.insert(0, assert);
+    let program = parse::parse_program(tokens)?;
+    match program.asserts.into_iter().next() {
+        Some(eq) if eq.kind == parse::AssertKind::Equivalent && program.defs.is_empty() => {
+            Ok((eq.left, eq.right))
+        }
+        _ => Err("Usage: :eq A == B".into()),
+    }
+}
+
+/// `:type A`
+fn type_of(args: &str, defs: &Defs, config: &eval::Config) -> Result<(), Box<dyn Error>> {
+    let expr = parse::parse(lex::lex(args.as_bytes())?)?
+        .1
+        .ok_or("Usage: :type A")?;
+    match types::Types::new(defs, config).infer(&expr) {
+        Ok(ty) => println!("{} : {}", expr.tidy(), ty),
+        Err(err) => println!("{} has no type: {}", expr.tidy(), err),
+    }
+    Ok(())
+}
+
+/// `:debruijn A`, where `A` is written with de Bruijn indices
+fn indexed(args: &str, defs: &Defs, config: &eval::Config) -> Result<(), Box<dyn Error>> {
+    let expr = DeBruijn::parse(args)?.to_expr();
+    println!("{}", expr.tidy());
+    let (evaled, _) = eval::reduce_with(expr, defs, config);
+    let evaled = evaled?;
+    println!("{:+}", evaled);
+    println!("= {}", evaled.tidy());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::*;
+
+    #[test]
+    fn equations() {
+        let (a, b) = equation(r"\x.x == \y.y").unwrap();
+        assert!(a.alpha_eq(&process(r"\x.x")));
+        assert!(b.alpha_eq(&process(r"\y.y")));
+        let (a, b) = equation("F (== 1 2) == G x").unwrap();
+        assert!(a.alpha_eq(&process("F (== 1 2)")));
+        assert!(b.alpha_eq(&process("G x")));
+        assert!(equation(r"\x.x \y.y").is_err());
+        assert!(equation("A => B").is_err());
+        assert!(equation("A == B; C = D").is_err());
+    }
+}
@@ -0,0 +1,544 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+
+use crate::eval::{Config, Numerals};
+use crate::expr::{Defs, Expr};
+
+pub mod typed;
+
+/// A simple type.
+///
+/// The types given out by `Types` are principal type schemes: all their
+/// variables are quantified, and numbered from 0 in order of appearance.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    Var(u32),
+    /// The integers of `Config::primitives`
+    Int,
+    Arrow(Box<Type>, Box<Type>),
+}
+
+impl Type {
+    fn arrow(a: Type, b: Type) -> Type {
+        Type::Arrow(Box::new(a), Box::new(b))
+    }
+
+    /// `a -> b -> .. -> z` for `[a, b, .., z]`
+    fn arrows(types: Vec<Type>) -> Type {
+        let mut types = types.into_iter().rev();
+        let last = types.next().unwrap();
+        types.fold(last, |res, ty| Type::arrow(ty, res))
+    }
+
+    /// How many variables there are, given that they are numbered from 0
+    fn vars(&self) -> u32 {
+        match self {
+            Type::Var(v) => v + 1,
+            Type::Int => 0,
+            Type::Arrow(a, b) => a.vars().max(b.vars()),
+        }
+    }
+
+    /// Adds `base` to every variable
+    fn shift(&self, base: u32) -> Type {
+        match self {
+            Type::Var(v) => Type::Var(v + base),
+            Type::Int => Type::Int,
+            Type::Arrow(a, b) => Type::arrow(a.shift(base), b.shift(base)),
+        }
+    }
+
+    /// Numbers the variables of `types` from 0 in order of first appearance
+    fn normalize(types: &[&Type]) -> Vec<Type> {
+        fn go(ty: &Type, names: &mut HashMap<u32, u32>) -> Type {
+            match ty {
+                Type::Var(v) => {
+                    let next = names.len() as u32;
+                    Type::Var(*names.entry(*v).or_insert(next))
+                }
+                Type::Int => Type::Int,
+                Type::Arrow(a, b) => {
+                    let a = go(a, names);
+                    Type::arrow(a, go(b, names))
+                }
+            }
+        }
+        let mut names = HashMap::new();
+        types.iter().map(|ty| go(ty, &mut names)).collect()
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Var(v) if *v < 26 => write!(f, "{}", (b'a' + *v as u8) as char),
+            Type::Var(v) => write!(f, "t{}", v),
+            Type::Int => f.write_str("Int"),
+            Type::Arrow(a, b) => match **a {
+                Type::Arrow(..) => write!(f, "({}) -> {}", a, b),
+                _ => write!(f, "{} -> {}", a, b),
+            },
+        }
+    }
+}
+
+/// Why a term has no type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypeError {
+    /// Typing `term` needs a type that contains itself, like that of `x` in
+    /// `x x`
+    Infinite {
+        term: String,
+        var: Type,
+        ty: Type,
+    },
+    /// `term` uses an integer as a function, or a function as an integer
+    Mismatch {
+        term: String,
+        left: Type,
+        right: Type,
+    },
+    FreeVariable(u8),
+    UnknownName(String),
+    /// The term uses a definition that has no type itself
+    Untyped(String),
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TypeError::Infinite { term, var, ty } => {
+                write!(f, "{} needs the infinite type {} = {}", term, var, ty)
+            }
+            TypeError::Mismatch { term, left, right } => {
+                write!(f, "{} needs {} and {} to be the same", term, left, right)
+            }
+            TypeError::FreeVariable(v) => write!(f, "Free variable: {}", *v as char),
+            TypeError::UnknownName(name) => write!(f, "Unknown name: {}", name),
+            TypeError::Untyped(name) => write!(f, "Uses {}, which has no type", name),
+        }
+    }
+}
+
+impl Error for TypeError {}
+
+/// The principal types of definitions, found by Hindley–Milner inference.
+///
+/// Definitions are typed after those they use, and generalized before they
+/// are used in turn, so `I` can be applied to both `I` and `0` in one
+/// term. Mutually recursive definitions are typed together, each at a
+/// single type within the group.
+pub struct Types {
+    known: HashMap<String, Result<Type, TypeError>>,
+    primitives: bool,
+    numerals: Numerals,
+}
+
+impl Types {
+    /// Infers the types of `defs`, with numerals and primitives as `config`
+    /// has them.
+    pub fn new(defs: &Defs, config: &Config) -> Self {
+        let mut types = Types {
+            known: HashMap::new(),
+            primitives: config.primitives,
+            numerals: config.numerals,
+        };
+        for group in dependency_order(defs) {
+            types.infer_group(&group, defs);
+        }
+        types
+    }
+
+    /// The type of a definition, `None` for names that aren't defined
+    pub fn get(&self, name: &str) -> Option<&Result<Type, TypeError>> {
+        self.known.get(name)
+    }
+
+    /// The definitions with their types, in alphabetical order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Result<Type, TypeError>)> {
+        let mut sorted: Vec<_> = self.known.iter().map(|(k, v)| (&k[..], v)).collect();
+        sorted.sort_by_key(|&(name, _)| name);
+        sorted.into_iter()
+    }
+
+    /// The principal type of `expr`
+    pub fn infer(&self, expr: &Expr) -> Result<Type, TypeError> {
+        let mut inference = Inference::new(self);
+        let ty = inference.infer(expr, &mut Vec::new())?;
+        Ok(inference.generalize(&ty))
+    }
+
+    fn infer_group(&mut self, group: &[&str], defs: &Defs) {
+        let mut inference = Inference::new(self);
+        for &name in group {
+            let ty = inference.fresh();
+            inference.mono.insert(name.to_owned(), ty);
+        }
+        let mut failed = None;
+        for &name in group {
+            let res = inference
+                .infer(&defs[name].value, &mut Vec::new())
+                .and_then(|body| {
+                    let ty = inference.mono[name].clone();
+                    inference
+                        .unify(&ty, &body)
+                        .map_err(|clash| inference.clash(name, clash))
+                });
+            if let Err(err) = res {
+                failed = Some((name, err));
+                break;
+            }
+        }
+
+        let results: Vec<_> = group
+            .iter()
+            .map(|&name| {
+                let ty = match &failed {
+                    Some((failed, err)) if *failed == name => Err(err.clone()),
+                    Some((failed, _)) => Err(TypeError::Untyped((*failed).to_owned())),
+                    None => Ok(inference.generalize(&inference.mono[name])),
+                };
+                (name.to_owned(), ty)
+            })
+            .collect();
+        self.known.extend(results);
+    }
+
+    /// The type of a name that isn't defined, if it is a numeral or a
+    /// primitive
+    fn builtin(&self, name: &str) -> Option<Result<Type, TypeError>> {
+        use Type::*;
+        let a = || Var(0);
+        let b = || Var(1);
+        let church = || Type::arrows(vec![Type::arrow(a(), a()), a(), a()]);
+        let literal = name.parse::<u64>();
+        if self.primitives {
+            return Some(Ok(match name {
+                "+" | "*" | "-" => Type::arrows(vec![Int, Int, Int]),
+                "==" => Type::arrows(vec![Int, Int, a(), a(), a()]),
+                "If" => Type::arrows(vec![Type::arrows(vec![a(), a(), a()]), b(), b(), b()]),
+                "ToChurch" => Type::arrow(Int, church()),
+                "FromChurch" => Type::arrow(church(), Int),
+                _ if literal.is_ok() => Int,
+                _ => return None,
+            }));
+        }
+        let n = literal.ok()?;
+        match self.numerals {
+            Numerals::Church => Some(Ok(church())),
+            numerals => {
+                let encoded = numerals.encode(n).ok()?;
+                Some(self.infer(&encoded))
+            }
+        }
+    }
+}
+
+/// What went wrong unifying two types
+enum Clash {
+    Infinite(u32, Type),
+    Mismatch(Type, Type),
+}
+
+/// The state of inferring one term or group of definitions
+struct Inference<'a> {
+    types: &'a Types,
+    /// What each type variable has been unified with
+    subst: Vec<Option<Type>>,
+    /// The definitions being typed, which aren't generalized yet
+    mono: HashMap<String, Type>,
+}
+
+impl<'a> Inference<'a> {
+    fn new(types: &'a Types) -> Self {
+        Inference {
+            types,
+            subst: Vec::new(),
+            mono: HashMap::new(),
+        }
+    }
+
+    fn fresh(&mut self) -> Type {
+        self.subst.push(None);
+        Type::Var(self.subst.len() as u32 - 1)
+    }
+
+    /// `ty` with fresh variables in place of its quantified ones
+    fn instantiate(&mut self, ty: &Type) -> Type {
+        let base = self.subst.len() as u32;
+        self.subst.extend((0..ty.vars()).map(|_| None));
+        ty.shift(base)
+    }
+
+    fn infer(&mut self, expr: &Expr, bound: &mut Vec<(u8, Type)>) -> Result<Type, TypeError> {
+        match expr {
+            Expr::Variable(v) => bound
+                .iter()
+                .rev()
+                .find(|(p, _)| p == v)
+                .map(|(_, ty)| ty.clone())
+                .ok_or(TypeError::FreeVariable(*v)),
+            Expr::Name(name) => self.name(name),
+            Expr::Abstr(p, body) => {
+                let param = self.fresh();
+                bound.push((*p, param.clone()));
+                let body = self.infer(body, bound);
+                bound.pop();
+                Ok(Type::arrow(param, body?))
+            }
+            Expr::Appl(f, x) => {
+                let f = self.infer(f, bound)?;
+                let x = self.infer(x, bound)?;
+                let res = self.fresh();
+                self.unify(&f, &Type::arrow(x, res.clone()))
+                    .map_err(|clash| self.clash(&expr.tidy(), clash))?;
+                Ok(res)
+            }
+        }
+    }
+
+    fn name(&mut self, name: &str) -> Result<Type, TypeError> {
+        if let Some(ty) = self.mono.get(name) {
+            return Ok(ty.clone());
+        }
+        match self.types.known.get(name) {
+            Some(Ok(ty)) => Ok(self.instantiate(ty)),
+            Some(Err(_)) => Err(TypeError::Untyped(name.to_owned())),
+            None => match self.types.builtin(name) {
+                Some(ty) => Ok(self.instantiate(&ty?)),
+                None => Err(TypeError::UnknownName(name.to_owned())),
+            },
+        }
+    }
+
+    /// Follows the variables that have been unified with something
+    fn shallow(&self, ty: &Type) -> Type {
+        let mut ty = ty;
+        while let Type::Var(v) = ty {
+            match &self.subst[*v as usize] {
+                Some(bound) => ty = bound,
+                None => break,
+            }
+        }
+        ty.clone()
+    }
+
+    /// `ty` with everything known about its variables filled in
+    fn resolve(&self, ty: &Type) -> Type {
+        match self.shallow(ty) {
+            Type::Arrow(a, b) => Type::arrow(self.resolve(&a), self.resolve(&b)),
+            ty => ty,
+        }
+    }
+
+    fn generalize(&self, ty: &Type) -> Type {
+        Type::normalize(&[&self.resolve(ty)]).pop().unwrap()
+    }
+
+    fn occurs(&self, v: u32, ty: &Type) -> bool {
+        match self.shallow(ty) {
+            Type::Var(w) => v == w,
+            Type::Int => false,
+            Type::Arrow(a, b) => self.occurs(v, &a) || self.occurs(v, &b),
+        }
+    }
+
+    fn unify(&mut self, a: &Type, b: &Type) -> Result<(), Clash> {
+        match (self.shallow(a), self.shallow(b)) {
+            (Type::Var(v), Type::Var(w)) if v == w => Ok(()),
+            (Type::Var(v), ty) | (ty, Type::Var(v)) => {
+                if self.occurs(v, &ty) {
+                    return Err(Clash::Infinite(v, ty));
+                }
+                self.subst[v as usize] = Some(ty);
+                Ok(())
+            }
+            (Type::Int, Type::Int) => Ok(()),
+            (Type::Arrow(a, b), Type::Arrow(c, d)) => {
+                self.unify(&a, &c)?;
+                self.unify(&b, &d)
+            }
+            (a, b) => Err(Clash::Mismatch(a, b)),
+        }
+    }
+
+    /// The error for a clash found while typing `term`
+    fn clash(&self, term: &str, clash: Clash) -> TypeError {
+        let term = term.to_owned();
+        match clash {
+            Clash::Infinite(v, ty) => {
+                let mut types = Type::normalize(&[&Type::Var(v), &self.resolve(&ty)]);
+                let ty = types.pop().unwrap();
+                let var = types.pop().unwrap();
+                TypeError::Infinite { term, var, ty }
+            }
+            Clash::Mismatch(a, b) => {
+                let mut types = Type::normalize(&[&self.resolve(&a), &self.resolve(&b)]);
+                let right = types.pop().unwrap();
+                let left = types.pop().unwrap();
+                TypeError::Mismatch { term, left, right }
+            }
+        }
+    }
+}
+
+/// The names of `defs` in groups of mutually recursive definitions, each
+/// group after the ones it uses
+fn dependency_order(defs: &Defs) -> Vec<Vec<&str>> {
+    // Tarjan's strongly connected components
+    struct Search<'a> {
+        defs: &'a Defs,
+        index: HashMap<&'a str, usize>,
+        low: HashMap<&'a str, usize>,
+        stack: Vec<&'a str>,
+        on_stack: HashSet<&'a str>,
+        groups: Vec<Vec<&'a str>>,
+    }
+
+    impl<'a> Search<'a> {
+        fn visit(&mut self, name: &'a str) {
+            let index = self.index.len();
+            self.index.insert(name, index);
+            self.low.insert(name, index);
+            self.stack.push(name);
+            self.on_stack.insert(name);
+
+            let mut uses: Vec<&str> = self.defs[name].value.names().into_iter().collect();
+            uses.sort_unstable();
+            for used in uses {
+                let used = match self.defs.get_key_value(used) {
+                    Some((used, _)) => &used[..],
+                    None => continue,
+                };
+                if !self.index.contains_key(used) {
+                    self.visit(used);
+                    let low = self.low[name].min(self.low[used]);
+                    self.low.insert(name, low);
+                } else if self.on_stack.contains(used) {
+                    let low = self.low[name].min(self.index[used]);
+                    self.low.insert(name, low);
+                }
+            }
+
+            if self.low[name] == index {
+                let mut group = Vec::new();
+                while let Some(member) = self.stack.pop() {
+                    self.on_stack.remove(member);
+                    group.push(member);
+                    if member == name {
+                        break;
+                    }
+                }
+                group.sort_unstable();
+                self.groups.push(group);
+            }
+        }
+    }
+
+    let mut search = Search {
+        defs,
+        index: HashMap::new(),
+        low: HashMap::new(),
+        stack: Vec::new(),
+        on_stack: HashSet::new(),
+        groups: Vec::new(),
+    };
+    let mut names: Vec<&str> = defs.keys().map(|k| &k[..]).collect();
+    names.sort_unstable();
+    for name in names {
+        if !search.index.contains_key(name) {
+            search.visit(name);
+        }
+    }
+    search.groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::Def;
+    use crate::test::*;
+
+    fn lib() -> Defs {
+        let src = include_bytes!("../res/lib");
+        crate::parse::parse(crate::lex::lex(&src[..]).unwrap())
+            .unwrap()
+            .0
+    }
+
+    fn shown(ty: Option<&Result<Type, TypeError>>) -> String {
+        match ty.unwrap() {
+            Ok(ty) => ty.to_string(),
+            Err(err) => format!("error: {}", err),
+        }
+    }
+
+    #[test]
+    fn lib_types() {
+        let types = Types::new(&lib(), &Config::default());
+        assert_eq!(shown(types.get("I")), "a -> a");
+        assert_eq!(shown(types.get("True")), "a -> b -> a");
+        assert_eq!(shown(types.get("False")), "a -> b -> b");
+        assert_eq!(
+            shown(types.get("Add")),
+            "(a -> b -> c) -> (a -> d -> b) -> a -> d -> c"
+        );
+        assert_eq!(
+            shown(types.get("Y")),
+            "error: aa needs the infinite type a = a -> b"
+        );
+
+        // Definitions are generalized before they are used
+        let id_id = types.infer(&process("Pair (I I) (I 2)")).unwrap();
+        assert_eq!(
+            id_id.to_string(),
+            "((a -> a) -> ((b -> b) -> b -> b) -> c) -> c"
+        );
+        assert_eq!(
+            types.infer(&process("Nope")),
+            Err(TypeError::UnknownName("Nope".into()))
+        );
+    }
+
+    #[test]
+    fn groups() {
+        let mut defs = Defs::new();
+        defs.insert("A".into(), Def::new(process(r"\x.x B")));
+        defs.insert("B".into(), Def::new(process(r"\x.A")));
+        defs.insert("C".into(), Def::new(process("B")));
+        defs.insert("D".into(), Def::new(process(r"\x.x x")));
+        defs.insert("E".into(), Def::new(process("D")));
+        assert_eq!(
+            dependency_order(&defs),
+            vec![vec!["A", "B"], vec!["C"], vec!["D"], vec!["E"]]
+        );
+
+        let types = Types::new(&defs, &Config::default());
+        // The group fails at `B`, which needs the type of `A` to contain
+        // its own
+        assert_eq!(types.get("A"), Some(&Err(TypeError::Untyped("B".into()))));
+        assert!(matches!(
+            types.get("B"),
+            Some(Err(TypeError::Infinite { .. }))
+        ));
+        assert_eq!(shown(types.get("E")), "error: Uses D, which has no type");
+    }
+
+    #[test]
+    fn primitives() {
+        let config = Config {
+            primitives: true,
+            ..Default::default()
+        };
+        let types = Types::new(&lib(), &config);
+        let infer = |src| match types.infer(&process(src)) {
+            Ok(ty) => ty.to_string(),
+            Err(err) => err.to_string(),
+        };
+        assert_eq!(infer("+ 1"), "Int -> Int");
+        assert_eq!(infer("If (== 1 2) I"), "(a -> a) -> a -> a");
+        assert_eq!(infer("FromChurch (ToChurch 3)"), "Int");
+        assert_eq!(infer("+ I"), "+ I needs Int and a -> a to be the same");
+    }
+}
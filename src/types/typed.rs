@@ -0,0 +1,658 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::expr::expr_aliases::*;
+use crate::expr::Expr;
+use crate::parse::Program;
+
+/// A type as written in annotations.
+///
+/// Names bound by `All` are type variables, and any other name is a base
+/// type like `Nat`.
+#[derive(Debug, Clone)]
+pub enum Ty {
+    Con(String),
+    Arrow(Box<Ty>, Box<Ty>),
+    /// `All A. T`, System F only
+    Forall(String, Box<Ty>),
+}
+
+/// A term as written, with its type annotations.
+///
+/// Evaluation sees the erased `Expr`, so the annotations only matter to the
+/// typed checkers.
+#[derive(Debug, Clone)]
+pub enum Term {
+    Var(u8),
+    Name(String),
+    /// `\x:T.e`, the type left out in untyped code
+    Abstr(u8, Option<Ty>, Box<Term>),
+    Appl(Box<Term>, Box<Term>),
+    /// `\[A].e`, System F only
+    TyAbstr(String, Box<Term>),
+    /// `e [T]`, System F only
+    TyAppl(Box<Term>, Ty),
+}
+
+/// Which typed lambda calculus to check a program in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Calculus {
+    /// The simply typed lambda calculus, with base types only
+    Simple,
+    /// The polymorphic lambda calculus, with `All` types and type
+    /// abstraction and application
+    SystemF,
+}
+
+/// Why an annotated definition or term doesn't check, with where it starts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckError {
+    pub msg: String,
+    pub row: u32,
+    pub col: u32,
+}
+
+impl Ty {
+    fn arrow(a: Ty, b: Ty) -> Ty {
+        Ty::Arrow(Box::new(a), Box::new(b))
+    }
+
+    fn free(&self) -> HashSet<&str> {
+        match self {
+            Ty::Con(c) => std::iter::once(&c[..]).collect(),
+            Ty::Arrow(a, b) => &a.free() | &b.free(),
+            Ty::Forall(v, body) => {
+                let mut free = body.free();
+                free.remove(&v[..]);
+                free
+            }
+        }
+    }
+
+    /// `self` with `with` in place of the free `var`, renaming binders that
+    /// would capture the variables of `with`
+    pub fn subst(&self, var: &str, with: &Ty) -> Ty {
+        match self {
+            Ty::Con(c) if c == var => with.clone(),
+            Ty::Con(_) => self.clone(),
+            Ty::Arrow(a, b) => Ty::arrow(a.subst(var, with), b.subst(var, with)),
+            Ty::Forall(v, _) if v == var => self.clone(),
+            Ty::Forall(v, body) => {
+                let free = with.free();
+                if !free.contains(&v[..]) {
+                    return Ty::Forall(v.clone(), Box::new(body.subst(var, with)));
+                }
+                let mut fresh = v.clone();
+                while free.contains(&fresh[..]) || body.free().contains(&fresh[..]) {
+                    fresh.push('\'');
+                }
+                let body = body.subst(v, &Ty::Con(fresh.clone()));
+                Ty::Forall(fresh, Box::new(body.subst(var, with)))
+            }
+        }
+    }
+
+    /// Whether the types are the same up to the names of bound variables
+    pub fn alpha_eq(&self, other: &Ty) -> bool {
+        fn go<'a>(a: &'a Ty, b: &'a Ty, bound: &mut Vec<(&'a str, &'a str)>) -> bool {
+            match (a, b) {
+                (Ty::Con(x), Ty::Con(y)) => {
+                    let x_at = bound.iter().rposition(|&(l, _)| l == x);
+                    let y_at = bound.iter().rposition(|&(_, r)| r == y);
+                    match (x_at, y_at) {
+                        (None, None) => x == y,
+                        (x_at, y_at) => x_at == y_at,
+                    }
+                }
+                (Ty::Arrow(a, b), Ty::Arrow(c, d)) => go(a, c, bound) && go(b, d, bound),
+                (Ty::Forall(x, a), Ty::Forall(y, b)) => {
+                    bound.push((x, y));
+                    let eq = go(a, b, bound);
+                    bound.pop();
+                    eq
+                }
+                _ => false,
+            }
+        }
+        go(self, other, &mut Vec::new())
+    }
+
+    fn is_polymorphic(&self) -> bool {
+        match self {
+            Ty::Con(_) => false,
+            Ty::Arrow(a, b) => a.is_polymorphic() || b.is_polymorphic(),
+            Ty::Forall(..) => true,
+        }
+    }
+}
+
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ty::Con(c) => f.write_str(c),
+            Ty::Arrow(a, b) => match **a {
+                Ty::Con(_) => write!(f, "{} -> {}", a, b),
+                _ => write!(f, "({}) -> {}", a, b),
+            },
+            Ty::Forall(v, body) => {
+                write!(f, "All {}", v)?;
+                let mut body = body;
+                while let Ty::Forall(v, inner) = &**body {
+                    write!(f, " {}", v)?;
+                    body = inner;
+                }
+                write!(f, ". {}", body)
+            }
+        }
+    }
+}
+
+impl Term {
+    /// The untyped term, without annotations, type abstractions and type
+    /// applications
+    pub fn erase(&self) -> Box<Expr> {
+        enum Job<'a> {
+            Visit(&'a Term),
+            Abstr(u8),
+            Appl,
+        }
+
+        let mut jobs = vec![Job::Visit(self)];
+        let mut done: Vec<Box<Expr>> = Vec::new();
+        while let Some(job) = jobs.pop() {
+            match job {
+                Job::Visit(Term::Var(v)) => done.push(var(*v)),
+                Job::Visit(Term::Name(n)) => done.push(name(n.clone())),
+                Job::Visit(Term::Abstr(p, _, b)) => {
+                    jobs.push(Job::Abstr(*p));
+                    jobs.push(Job::Visit(b));
+                }
+                Job::Visit(Term::Appl(a, b)) => {
+                    jobs.push(Job::Appl);
+                    jobs.push(Job::Visit(b));
+                    jobs.push(Job::Visit(a));
+                }
+                Job::Visit(Term::TyAbstr(_, e) | Term::TyAppl(e, _)) => jobs.push(Job::Visit(e)),
+                Job::Abstr(p) => {
+                    let body = done.pop().unwrap();
+                    done.push(abstr(p, body));
+                }
+                Job::Appl => {
+                    let b = done.pop().unwrap();
+                    let a = done.pop().unwrap();
+                    done.push(appl(a, b));
+                }
+            }
+        }
+        done.pop().unwrap()
+    }
+
+    fn take(&mut self) -> Term {
+        std::mem::replace(self, Term::Var(0))
+    }
+}
+
+impl Drop for Term {
+    fn drop(&mut self) {
+        // Like `Expr`, children are moved onto a stack so that dropping a
+        // deep term doesn't recurse
+        let mut stack: Vec<Term> = Vec::new();
+        let mut next = match self {
+            Term::Var(_) | Term::Name(_) => return,
+            Term::Abstr(_, _, b) | Term::TyAbstr(_, b) | Term::TyAppl(b, _) => b.take(),
+            Term::Appl(a, b) => {
+                stack.push(b.take());
+                a.take()
+            }
+        };
+        loop {
+            match &mut next {
+                Term::Var(_) | Term::Name(_) => {}
+                Term::Abstr(_, _, b) | Term::TyAbstr(_, b) | Term::TyAppl(b, _) => {
+                    stack.push(b.take())
+                }
+                Term::Appl(a, b) => {
+                    stack.push(a.take());
+                    stack.push(b.take());
+                }
+            }
+            next = match stack.pop() {
+                Some(t) => t,
+                None => break,
+            };
+        }
+    }
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        enum Job<'a> {
+            Visit(&'a Term),
+            Str(&'static str),
+            Ty(&'a Ty),
+        }
+
+        // Jobs are pushed in reverse, the last one is done first
+        let mut jobs = vec![Job::Visit(self)];
+        while let Some(job) = jobs.pop() {
+            let term = match job {
+                Job::Visit(term) => term,
+                Job::Str(s) => {
+                    f.write_str(s)?;
+                    continue;
+                }
+                Job::Ty(ty) => {
+                    write!(f, "{}", ty)?;
+                    continue;
+                }
+            };
+            match term {
+                Term::Var(v) => write!(f, "{}", *v as char)?,
+                Term::Name(n) => f.write_str(n)?,
+                Term::Abstr(p, ty, b) => {
+                    write!(f, "\\{}", *p as char)?;
+                    if let Some(ty) = ty {
+                        write!(f, " : {}", ty)?;
+                    }
+                    f.write_str(". ")?;
+                    jobs.push(Job::Visit(b));
+                }
+                Term::TyAbstr(v, b) => {
+                    write!(f, "\\[{}]. ", v)?;
+                    jobs.push(Job::Visit(b));
+                }
+                Term::Appl(a, b) => {
+                    match **b {
+                        Term::Appl(..) | Term::TyAppl(..) | Term::Abstr(..) | Term::TyAbstr(..) => {
+                            jobs.extend([Job::Str(")"), Job::Visit(b), Job::Str(" (")])
+                        }
+                        _ => jobs.extend([Job::Visit(b), Job::Str(" ")]),
+                    }
+                    match **a {
+                        Term::Abstr(..) | Term::TyAbstr(..) => {
+                            jobs.extend([Job::Str(")"), Job::Visit(a), Job::Str("(")])
+                        }
+                        _ => jobs.push(Job::Visit(a)),
+                    }
+                }
+                Term::TyAppl(e, ty) => {
+                    jobs.extend([Job::Str("]"), Job::Ty(ty)]);
+                    match **e {
+                        Term::Abstr(..) | Term::TyAbstr(..) => {
+                            jobs.extend([Job::Str(") ["), Job::Visit(e), Job::Str("(")])
+                        }
+                        _ => jobs.extend([Job::Str(" ["), Job::Visit(e)]),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Calculus {
+    pub const NAMES: [&'static str; 2] = ["simple", "system-f"];
+}
+
+impl FromStr for Calculus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "simple" => Ok(Calculus::Simple),
+            "system-f" => Ok(Calculus::SystemF),
+            _ => Err(format!(
+                "Unknown calculus: {}, expected one of {}",
+                s,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let CheckError { row, col, msg } = self;
+        write!(f, "TypeError({}:{}): {}", row, col, msg)
+    }
+}
+
+impl Error for CheckError {}
+
+/// Checks the definitions and the main term of `program` in the order they
+/// are written, each with the types in `known` and of the definitions
+/// before it.
+///
+/// Definitions with a signature `Name : T;` are checked against it, which
+/// lets their parameters go without annotations. Numerals are constants of
+/// the base type `Nat`. The types of definitions that check are added to
+/// `known`; the results are named like the definitions, and the main term
+/// after itself.
+pub fn check_program(
+    program: &Program,
+    calculus: Calculus,
+    known: &mut HashMap<String, Ty>,
+) -> Vec<(String, Result<Ty, CheckError>)> {
+    let mut results = Vec::new();
+    let mut signatures = HashMap::new();
+    for sig in &program.signatures {
+        signatures.insert(&sig.name[..], sig);
+        if !program.defs.contains_key(&sig.name) {
+            let msg = format!("{} has a signature but no definition", sig.name);
+            let (row, col) = (sig.row, sig.col);
+            results.push((sig.name.clone(), Err(CheckError { msg, row, col })));
+        }
+    }
+
+    for annotated in &program.annotated {
+        let label = match &annotated.name {
+            Some(name) => name.clone(),
+            None => annotated.term.to_string(),
+        };
+        let signature = annotated.name.as_ref().and_then(|n| signatures.get(&n[..]));
+        let mut checker = Checker {
+            calculus,
+            known,
+            vars: Vec::new(),
+        };
+        let res = match signature {
+            Some(sig) => checker
+                .well_formed(&sig.ty)
+                .and_then(|()| checker.check(&annotated.term, &sig.ty))
+                .map(|()| sig.ty.clone()),
+            None => checker.synth(&annotated.term),
+        };
+        let res = res.map_err(|msg| {
+            let msg = match &annotated.name {
+                Some(name) => format!("In {}: {}", name, msg),
+                None => msg,
+            };
+            let (row, col) = (annotated.row, annotated.col);
+            CheckError { msg, row, col }
+        });
+        if let (Some(name), Ok(ty)) = (&annotated.name, &res) {
+            known.insert(name.clone(), ty.clone());
+        }
+        results.push((label, res));
+    }
+    results
+}
+
+struct Checker<'a> {
+    calculus: Calculus,
+    known: &'a HashMap<String, Ty>,
+    /// The types of the bound variables in scope, innermost last
+    vars: Vec<(u8, Ty)>,
+}
+
+impl Checker<'_> {
+    fn well_formed(&self, ty: &Ty) -> Result<(), String> {
+        match self.calculus {
+            Calculus::Simple if ty.is_polymorphic() => {
+                Err(format!("{} is polymorphic, which needs System F", ty))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn system_f(&self, what: &str) -> Result<(), String> {
+        match self.calculus {
+            Calculus::Simple => Err(format!("{} needs System F", what)),
+            Calculus::SystemF => Ok(()),
+        }
+    }
+
+    fn bound<T>(&mut self, p: u8, ty: Ty, f: impl FnOnce(&mut Self) -> T) -> T {
+        self.vars.push((p, ty));
+        let res = f(self);
+        self.vars.pop();
+        res
+    }
+
+    /// Fails if `\\[v]. body` would bind a `v` from outside of it: one in
+    /// the types of the variables in scope, in `expected`, or in the types
+    /// of the names and numerals `body` uses.
+    fn no_capture(&self, v: &str, body: &Term, expected: Option<&Ty>) -> Result<(), String> {
+        let nat = Ty::Con("Nat".into());
+        let mut outside: Vec<&Ty> = self.vars.iter().map(|(_, ty)| ty).collect();
+        outside.extend(expected);
+        let mut stack = vec![body];
+        while let Some(term) = stack.pop() {
+            match term {
+                Term::Var(_) => {}
+                Term::Name(n) => match self.known.get(n) {
+                    Some(ty) => outside.push(ty),
+                    None if n.parse::<u64>().is_ok() => outside.push(&nat),
+                    None => {}
+                },
+                Term::Abstr(_, _, b) | Term::TyAbstr(_, b) | Term::TyAppl(b, _) => stack.push(b),
+                Term::Appl(a, b) => {
+                    stack.push(a);
+                    stack.push(b);
+                }
+            }
+        }
+        match outside.into_iter().find(|ty| ty.free().contains(v)) {
+            Some(ty) => Err(format!("\\[{}] would capture the {} in {}", v, v, ty)),
+            None => Ok(()),
+        }
+    }
+
+    /// The type of `term`, which must have its parameters annotated
+    fn synth(&mut self, term: &Term) -> Result<Ty, String> {
+        match term {
+            Term::Var(v) => self
+                .vars
+                .iter()
+                .rev()
+                .find(|(p, _)| p == v)
+                .map(|(_, ty)| ty.clone())
+                .ok_or_else(|| format!("Free variable: {}", *v as char)),
+            Term::Name(n) => match self.known.get(n) {
+                Some(ty) => Ok(ty.clone()),
+                None if n.parse::<u64>().is_ok() => Ok(Ty::Con("Nat".into())),
+                None => Err(format!("Unknown or untyped name: {}", n)),
+            },
+            Term::Abstr(p, Some(ty), body) => {
+                self.well_formed(ty)?;
+                let body = self.bound(*p, ty.clone(), |c| c.synth(body))?;
+                Ok(Ty::arrow(ty.clone(), body))
+            }
+            Term::Abstr(p, None, _) => Err(format!(
+                "The parameter {} of {} needs a type",
+                *p as char,
+                term.erase().tidy()
+            )),
+            Term::Appl(f, x) => match self.synth(f)? {
+                Ty::Arrow(a, b) => {
+                    self.check(x, &a)?;
+                    Ok(*b)
+                }
+                ty => Err(format!(
+                    "{} is applied to {}, but has type {}",
+                    f.erase().tidy(),
+                    x.erase().tidy(),
+                    ty
+                )),
+            },
+            Term::TyAbstr(v, body) => {
+                self.system_f("Type abstraction")?;
+                self.no_capture(v, body, None)?;
+                Ok(Ty::Forall(v.clone(), Box::new(self.synth(body)?)))
+            }
+            Term::TyAppl(e, ty) => {
+                self.system_f("Type application")?;
+                match self.synth(e)? {
+                    Ty::Forall(v, body) => Ok(body.subst(&v, ty)),
+                    found => Err(format!(
+                        "{} is applied to the type {}, but has type {}",
+                        e.erase().tidy(),
+                        ty,
+                        found
+                    )),
+                }
+            }
+        }
+    }
+
+    /// Checks that `term` has type `expected`, taking the types of
+    /// parameters that go without annotations from it
+    fn check(&mut self, term: &Term, expected: &Ty) -> Result<(), String> {
+        match (term, expected) {
+            (Term::Abstr(p, ty, body), Ty::Arrow(a, b)) => {
+                if let Some(ty) = ty {
+                    self.well_formed(ty)?;
+                    if !ty.alpha_eq(a) {
+                        return Err(format!(
+                            "The parameter {} of {} has type {}, expected {}",
+                            *p as char,
+                            term.erase().tidy(),
+                            ty,
+                            a
+                        ));
+                    }
+                }
+                self.bound(*p, (**a).clone(), |c| c.check(body, b))
+            }
+            (Term::Abstr(..), _) => Err(format!(
+                "{} is a function, expected {}",
+                term.erase().tidy(),
+                expected
+            )),
+            (Term::TyAbstr(v, body), Ty::Forall(w, b)) => {
+                self.system_f("Type abstraction")?;
+                self.no_capture(v, body, Some(expected))?;
+                let b = b.subst(w, &Ty::Con(v.clone()));
+                self.check(body, &b)
+            }
+            _ => {
+                let found = self.synth(term)?;
+                if found.alpha_eq(expected) {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "{} has type {}, expected {}",
+                        term.erase().tidy(),
+                        found,
+                        expected
+                    ))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lex::lex;
+    use crate::parse::parse_program;
+
+    fn checked(src: &str, calculus: Calculus) -> Vec<String> {
+        let program = parse_program(lex(src.as_bytes()).unwrap()).unwrap();
+        check_program(&program, calculus, &mut HashMap::new())
+            .into_iter()
+            .map(|(name, res)| match res {
+                Ok(ty) => format!("{} : {}", name, ty),
+                Err(err) => err.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn simply_typed() {
+        let src = "Twice = \\f : Nat -> Nat. \\x : Nat. f (f x);
+            Add : Nat -> Nat -> Nat;
+            Add = \\nmfx.nf(mfx);
+            Three : Nat;
+            Three = Twice (\\n:Nat.n) 3;
+            Twice";
+        assert_eq!(
+            checked(src, Calculus::Simple),
+            [
+                "Twice : (Nat -> Nat) -> Nat -> Nat",
+                "TypeError(3:13): In Add: \\fx.nf(mfx) is a function, expected Nat",
+                "Three : Nat",
+                "Twice : (Nat -> Nat) -> Nat -> Nat",
+            ]
+        );
+
+        let src = "Id = \\x.x;\nK : A -> B -> A;\nK = \\x y : B. x;\nApp = K 1;\nLoose : Nat;";
+        assert_eq!(
+            checked(src, Calculus::Simple),
+            [
+                "TypeError(5:1): Loose has a signature but no definition",
+                "TypeError(1:1): In Id: The parameter x of \\x.x needs a type",
+                "K : A -> B -> A",
+                "TypeError(4:1): In App: 1 has type Nat, expected A",
+            ]
+        );
+
+        let src = "Id = \\[A] x : A. x;\nUse = Id [Nat] 1";
+        let errs = checked(src, Calculus::Simple);
+        assert_eq!(
+            errs[0],
+            "TypeError(1:1): In Id: Type abstraction needs System F"
+        );
+        assert_eq!(
+            errs[1],
+            "TypeError(2:1): In Use: Type application needs System F"
+        );
+    }
+
+    #[test]
+    fn system_f() {
+        let src = "Id : All A. A -> A;
+            Id = \\[A] x. x;
+            Two = \\[A] f : A -> A. \\x : A. f (f x);
+            Self = \\x : All A. A -> A. x [All A. A -> A] x;
+            Const : All A B. A -> B -> A;
+            Const = \\[B] [A] x y. x;
+            Id [Nat] 3";
+        assert_eq!(
+            checked(src, Calculus::SystemF),
+            [
+                "Id : All A. A -> A",
+                "Two : All A. (A -> A) -> A -> A",
+                "Self : (All A. A -> A) -> All A. A -> A",
+                "Const : All A B. A -> B -> A",
+                "Id [Nat] 3 : Nat",
+            ]
+        );
+        assert!(checked("Id [Nat]", Calculus::SystemF)[0].contains("Unknown"));
+
+        // Type abstractions don't capture the types around them
+        let src = "Bad = \\x : Nat. \\[Nat]. x;
+            Bad' : All A. B -> A;
+            Bad' = \\[B] x. x;
+            Three = \\[Nat]. 3;
+            Fine = \\[A] x : A. \\[B]. x";
+        assert_eq!(
+            checked(src, Calculus::SystemF),
+            [
+                "TypeError(1:1): In Bad: \\[Nat] would capture the Nat in Nat",
+                "TypeError(3:13): In Bad': \\[B] would capture the B in All A. B -> A",
+                "TypeError(4:13): In Three: \\[Nat] would capture the Nat in Nat",
+                "Fine : All A. A -> All B. A",
+            ]
+        );
+        assert_eq!(
+            checked("\\x : Nat. x [Nat]", Calculus::SystemF),
+            ["TypeError(1:1): x is applied to the type Nat, but has type Nat"]
+        );
+
+        // Substitution avoids capturing the variables it puts in
+        let ty = Ty::Forall(
+            "B".into(),
+            Box::new(Ty::arrow(Ty::Con("A".into()), Ty::Con("B".into()))),
+        );
+        let with = ty.subst("A", &Ty::Con("B".into()));
+        assert_eq!(with.to_string(), "All B'. B -> B'");
+        assert!(with.alpha_eq(&Ty::Forall(
+            "C".into(),
+            Box::new(Ty::arrow(Ty::Con("B".into()), Ty::Con("C".into())))
+        )));
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::expr::{Defs, Expr};
+
+/// A single instruction. Terms are laid out in prefix order, so an
+/// abstraction's body starts right after it and an application's argument
+/// starts right after its function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// A bound variable by de Bruijn index
+    Var(u32),
+    /// A variable not bound anywhere in the term
+    Free(u8),
+    /// A definition or numeral, by index into `Program::globals`
+    Global(u32),
+    /// A name with no definition, by index into `Program::unknowns`
+    Unknown(u32),
+    /// An abstraction and the name of its parameter
+    Lam(u8),
+    /// An application and the length of its function
+    App(u32),
+}
+
+#[derive(Clone, Debug)]
+pub struct Program {
+    pub code: Vec<Op>,
+    /// Entry point of the main expression
+    pub main: u32,
+    /// Name and entry point of every definition reachable from main
+    pub globals: Vec<(String, u32)>,
+    pub unknowns: Vec<String>,
+}
+
+/// Compiles `expr` with every name resolved against `defs`, the same way
+/// `eval::reduce` would unfold them.
+pub fn compile(expr: &Expr, defs: &Defs) -> Program {
+    let mut compiler = Compiler {
+        code: Vec::new(),
+        defs,
+        globals: Vec::new(),
+        global_ids: HashMap::new(),
+        unknowns: Vec::new(),
+        unknown_ids: HashMap::new(),
+        pending: Vec::new(),
+    };
+    let main = compiler.term(expr);
+    while let Some((id, value)) = compiler.pending.pop() {
+        let entry = compiler.term(&value);
+        compiler.globals[id as usize].1 = entry;
+    }
+    Program {
+        code: compiler.code,
+        main,
+        globals: compiler.globals,
+        unknowns: compiler.unknowns,
+    }
+}
+
+struct Compiler<'a> {
+    code: Vec<Op>,
+    defs: &'a Defs,
+    globals: Vec<(String, u32)>,
+    global_ids: HashMap<String, u32>,
+    unknowns: Vec<String>,
+    unknown_ids: HashMap<String, u32>,
+    pending: Vec<(u32, Box<Expr>)>,
+}
+
+impl Compiler<'_> {
+    fn term(&mut self, expr: &Expr) -> u32 {
+        use Expr::*;
+        enum Job<'e> {
+            Visit(&'e Expr),
+            Leave(u8),
+            /// Patch the length of the function of the `App` at this address
+            Patch(usize),
+        }
+
+        let entry = self.code.len() as u32;
+        let mut scopes: HashMap<u8, Vec<u32>> = HashMap::new();
+        let mut level = 0;
+        let mut jobs = vec![Job::Visit(expr)];
+        while let Some(job) = jobs.pop() {
+            match job {
+                Job::Visit(Variable(v)) => {
+                    let op = match scopes.get(v).and_then(|s| s.last()) {
+                        Some(l) => Op::Var(level - 1 - l),
+                        None => Op::Free(*v),
+                    };
+                    self.code.push(op);
+                }
+                Job::Visit(Name(n)) => {
+                    let op = self.name(n);
+                    self.code.push(op);
+                }
+                Job::Visit(Abstr(p, b)) => {
+                    self.code.push(Op::Lam(*p));
+                    scopes.entry(*p).or_default().push(level);
+                    level += 1;
+                    jobs.push(Job::Leave(*p));
+                    jobs.push(Job::Visit(b));
+                }
+                Job::Visit(Appl(a, b)) => {
+                    self.code.push(Op::App(0));
+                    jobs.push(Job::Visit(b));
+                    jobs.push(Job::Patch(self.code.len() - 1));
+                    jobs.push(Job::Visit(a));
+                }
+                Job::Leave(p) => {
+                    scopes.get_mut(&p).and_then(Vec::pop);
+                    level -= 1;
+                }
+                Job::Patch(at) => {
+                    let len = self.code.len() - at - 1;
+                    self.code[at] = Op::App(len as u32);
+                }
+            }
+        }
+        entry
+    }
+
+    fn name(&mut self, n: &str) -> Op {
+        if let Some(&id) = self.global_ids.get(n) {
+            return Op::Global(id);
+        }
+        let value = if let Some(def) = self.defs.get(n) {
+            def.value.clone()
+        } else if let Ok(num) = n.parse() {
+            Expr::church_num(num)
+        } else {
+            let next = self.unknowns.len() as u32;
+            let id = *self.unknown_ids.entry(n.to_owned()).or_insert(next);
+            if id == next {
+                self.unknowns.push(n.to_owned());
+            }
+            return Op::Unknown(id);
+        };
+        let id = self.globals.len() as u32;
+        self.globals.push((n.to_owned(), 0));
+        self.global_ids.insert(n.to_owned(), id);
+        self.pending.push((id, value));
+        Op::Global(id)
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut labels: HashMap<u32, &str> = HashMap::new();
+        labels.insert(self.main, "main");
+        for (name, entry) in &self.globals {
+            labels.insert(*entry, name);
+        }
+        for (addr, op) in self.code.iter().enumerate() {
+            if let Some(label) = labels.get(&(addr as u32)) {
+                writeln!(f, "{}:", label)?;
+            }
+            write!(f, "{:>6}  ", addr)?;
+            match op {
+                Op::Var(i) => writeln!(f, "var {}", i)?,
+                Op::Free(v) => writeln!(f, "free {}", std::ascii::escape_default(*v))?,
+                Op::Global(g) => writeln!(f, "global {}", self.globals[*g as usize].0)?,
+                Op::Unknown(u) => writeln!(f, "unknown {}", self.unknowns[*u as usize])?,
+                Op::Lam(p) => writeln!(f, "lam {}", std::ascii::escape_default(*p))?,
+                Op::App(len) => writeln!(f, "app {}", addr as u32 + 1 + len)?,
+            }
+        }
+        Ok(())
+    }
+}
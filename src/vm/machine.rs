@@ -0,0 +1,275 @@
+use super::compile::{Op, Program};
+use crate::debruijn::DeBruijn;
+use crate::eval::EvalError;
+
+/// Index into the thunk or cell heap
+type Ref = u32;
+const NIL: Ref = u32::MAX;
+
+#[derive(Clone, Copy, Debug)]
+enum Head {
+    /// A parameter introduced by readback, by binding depth
+    Level(u32),
+    Free(u8),
+    Unknown(u32),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Value {
+    Lam {
+        body: u32,
+        env: Ref,
+    },
+    /// A stuck application, its arguments listed last to first
+    Neutral {
+        head: Head,
+        spine: Ref,
+    },
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Thunk {
+    Delayed { pc: u32, env: Ref },
+    Evaluating,
+    Done(Value),
+}
+
+/// Link of an environment or a spine, both lists of thunks
+#[derive(Clone, Copy, Debug)]
+struct Cell {
+    thunk: Ref,
+    next: Ref,
+}
+
+enum Frame {
+    Arg(Ref),
+    Update(Ref),
+}
+
+enum Control {
+    Force(Ref),
+    Eval(u32, Ref),
+    Return(Value),
+}
+
+enum Job {
+    Eval(Ref, u32),
+    Abstr(u8),
+    Apps(Head, u32, u32),
+}
+
+/// A lazy machine for strong normalization of compiled programs.
+///
+/// Arguments are shared thunks, evaluated at most once, and weak head normal
+/// forms are read back under abstractions until the whole term is normal.
+/// Nothing is ever freed; the heap only grows until the machine is dropped.
+pub struct Machine<'p> {
+    program: &'p Program,
+    thunks: Vec<Thunk>,
+    cells: Vec<Cell>,
+    globals: Vec<Ref>,
+    steps: u64,
+    pub max_steps: u64,
+    pub max_heap: usize,
+}
+
+impl<'p> Machine<'p> {
+    pub fn new(program: &'p Program) -> Self {
+        let mut m = Machine {
+            program,
+            thunks: Vec::new(),
+            cells: Vec::new(),
+            globals: Vec::new(),
+            steps: 0,
+            max_steps: 10000000,
+            max_heap: 20000000,
+        };
+        for &(_, entry) in &program.globals {
+            m.globals.push(m.thunks.len() as Ref);
+            m.thunks.push(Thunk::Delayed {
+                pc: entry,
+                env: NIL,
+            });
+        }
+        m
+    }
+
+    /// Beta reductions performed so far
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn heap_size(&self) -> usize {
+        self.thunks.len() + self.cells.len()
+    }
+
+    /// Runs the main expression to its beta-eta normal form.
+    pub fn normalize(&mut self) -> Result<Box<DeBruijn>, Box<EvalError>> {
+        use DeBruijn::*;
+
+        let main = self.alloc(Thunk::Delayed {
+            pc: self.program.main,
+            env: NIL,
+        })?;
+        let mut jobs = vec![Job::Eval(main, 0)];
+        let mut done: Vec<Box<DeBruijn>> = Vec::new();
+        while let Some(job) = jobs.pop() {
+            match job {
+                Job::Eval(thunk, level) => match self.whnf(thunk)? {
+                    Value::Lam { body, env } => {
+                        let hint = match self.program.code[body as usize - 1] {
+                            Op::Lam(p) => p,
+                            _ => unreachable!("abstraction body not preceded by Lam"),
+                        };
+                        let param = self.alloc(Thunk::Done(Value::Neutral {
+                            head: Head::Level(level),
+                            spine: NIL,
+                        }))?;
+                        let env = self.cons(param, env)?;
+                        let body = self.alloc(Thunk::Delayed { pc: body, env })?;
+                        jobs.push(Job::Abstr(hint));
+                        jobs.push(Job::Eval(body, level + 1));
+                    }
+                    Value::Neutral { head, mut spine } => {
+                        let mut n = 0;
+                        jobs.push(Job::Apps(head, 0, level));
+                        let apps = jobs.len() - 1;
+                        // The spine lists the last argument first, which is
+                        // also the order the job stack wants them in
+                        while spine != NIL {
+                            let Cell { thunk, next } = self.cells[spine as usize];
+                            jobs.push(Job::Eval(thunk, level));
+                            spine = next;
+                            n += 1;
+                        }
+                        jobs[apps] = Job::Apps(head, n, level);
+                    }
+                },
+                Job::Abstr(hint) => {
+                    let mut body = done.pop().unwrap();
+                    // Eta reduction: \a.Ea  =>  E  when a is not free in E
+                    let eta = matches!(&*body, Appl(f, box Bound(0)) if !f.references(0));
+                    done.push(match *body {
+                        Appl(ref mut f, _) if eta => {
+                            let mut f = std::mem::replace(f, Box::new(Bound(0)));
+                            f.shift(-1, 0);
+                            f
+                        }
+                        _ => Box::new(Abstr(hint, body)),
+                    });
+                }
+                Job::Apps(head, n, level) => {
+                    let mut term = Box::new(match head {
+                        Head::Level(l) => Bound(level - 1 - l),
+                        Head::Free(v) => Free(v),
+                        Head::Unknown(u) => Name(self.program.unknowns[u as usize].clone()),
+                    });
+                    for arg in done.drain(done.len() - n as usize..) {
+                        term = Box::new(Appl(term, arg));
+                    }
+                    done.push(term);
+                }
+            }
+        }
+        Ok(done.pop().unwrap())
+    }
+
+    /// Evaluates a thunk to weak head normal form, updating every thunk on
+    /// the way with its value.
+    fn whnf(&mut self, thunk: Ref) -> Result<Value, Box<EvalError>> {
+        let mut stack: Vec<Frame> = Vec::new();
+        let mut ctl = Control::Force(thunk);
+        loop {
+            ctl = match ctl {
+                Control::Force(t) => match self.thunks[t as usize] {
+                    Thunk::Delayed { pc, env } => {
+                        self.thunks[t as usize] = Thunk::Evaluating;
+                        stack.push(Frame::Update(t));
+                        Control::Eval(pc, env)
+                    }
+                    Thunk::Evaluating => {
+                        return Err(EvalError::boxed("Term depends on its own value"))
+                    }
+                    Thunk::Done(v) => Control::Return(v),
+                },
+                Control::Eval(pc, env) => match self.program.code[pc as usize] {
+                    Op::Var(i) => Control::Force(self.lookup(env, i)),
+                    Op::Global(g) => Control::Force(self.globals[g as usize]),
+                    Op::Free(v) => Control::Return(Value::Neutral {
+                        head: Head::Free(v),
+                        spine: NIL,
+                    }),
+                    Op::Unknown(u) => Control::Return(Value::Neutral {
+                        head: Head::Unknown(u),
+                        spine: NIL,
+                    }),
+                    Op::Lam(_) => Control::Return(Value::Lam { body: pc + 1, env }),
+                    Op::App(len) => {
+                        let arg_pc = pc + 1 + len;
+                        // Variables and globals already are thunks
+                        let arg = match self.program.code[arg_pc as usize] {
+                            Op::Var(i) => self.lookup(env, i),
+                            Op::Global(g) => self.globals[g as usize],
+                            _ => self.alloc(Thunk::Delayed { pc: arg_pc, env })?,
+                        };
+                        stack.push(Frame::Arg(arg));
+                        Control::Eval(pc + 1, env)
+                    }
+                },
+                Control::Return(v) => match stack.pop() {
+                    None => return Ok(v),
+                    Some(Frame::Update(t)) => {
+                        self.thunks[t as usize] = Thunk::Done(v);
+                        Control::Return(v)
+                    }
+                    Some(Frame::Arg(arg)) => match v {
+                        Value::Lam { body, env } => {
+                            if self.steps == self.max_steps {
+                                return Err(EvalError::boxed(format!(
+                                    "Iteration limit reached: {}",
+                                    self.max_steps
+                                )));
+                            }
+                            self.steps += 1;
+                            Control::Eval(body, self.cons(arg, env)?)
+                        }
+                        Value::Neutral { head, spine } => Control::Return(Value::Neutral {
+                            head,
+                            spine: self.cons(arg, spine)?,
+                        }),
+                    },
+                },
+            }
+        }
+    }
+
+    fn lookup(&self, mut env: Ref, index: u32) -> Ref {
+        for _ in 0..index {
+            env = self.cells[env as usize].next;
+        }
+        self.cells[env as usize].thunk
+    }
+
+    fn alloc(&mut self, thunk: Thunk) -> Result<Ref, Box<EvalError>> {
+        self.check_heap()?;
+        self.thunks.push(thunk);
+        Ok(self.thunks.len() as Ref - 1)
+    }
+
+    fn cons(&mut self, thunk: Ref, next: Ref) -> Result<Ref, Box<EvalError>> {
+        self.check_heap()?;
+        self.cells.push(Cell { thunk, next });
+        Ok(self.cells.len() as Ref - 1)
+    }
+
+    fn check_heap(&self) -> Result<(), Box<EvalError>> {
+        if self.heap_size() >= self.max_heap {
+            Err(EvalError::boxed(format!(
+                "Heap outgrew maximum size: {}",
+                self.max_heap
+            )))
+        } else {
+            Ok(())
+        }
+    }
+}
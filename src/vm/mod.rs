@@ -0,0 +1,22 @@
+use std::error;
+
+use crate::expr::{Defs, Expr};
+
+pub mod compile;
+pub mod machine;
+
+pub use compile::{compile, Op, Program};
+pub use machine::Machine;
+
+#[cfg(test)]
+mod tests;
+
+/// Normalizes `expr` on the bytecode machine.
+///
+/// The result is alpha-equivalent to the one `eval::reduce` finds, but
+/// shared arguments are evaluated only once.
+pub fn normalize(expr: &Expr, defs: &Defs) -> Result<Box<Expr>, Box<dyn error::Error>> {
+    let program = compile(expr, defs);
+    let normal = Machine::new(&program).normalize()?;
+    Ok(normal.to_expr())
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+
+use super::*;
+use crate::eval;
+use crate::expr::expr_aliases::*;
+use crate::test::*;
+use crate::{lex, parse};
+
+fn lib() -> Defs {
+    let src = include_bytes!("../../res/lib");
+    parse::parse(lex::lex(&src[..]).unwrap()).unwrap().0
+}
+
+fn same_as_reduce(expr: Box<Expr>, defs: &Defs) {
+    let expected = eval::reduce(expr.clone(), defs).0.unwrap();
+    let got = normalize(&expr, defs).unwrap();
+    assert!(
+        got.alpha_eq(&expected),
+        "{} normalized to {}, expected {}",
+        expr,
+        got,
+        expected
+    );
+}
+
+#[test]
+fn church_arith() {
+    let defs = lib();
+    for n in 0..5 {
+        for m in 0..5 {
+            for op in ["Add", "Mul", "Pow", "Sub'"] {
+                same_as_reduce(vappl!(name(op), chnum(n), chnum(m)), &defs);
+            }
+        }
+    }
+}
+
+#[test]
+fn lib_programs() {
+    let defs = lib();
+    for src in [
+        "Fact 3",
+        "RecFact 3",
+        "Sub 7 3",
+        "RecEq 3 3",
+        "Eq 2 3",
+        "Pair (Succ 2) (Fst (Pair 1 0))",
+        "Y (\\rn.n (Const False) True)",
+    ] {
+        same_as_reduce(process(src), &defs);
+    }
+}
+
+#[test]
+fn step_limit() {
+    let program = compile(&process("Y I"), &lib());
+    let mut machine = Machine::new(&program);
+    machine.max_steps = 1000;
+    assert!(machine.normalize().is_err());
+    assert_eq!(machine.steps(), 1000);
+}
+
+#[test]
+fn open_terms() {
+    let defs = HashMap::new();
+    same_as_reduce(process(r"\c.(\ba.ca)a"), &defs);
+    same_as_reduce(process(r"(\xy.x)y Unknown"), &defs);
+    same_as_reduce(process(r"\x.f x"), &defs);
+    same_as_reduce(process(r"\ab.(\x.b x)(\y.a y)"), &defs);
+}
+
+#[test]
+fn compile_layout() {
+    let program = compile(&process(r"(\x.x)y"), &HashMap::new());
+    assert_eq!(
+        program.code,
+        vec![Op::App(2), Op::Lam(b'x'), Op::Var(0), Op::Free(b'y')]
+    );
+}
+
+#[test]
+fn deep_result() {
+    let n = 100000;
+    let normal = normalize(&appl(name("Succ"), chnum(n)), &lib()).unwrap();
+    assert_eq!(normal.try_unchurch_num(), Some(n + 1));
+}